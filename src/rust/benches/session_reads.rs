@@ -0,0 +1,187 @@
+//! Measures how many history snapshots concurrent readers (e.g. dashboards
+//! polling `messages()` and `modified_time()`) get through per second, on an
+//! idle session and while replies stream into it.
+//!
+//! Run with `cargo bench --bench session_reads`. Replies come from a local
+//! SSE server, so no credentials or network access are needed.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use geminisdk::{BackendOptions, GeminiBackend, GeminiSession, MessageOptions, SessionConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const RUN_FOR: Duration = Duration::from_secs(2);
+const CHUNKS_PER_REPLY: usize = 500;
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
+async fn main() {
+    let base_url = start_server().await;
+    println!(
+        "{:>8} {:>12} {:>16}",
+        "readers", "idle (op/s)", "streaming (op/s)"
+    );
+    for readers in [1, 4, 16] {
+        let idle = reads_per_second(&base_url, readers, false).await;
+        let streaming = reads_per_second(&base_url, readers, true).await;
+        println!("{:>8} {:>12.0} {:>16.0}", readers, idle, streaming);
+    }
+}
+
+/// Runs `readers` snapshotting tasks for `RUN_FOR`, with a writer streaming
+/// replies back to back when `streaming` is set.
+async fn reads_per_second(base_url: &str, readers: usize, streaming: bool) -> f64 {
+    let session = Arc::new(new_session(base_url));
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+
+    let writer = streaming.then(|| {
+        let (session, done) = (session.clone(), done.clone());
+        tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                let prompt = MessageOptions {
+                    prompt: "Count to five hundred".to_string(),
+                    ..Default::default()
+                };
+                session.send(prompt).await.expect("streamed reply");
+                // Keep the history from growing without bound.
+                session.clear_history().await;
+            }
+        })
+    });
+
+    let started = Instant::now();
+    let tasks: Vec<_> = (0..readers)
+        .map(|_| {
+            let (session, done, reads) = (session.clone(), done.clone(), reads.clone());
+            tokio::spawn(async move {
+                while !done.load(Ordering::Relaxed) {
+                    std::hint::black_box(session.messages().await);
+                    std::hint::black_box(session.modified_time().await);
+                    reads.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    tokio::time::sleep(RUN_FOR).await;
+    done.store(true, Ordering::Relaxed);
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = started.elapsed();
+    if let Some(writer) = writer {
+        writer.await.unwrap();
+    }
+    reads.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64()
+}
+
+fn new_session(base_url: &str) -> GeminiSession {
+    let backend = GeminiBackend::new(BackendOptions {
+        oauth_path: Some(write_credentials()),
+        base_url: Some(base_url.to_string()),
+        ..Default::default()
+    })
+    .expect("backend");
+    GeminiSession::with_config(
+        uuid::Uuid::new_v4().to_string(),
+        Arc::new(backend),
+        SessionConfig {
+            model: Some("gemini-2.5-flash".to_string()),
+            streaming: Some(true),
+            ..Default::default()
+        },
+    )
+}
+
+/// Writes a credentials file with a token that stays valid for the run.
+fn write_credentials() -> String {
+    let dir = std::env::temp_dir().join(format!("geminisdk-bench-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("oauth_creds.json");
+    let expiry_ms = chrono::Utc::now().timestamp_millis() + 3_600_000;
+    let creds = serde_json::json!({
+        "access_token": "bench-access-token",
+        "refresh_token": "bench-refresh-token",
+        "token_type": "Bearer",
+        "expiry_date": expiry_ms
+    });
+    std::fs::write(&path, creds.to_string()).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+/// Serves `loadCodeAssist` with a ready project and every other call with a
+/// streamed reply of `CHUNKS_PER_REPLY` chunks.
+async fn start_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve(socket));
+        }
+    });
+    format!("http://{}", addr)
+}
+
+async fn serve(mut socket: TcpStream) {
+    let Some(path) = read_request(&mut socket).await else {
+        return;
+    };
+    let (content_type, body) = if path.ends_with(":loadCodeAssist") {
+        let body = serde_json::json!({
+            "currentTier": {"id": "standard-tier"},
+            "cloudaicompanionProject": "bench-project"
+        });
+        ("application/json", body.to_string())
+    } else {
+        let mut body = String::new();
+        for i in 0..CHUNKS_PER_REPLY {
+            let mut candidate =
+                serde_json::json!({"content": {"role": "model", "parts": [{"text": "word "}]}});
+            if i + 1 == CHUNKS_PER_REPLY {
+                candidate["finishReason"] = serde_json::json!("STOP");
+            }
+            let chunk = serde_json::json!({"response": {"candidates": [candidate]}});
+            body.push_str(&format!("data: {}\n\n", chunk));
+        }
+        ("text/event-stream", body)
+    };
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        content_type,
+        body.len(),
+        body
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+    let _ = socket.shutdown().await;
+}
+
+/// Reads one request and returns its path.
+async fn read_request(socket: &mut TcpStream) -> Option<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let n = socket.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        let text = String::from_utf8_lossy(&buffer);
+        let Some(header_end) = text.find("\r\n\r\n") else {
+            continue;
+        };
+        let content_length = text[..header_end]
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        if buffer.len() >= header_end + 4 + content_length {
+            return text.split_whitespace().nth(1).map(String::from);
+        }
+    }
+}
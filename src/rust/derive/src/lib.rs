@@ -0,0 +1,217 @@
+//! Derive macros for GeminiSDK Rust.
+//!
+//! Use these through the `geminisdk` re-exports rather than depending on this
+//! crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::meta::ParseNestedMeta;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Expr, Fields, Lit, LitStr,
+};
+
+/// Derives `geminisdk::ToolSchema` for a struct with named fields, building
+/// the tool's parameters from the field types and doc comments.
+///
+/// Fields are required unless they are `Option`s or marked
+/// `#[serde(default)]`. The serde attributes that change the argument names
+/// the model must send (`rename`, `rename_all`, `skip`) are honoured.
+#[proc_macro_derive(ToolSchema)]
+pub fn derive_tool_schema(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(unsupported(&input)),
+        },
+        _ => return Err(unsupported(&input)),
+    };
+
+    let container = SerdeAttrs::parse(&input.attrs)?;
+    let mut properties = Vec::new();
+    for field in fields {
+        let attrs = SerdeAttrs::parse(&field.attrs)?;
+        if attrs.skip {
+            continue;
+        }
+        if attrs.flatten {
+            return Err(syn::Error::new_spanned(
+                field,
+                "ToolSchema does not support #[serde(flatten)]",
+            ));
+        }
+        let ident = field
+            .ident
+            .as_ref()
+            .expect("named field")
+            .unraw()
+            .to_string();
+        let name = match attrs.rename {
+            Some(name) => name,
+            None => rename_field(&ident, container.rename_all.as_deref()),
+        };
+        let ty = &field.ty;
+        let description = match doc_comment(&field.attrs) {
+            Some(doc) => quote!(.with_description(#doc)),
+            None => quote!(),
+        };
+        let required = !attrs.default;
+        properties.push(quote! {
+            params.properties.insert(
+                #name.to_string(),
+                <#ty as ::geminisdk::SchemaProperty>::schema_property()#description,
+            );
+            if #required && !<#ty as ::geminisdk::SchemaProperty>::OPTIONAL {
+                params.required.push(#name.to_string());
+            }
+        });
+    }
+
+    let name = &input.ident;
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::geminisdk::SchemaProperty));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::geminisdk::ToolSchema for #name #ty_generics #where_clause {
+            fn tool_parameters() -> ::geminisdk::ToolParameters {
+                let mut params = ::geminisdk::ToolParameters::new();
+                #(#properties)*
+                params
+            }
+        }
+
+        impl #impl_generics ::geminisdk::SchemaProperty for #name #ty_generics #where_clause {
+            fn schema_property() -> ::geminisdk::ToolProperty {
+                ::geminisdk::ToolProperty::object(<Self as ::geminisdk::ToolSchema>::tool_parameters())
+            }
+        }
+    })
+}
+
+fn unsupported(input: &DeriveInput) -> syn::Error {
+    syn::Error::new_spanned(
+        &input.ident,
+        "ToolSchema can only be derived for structs with named fields",
+    )
+}
+
+/// The `#[serde(...)]` options that affect the generated schema.
+#[derive(Default)]
+struct SerdeAttrs {
+    rename: Option<String>,
+    rename_all: Option<String>,
+    skip: bool,
+    default: bool,
+    flatten: bool,
+}
+
+impl SerdeAttrs {
+    fn parse(attrs: &[Attribute]) -> syn::Result<Self> {
+        let mut parsed = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("serde")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    parsed.rename = deserialize_name(&meta)?;
+                } else if meta.path.is_ident("rename_all") {
+                    parsed.rename_all = deserialize_name(&meta)?;
+                } else if meta.path.is_ident("skip") || meta.path.is_ident("skip_deserializing") {
+                    parsed.skip = true;
+                } else if meta.path.is_ident("default") {
+                    parsed.default = true;
+                    skip_meta(&meta)?;
+                } else if meta.path.is_ident("flatten") {
+                    parsed.flatten = true;
+                } else {
+                    skip_meta(&meta)?;
+                }
+                Ok(())
+            })?;
+        }
+        Ok(parsed)
+    }
+}
+
+/// Reads `name = "..."` or the `deserialize` half of
+/// `name(serialize = "...", deserialize = "...")`.
+fn deserialize_name(meta: &ParseNestedMeta) -> syn::Result<Option<String>> {
+    if meta.input.peek(syn::Token![=]) {
+        return Ok(Some(meta.value()?.parse::<LitStr>()?.value()));
+    }
+    let mut name = None;
+    meta.parse_nested_meta(|inner| {
+        if inner.path.is_ident("deserialize") {
+            name = Some(inner.value()?.parse::<LitStr>()?.value());
+        } else {
+            skip_meta(&inner)?;
+        }
+        Ok(())
+    })?;
+    Ok(name)
+}
+
+/// Consumes the value of a serde option the schema doesn't care about.
+fn skip_meta(meta: &ParseNestedMeta) -> syn::Result<()> {
+    if meta.input.peek(syn::Token![=]) {
+        meta.value()?.parse::<Expr>()?;
+    } else if meta.input.peek(syn::token::Paren) {
+        meta.parse_nested_meta(|inner| skip_meta(&inner))?;
+    }
+    Ok(())
+}
+
+/// Joins the lines of an item's doc comment, if it has one.
+fn doc_comment(attrs: &[Attribute]) -> Option<String> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta.require_name_value().ok()?.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(doc) => Some(doc.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
+
+/// Applies a serde `rename_all` rule to a snake_case field name.
+fn rename_field(field: &str, rule: Option<&str>) -> String {
+    let pascal = || -> String {
+        field
+            .split('_')
+            .map(|word| {
+                let mut chars = word.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                }
+            })
+            .collect()
+    };
+    match rule {
+        Some("UPPERCASE") | Some("SCREAMING_SNAKE_CASE") => field.to_ascii_uppercase(),
+        Some("PascalCase") => pascal(),
+        Some("camelCase") => {
+            let pascal = pascal();
+            let mut chars = pascal.chars();
+            match chars.next() {
+                Some(first) => first.to_lowercase().chain(chars).collect(),
+                None => pascal,
+            }
+        }
+        Some("kebab-case") => field.replace('_', "-"),
+        Some("SCREAMING-KEBAB-CASE") => field.to_ascii_uppercase().replace('_', "-"),
+        _ => field.to_string(),
+    }
+}
@@ -4,9 +4,6 @@ use geminisdk::{GeminiClient, GeminiClientOptions, MessageOptions, SessionConfig
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize logging
-    env_logger::init();
-
     println!("GeminiSDK Rust - Basic Usage Example\n");
 
     // Create client with default options
@@ -27,12 +24,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create a session
     println!("Creating session...");
     let session = client
-        .create_session(SessionConfig {
-            model: Some("gemini-2.5-flash".to_string()),
-            streaming: Some(false), // Non-streaming for simplicity
-            system_message: Some("You are a helpful assistant.".to_string()),
-            ..Default::default()
-        })
+        .create_session(
+            SessionConfig::builder()
+                .model("gemini-2.5-flash")
+                .streaming(false) // Non-streaming for simplicity
+                .system_message("You are a helpful assistant.")
+                .build(),
+        )
         .await?;
 
     println!("Session created: {}\n", session.session_id());
@@ -43,8 +41,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .send_and_wait(MessageOptions {
             prompt: "What are three interesting facts about the Rust programming language?"
                 .to_string(),
-            attachments: None,
-            context: None,
+            ..Default::default()
         })
         .await?;
 
@@ -1,29 +1,233 @@
 //! Backend for Gemini CLI / Google Code Assist API.
 
-use crate::auth::GeminiOAuthManager;
+use crate::auth::{Clock, CredentialStore, GeminiOAuthManager, ServiceAccountKey};
 use crate::errors::{GeminiSDKError, Result};
 use crate::types::{
-    FunctionCall, GenerationConfig, LLMChunk, LLMUsage, Message, MessageContent, Role,
-    ThinkingConfig, Tool, ToolCall, HTTP_FORBIDDEN, HTTP_UNAUTHORIZED,
+    Attachment, BlockReason, BuiltinTool, CodeExecution, ContentPart, FileHandle, FunctionCall,
+    GenerationConfig, GroundingMetadata, GroundingSource, LLMChunk, LLMUsage, Message,
+    MessageContent, Role, SafetySetting, ThinkingConfig, Tool, ToolCall, ToolResult,
+    GEMINI_CODE_ASSIST_API_VERSION, GEMINI_CODE_ASSIST_ENDPOINT, GEMINI_FILES_ENDPOINT,
+    HTTP_FORBIDDEN, HTTP_UNAUTHORIZED,
 };
 use futures::stream::{Stream, StreamExt};
 use reqwest::Client;
 use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-const ONBOARD_MAX_RETRIES: u32 = 30;
-const ONBOARD_SLEEP_SECONDS: u64 = 2;
+/// Stream of parsed chunks returned by streaming completions.
+pub type LLMChunkStream = Pin<Box<dyn Stream<Item = Result<LLMChunk>> + Send>>;
 
+const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+/// Most texts the API accepts in one `batchEmbedContents` call.
+const EMBED_BATCH_LIMIT: usize = 100;
+
+/// Retry behavior for rate-limited (429) and server-error (5xx) responses.
+///
+/// Delays grow exponentially from `base_delay` up to `max_delay`, with
+/// jitter applied. A `Retry-After` header from the server takes precedence,
+/// but is still capped at `max_delay`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How long to wait for account onboarding, which runs before the first
+/// request when the account has no Code Assist tier yet.
+///
+/// `onboardUser` is polled every `poll_interval` until the operation is done,
+/// at most `max_polls` times. A free-tier account that gives up continues
+/// without a project; any other tier fails with `GeminiSDKError::Onboarding`
+/// naming the operation, and the next request resumes polling it.
 #[derive(Debug, Clone)]
+pub struct OnboardingPolicy {
+    pub max_polls: u32,
+    pub poll_interval: Duration,
+    /// Stops waiting between polls once cancelled.
+    pub cancel: Option<CancellationToken>,
+}
+
+impl Default for OnboardingPolicy {
+    fn default() -> Self {
+        Self {
+            max_polls: 30,
+            poll_interval: Duration::from_secs(2),
+            cancel: None,
+        }
+    }
+}
+
+impl OnboardingPolicy {
+    /// Polls once and fails right away if onboarding is still running, so
+    /// interactive callers can report progress and retry later.
+    pub fn no_wait() -> Self {
+        Self {
+            max_polls: 1,
+            ..Default::default()
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_retryable_status(status: u16) -> bool {
+        status == 429 || (500..600).contains(&status)
+    }
+
+    /// Returns the backoff delay for a zero-based retry attempt: half of the
+    /// capped exponential delay plus a random share of the other half.
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let half = exp / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    /// The delay before retry `attempt`: the server's `Retry-After` seconds
+    /// when given, else the backoff delay. Either way at most `max_delay`, so
+    /// a bogus header can't stall the call for hours.
+    pub fn delay_with_retry_after(&self, attempt: u32, retry_after: Option<u64>) -> Duration {
+        match retry_after {
+            Some(secs) => Duration::from_secs(secs).min(self.max_delay),
+            None => self.delay_for_attempt(attempt),
+        }
+    }
+}
+
+/// Client identity sent with `loadCodeAssist` and `onboardUser`. Code Assist
+/// uses it for routing and analytics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientMetadata {
+    pub ide_type: String,
+    pub platform: String,
+    pub plugin_type: String,
+    /// Defaults to the project from `GOOGLE_CLOUD_PROJECT` when unset.
+    pub duet_project: Option<String>,
+}
+
+impl Default for ClientMetadata {
+    fn default() -> Self {
+        Self {
+            ide_type: "IDE_UNSPECIFIED".to_string(),
+            platform: "PLATFORM_UNSPECIFIED".to_string(),
+            plugin_type: "GEMINI".to_string(),
+            duet_project: None,
+        }
+    }
+}
+
+impl ClientMetadata {
+    fn to_value(&self, env_project_id: Option<&str>) -> Value {
+        json!({
+            "ideType": self.ide_type,
+            "platform": self.platform,
+            "pluginType": self.plugin_type,
+            "duetProject": self.duet_project.as_deref().or(env_project_id)
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct BackendOptions {
     pub timeout: Option<Duration>,
     pub oauth_path: Option<String>,
     pub client_id: Option<String>,
     pub client_secret: Option<String>,
+    /// Upper bound on the serialized request body; larger requests are
+    /// rejected before any network call.
+    pub max_request_bytes: Option<usize>,
+    pub retry_policy: RetryPolicy,
+    /// Polling for account onboarding; see `OnboardingPolicy`.
+    pub onboarding: OnboardingPolicy,
+    /// Overrides where OAuth credentials are loaded from and saved to.
+    pub credential_store: Option<Arc<dyn CredentialStore>>,
+    /// Fail the stream on SSE `data:` payloads that are not valid JSON instead
+    /// of logging and skipping them.
+    pub strict_sse_parsing: bool,
+    /// Extra OAuth scopes requested alongside the Gemini ones.
+    pub additional_scopes: Vec<String>,
+    /// Clock used for token expiry checks; defaults to the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+    pub client_metadata: ClientMetadata,
+    /// Code Assist base URL, e.g. a staging endpoint or a local mock.
+    /// Defaults to `GEMINI_CODE_ASSIST_ENDPOINT`.
+    pub base_url: Option<String>,
+    /// API version path segment. Defaults to `GEMINI_CODE_ASSIST_API_VERSION`.
+    pub api_version: Option<String>,
+    /// HTTP client for API and token requests, e.g. one with custom TLS
+    /// roots. When set, `timeout` is not applied; configure it on the client.
+    pub http_client: Option<Client>,
+    /// Proxy URL for API and token requests. When unset, the `HTTPS_PROXY`,
+    /// `HTTP_PROXY` and `NO_PROXY` environment variables apply.
+    pub proxy: Option<String>,
+    /// Notified around every HTTP call to the Code Assist API.
+    pub observer: Option<Arc<dyn RequestObserver>>,
+    /// Base URL of the Files API used by `upload_file`. Defaults to
+    /// `GEMINI_FILES_ENDPOINT`.
+    pub files_base_url: Option<String>,
+    /// Inline data larger than this many bytes is uploaded with
+    /// `upload_file` and sent as a file reference instead. Off when `None`.
+    pub inline_data_limit: Option<usize>,
+    /// Most generation requests allowed in flight at once across every
+    /// session sharing this backend; extra calls wait for a free slot.
+    /// Unlimited when `None`.
+    pub max_concurrent_requests: Option<usize>,
+    /// Authenticate as this service account instead of with the stored
+    /// user OAuth credentials.
+    pub service_account: Option<ServiceAccountKey>,
+}
+
+impl std::fmt::Debug for BackendOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackendOptions")
+            .field("timeout", &self.timeout)
+            .field("oauth_path", &self.oauth_path)
+            .field("client_id", &self.client_id)
+            .field("max_request_bytes", &self.max_request_bytes)
+            .field("retry_policy", &self.retry_policy)
+            .field("onboarding", &self.onboarding)
+            .field("credential_store", &self.credential_store.is_some())
+            .field("strict_sse_parsing", &self.strict_sse_parsing)
+            .field("additional_scopes", &self.additional_scopes)
+            .field("clock", &self.clock.is_some())
+            .field("client_metadata", &self.client_metadata)
+            .field("base_url", &self.base_url)
+            .field("api_version", &self.api_version)
+            .field("http_client", &self.http_client.is_some())
+            .field("proxy", &self.proxy)
+            .field("observer", &self.observer.is_some())
+            .field("files_base_url", &self.files_base_url)
+            .field("inline_data_limit", &self.inline_data_limit)
+            .field("max_concurrent_requests", &self.max_concurrent_requests)
+            .field("service_account", &self.service_account)
+            .finish()
+    }
 }
 
 impl Default for BackendOptions {
@@ -33,33 +237,252 @@ impl Default for BackendOptions {
             oauth_path: None,
             client_id: None,
             client_secret: None,
+            max_request_bytes: Some(DEFAULT_MAX_REQUEST_BYTES),
+            retry_policy: RetryPolicy::default(),
+            onboarding: OnboardingPolicy::default(),
+            credential_store: None,
+            strict_sse_parsing: false,
+            additional_scopes: Vec::new(),
+            clock: None,
+            client_metadata: ClientMetadata::default(),
+            base_url: None,
+            api_version: None,
+            http_client: None,
+            proxy: None,
+            observer: None,
+            files_base_url: None,
+            inline_data_limit: None,
+            max_concurrent_requests: None,
+            service_account: None,
+        }
+    }
+}
+
+/// The per-request settings sent alongside a conversation. Every field is
+/// optional; `RequestOptions::default()` sends the model's defaults.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestOptions<'a> {
+    pub generation_config: Option<&'a GenerationConfig>,
+    pub thinking_config: Option<&'a ThinkingConfig>,
+    pub tools: Option<&'a [Tool]>,
+    pub safety_settings: Option<&'a [SafetySetting]>,
+}
+
+/// An inline part too large to send as is, copied out of the history by
+/// `GeminiBackend::large_parts` so it can be uploaded unlocked.
+pub(crate) struct LargePart {
+    message: usize,
+    part: usize,
+    data: Vec<u8>,
+    mime_type: String,
+    file_uri: Option<String>,
+}
+
+impl LargePart {
+    /// Points the part this was copied from at the uploaded file, so it is
+    /// only uploaded once. Skipped when the history changed meanwhile and
+    /// that part no longer holds the same data.
+    pub(crate) fn apply(self, messages: &mut [Message]) {
+        let Some(file_uri) = self.file_uri else {
+            return;
+        };
+        let part = messages
+            .get_mut(self.message)
+            .and_then(|message| match &mut message.content {
+                MessageContent::Parts(parts) => parts.get_mut(self.part),
+                MessageContent::Text(_) => None,
+            });
+        if let Some(part) = part.filter(|part| part.image_data.as_ref() == Some(&self.data)) {
+            part.file_uri = Some(file_uri);
+            part.image_data = None;
         }
     }
 }
 
+/// An outgoing Code Assist API call, as seen by a `RequestObserver`.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    /// Shared by every attempt of one logical request.
+    pub request_id: String,
+    pub method: String,
+    pub url: String,
+    /// Request headers, with the `Authorization` value redacted.
+    pub headers: Vec<(String, String)>,
+    /// Zero for the first attempt, counting up with each retry.
+    pub attempt: u32,
+}
+
+/// The HTTP response to a `RequestInfo`.
+#[derive(Debug, Clone)]
+pub struct ResponseInfo {
+    pub request_id: String,
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    /// Time from sending the request until the response headers arrived.
+    pub latency: Duration,
+    pub attempt: u32,
+}
+
+/// Hook for logging or tracing the backend's HTTP traffic. `on_request` and
+/// `on_response` fire for every attempt; `on_error` fires once when a call
+/// fails for good.
+pub trait RequestObserver: Send + Sync {
+    fn on_request(&self, _request: &RequestInfo) {}
+    fn on_response(&self, _response: &ResponseInfo) {}
+    fn on_error(&self, _error: &GeminiSDKError) {}
+}
+
+/// Builds an HTTP client with the given timeout, routed through `proxy` when
+/// one is given. Fails on an invalid proxy URL.
+pub(crate) fn build_http_client(timeout: Option<Duration>, proxy: Option<&str>) -> Result<Client> {
+    let mut builder = Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy).map_err(|e| {
+            GeminiSDKError::configuration(format!("Invalid proxy URL '{}': {}", proxy, e))
+        })?;
+        builder = builder.proxy(proxy);
+    }
+    builder
+        .build()
+        .map_err(|e| GeminiSDKError::configuration(format!("Failed to build HTTP client: {}", e)))
+}
+
 pub struct GeminiBackend {
-    timeout: Duration,
+    api_endpoint: String,
     oauth_manager: GeminiOAuthManager,
     project_id: Arc<Mutex<Option<String>>>,
     http_client: Client,
+    max_request_bytes: usize,
+    retry_policy: RetryPolicy,
+    onboarding: OnboardingPolicy,
+    strict_sse_parsing: bool,
+    client_metadata: ClientMetadata,
+    observer: Option<Arc<dyn RequestObserver>>,
+    files_endpoint: String,
+    inline_data_limit: Option<usize>,
+    limiter: Option<Arc<Semaphore>>,
 }
 
 impl GeminiBackend {
-    pub fn new(options: BackendOptions) -> Self {
+    /// Builds a backend from `options`. Fails with a configuration error when
+    /// `proxy` is not a valid proxy URL.
+    pub fn new(options: BackendOptions) -> Result<Self> {
         let timeout = options.timeout.unwrap_or(Duration::from_secs(720));
-        Self {
-            timeout,
-            oauth_manager: GeminiOAuthManager::new(
-                options.oauth_path,
-                options.client_id,
-                options.client_secret,
-            ),
+        let mut oauth_manager = GeminiOAuthManager::new(
+            options.oauth_path,
+            options.client_id,
+            options.client_secret,
+            options.credential_store,
+        )
+        .with_additional_scopes(options.additional_scopes);
+        if let Some(clock) = options.clock {
+            oauth_manager = oauth_manager.with_clock(clock);
+        }
+        if let Some(key) = options.service_account {
+            oauth_manager = oauth_manager.with_service_account(key);
+        }
+        if let Some(client) = &options.http_client {
+            oauth_manager = oauth_manager.with_http_client(client.clone());
+        } else if let Some(proxy) = options.proxy.as_deref() {
+            oauth_manager = oauth_manager.with_http_client(build_http_client(None, Some(proxy))?);
+        }
+        let api_endpoint = format!(
+            "{}/{}",
+            options
+                .base_url
+                .as_deref()
+                .unwrap_or(GEMINI_CODE_ASSIST_ENDPOINT)
+                .trim_end_matches('/'),
+            options
+                .api_version
+                .as_deref()
+                .unwrap_or(GEMINI_CODE_ASSIST_API_VERSION)
+        );
+        let http_client = match options.http_client {
+            Some(client) => client,
+            None => build_http_client(Some(timeout), options.proxy.as_deref())?,
+        };
+        Ok(Self {
+            api_endpoint,
+            oauth_manager,
             project_id: Arc::new(Mutex::new(None)),
-            http_client: Client::builder()
-                .timeout(timeout)
-                .build()
-                .unwrap_or_default(),
+            http_client,
+            max_request_bytes: options
+                .max_request_bytes
+                .unwrap_or(DEFAULT_MAX_REQUEST_BYTES),
+            retry_policy: options.retry_policy,
+            onboarding: options.onboarding,
+            strict_sse_parsing: options.strict_sse_parsing,
+            client_metadata: options.client_metadata,
+            observer: options.observer,
+            files_endpoint: options
+                .files_base_url
+                .as_deref()
+                .unwrap_or(GEMINI_FILES_ENDPOINT)
+                .trim_end_matches('/')
+                .to_string(),
+            inline_data_limit: options.inline_data_limit,
+            limiter: options
+                .max_concurrent_requests
+                .map(|n| Arc::new(Semaphore::new(n.max(1)))),
+        })
+    }
+
+    /// Sends `request`, reporting it and its response to the observer.
+    async fn execute(
+        &self,
+        request: reqwest::RequestBuilder,
+        request_id: &str,
+        attempt: u32,
+    ) -> reqwest::Result<reqwest::Response> {
+        let Some(observer) = &self.observer else {
+            return request.send().await;
+        };
+
+        let request = request.build()?;
+        let info = RequestInfo {
+            request_id: request_id.to_string(),
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| {
+                    let value = if *name == reqwest::header::AUTHORIZATION {
+                        "[REDACTED]".to_string()
+                    } else {
+                        value.to_str().unwrap_or_default().to_string()
+                    };
+                    (name.to_string(), value)
+                })
+                .collect(),
+            attempt,
+        };
+        observer.on_request(&info);
+
+        let started = Instant::now();
+        let response = self.http_client.execute(request).await?;
+        observer.on_response(&ResponseInfo {
+            request_id: info.request_id,
+            method: info.method,
+            url: info.url,
+            status: response.status().as_u16(),
+            latency: started.elapsed(),
+            attempt,
+        });
+        Ok(response)
+    }
+
+    /// Reports a failed call to the observer before handing it back.
+    fn observe<T>(&self, result: Result<T>) -> Result<T> {
+        if let (Err(e), Some(observer)) = (&result, &self.observer) {
+            observer.on_error(e);
         }
+        result
     }
 
     async fn get_auth_headers(&self, force_refresh: bool) -> Result<Vec<(String, String)>> {
@@ -70,37 +493,91 @@ impl GeminiBackend {
         ])
     }
 
+    /// Converts the history to Gemini `contents`. Tool calls become
+    /// `functionCall` parts on the model turn; their results become
+    /// `functionResponse` parts (named after the originating call) on the
+    /// following user turn, with consecutive results merged into one turn.
+    /// Results are recognised by `Role::Tool` or, for messages built before
+    /// that role existed, by a `tool_call_id` alone.
     fn prepare_messages(&self, messages: &[Message]) -> Vec<Value> {
-        let mut result = Vec::new();
+        let mut result: Vec<Value> = Vec::new();
+        let call_names: HashMap<&str, &str> = messages
+            .iter()
+            .flat_map(|m| m.tool_calls.iter().flatten())
+            .map(|tc| (tc.id.as_str(), tc.function.name.as_str()))
+            .collect();
+        let mut in_function_responses = false;
+
+        for msg in messages.iter().filter(|m| m.role != Role::System) {
+            if msg.role == Role::Tool || msg.tool_call_id.is_some() {
+                let name = msg
+                    .tool_call_id
+                    .as_deref()
+                    .and_then(|id| call_names.get(id))
+                    .copied()
+                    .or(msg.name.as_deref())
+                    .unwrap_or("");
+                // Binary tool output (e.g. a chart) follows the response as
+                // inline data in the same turn.
+                let mut inline_parts = Vec::new();
+                let text = match &msg.content {
+                    MessageContent::Text(s) => s.clone(),
+                    MessageContent::Parts(parts) => {
+                        inline_parts.extend(parts.iter().filter_map(media_part));
+                        parts
+                            .iter()
+                            .filter_map(|p| p.text.as_deref())
+                            .collect::<Vec<_>>()
+                            .join("\n")
+                    }
+                };
+                let tool_result = ToolResult {
+                    result_type: msg.tool_result_type,
+                    text_result_for_llm: Some(text),
+                    binary_result: None,
+                    binary_mime_type: None,
+                    session_log: None,
+                };
+                let mut new_parts = vec![tool_result.to_function_response(name)];
+                new_parts.extend(inline_parts);
+
+                match result.last_mut() {
+                    Some(turn) if in_function_responses => {
+                        if let Some(parts) = turn["parts"].as_array_mut() {
+                            parts.extend(new_parts);
+                        }
+                    }
+                    _ => result.push(json!({"role": "user", "parts": new_parts})),
+                }
+                in_function_responses = true;
+                continue;
+            }
+            in_function_responses = false;
 
-        for msg in messages {
             let role = match msg.role {
                 Role::Assistant => "model",
                 _ => "user",
             };
+            let has_tool_calls = msg.tool_calls.as_ref().is_some_and(|c| !c.is_empty());
 
             let mut content_parts: Vec<Value> = Vec::new();
 
+            if let Some(reasoning) = msg.reasoning.as_deref().filter(|r| !r.is_empty()) {
+                content_parts.push(json!({"text": reasoning, "thought": true}));
+            }
+
             match &msg.content {
                 MessageContent::Text(text) => {
-                    content_parts.push(json!({"text": text}));
+                    if !(has_tool_calls && text.is_empty()) {
+                        content_parts.push(json!({"text": text}));
+                    }
                 }
                 MessageContent::Parts(parts) => {
                     for part in parts {
                         if let Some(text) = &part.text {
                             content_parts.push(json!({"text": text}));
                         }
-                        if let Some(data) = &part.image_data {
-                            if let Some(mime) = &part.image_mime_type {
-                                let b64 = base64_encode(data);
-                                content_parts.push(json!({
-                                    "inlineData": {
-                                        "mimeType": mime,
-                                        "data": b64
-                                    }
-                                }));
-                            }
-                        }
+                        content_parts.extend(media_part(part));
                     }
                 }
             }
@@ -116,19 +593,6 @@ impl GeminiBackend {
                 }
             }
 
-            if let Some(_tool_call_id) = &msg.tool_call_id {
-                let response_content = match &msg.content {
-                    MessageContent::Text(s) => json!({"result": s}),
-                    MessageContent::Parts(_) => json!({"result": ""}),
-                };
-                content_parts.push(json!({
-                    "functionResponse": {
-                        "name": msg.name.as_deref().unwrap_or(""),
-                        "response": response_content
-                    }
-                }));
-            }
-
             if !content_parts.is_empty() {
                 result.push(json!({
                     "role": role,
@@ -140,6 +604,28 @@ impl GeminiBackend {
         result
     }
 
+    fn prepare_system_instruction(&self, messages: &[Message]) -> Option<Value> {
+        let texts: Vec<String> = messages
+            .iter()
+            .filter(|m| m.role == Role::System)
+            .map(|m| match &m.content {
+                MessageContent::Text(text) => text.clone(),
+                MessageContent::Parts(parts) => parts
+                    .iter()
+                    .filter_map(|p| p.text.as_deref())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            })
+            .filter(|t| !t.is_empty())
+            .collect();
+
+        if texts.is_empty() {
+            return None;
+        }
+
+        Some(json!({"parts": [{"text": texts.join("\n\n")}]}))
+    }
+
     fn prepare_tools(&self, tools: &[Tool]) -> Option<Vec<Value>> {
         if tools.is_empty() {
             return None;
@@ -173,6 +659,138 @@ impl GeminiBackend {
         Some(vec![json!({"functionDeclarations": func_decls})])
     }
 
+    /// Turns a message attachment into a part, reading and downloading it
+    /// with this backend's HTTP client and `max_request_bytes` as the cap.
+    pub(crate) async fn resolve_attachment(&self, attachment: &Attachment) -> Result<ContentPart> {
+        attachment
+            .resolve(&self.http_client, self.max_request_bytes)
+            .await
+    }
+
+    /// Uploads `bytes` with the Files API's resumable protocol. Messages can
+    /// then reference the file by URI (`ContentPart::file_uri`) instead of
+    /// carrying the data inline.
+    pub async fn upload_file(&self, bytes: &[u8], mime_type: &str) -> Result<FileHandle> {
+        let result = self.upload_file_inner(bytes, mime_type).await;
+        self.observe(result)
+    }
+
+    async fn upload_file_inner(&self, bytes: &[u8], mime_type: &str) -> Result<FileHandle> {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let headers = self.get_auth_headers(false).await?;
+
+        let mut start = self
+            .http_client
+            .post(format!("{}/upload/v1beta/files", self.files_endpoint));
+        for (key, value) in &headers {
+            start = start.header(key.as_str(), value.as_str());
+        }
+        let start = start
+            .header("X-Goog-Upload-Protocol", "resumable")
+            .header("X-Goog-Upload-Command", "start")
+            .header("X-Goog-Upload-Header-Content-Length", bytes.len())
+            .header("X-Goog-Upload-Header-Content-Type", mime_type)
+            .body(json!({"file": {"mimeType": mime_type}}).to_string());
+        let response = self.execute(start, &request_id, 0).await?;
+        let status = response.status().as_u16();
+        if !response.status().is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(self.handle_http_error(status, &body, None, None));
+        }
+        let upload_url = response
+            .headers()
+            .get("x-goog-upload-url")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| {
+                GeminiSDKError::api_error("Files API did not return an upload URL", status)
+            })?;
+
+        let mut upload = self.http_client.post(&upload_url);
+        for (key, value) in &headers {
+            if key != "Content-Type" {
+                upload = upload.header(key.as_str(), value.as_str());
+            }
+        }
+        let upload = upload
+            .header("X-Goog-Upload-Offset", "0")
+            .header("X-Goog-Upload-Command", "upload, finalize")
+            .body(bytes.to_vec());
+        let response = self.execute(upload, &request_id, 0).await?;
+        let status = response.status().as_u16();
+        if !response.status().is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(self.handle_http_error(status, &body, None, None));
+        }
+
+        let data = read_json_body(response).await?;
+        let file = data.get("file").unwrap_or(&data);
+        let field = |name: &str| file.get(name).and_then(|v| v.as_str()).map(String::from);
+        Ok(FileHandle {
+            name: field("name").unwrap_or_default(),
+            uri: field("uri").ok_or_else(|| {
+                GeminiSDKError::api_error("Files API response has no file URI", status)
+            })?,
+            mime_type: field("mimeType").unwrap_or_else(|| mime_type.to_string()),
+            // int64 fields arrive as strings.
+            size_bytes: field("sizeBytes")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(bytes.len() as u64),
+        })
+    }
+
+    /// Copies of the inline parts in `messages` larger than
+    /// `inline_data_limit`, to upload with `upload_large_part` without
+    /// holding on to the messages meanwhile.
+    pub(crate) fn large_parts(&self, messages: &[Message]) -> Vec<LargePart> {
+        let Some(limit) = self.inline_data_limit else {
+            return Vec::new();
+        };
+        let mut large = Vec::new();
+        for (message_index, message) in messages.iter().enumerate() {
+            let MessageContent::Parts(parts) = &message.content else {
+                continue;
+            };
+            for (part_index, part) in parts.iter().enumerate() {
+                let (Some(data), Some(mime)) = (&part.image_data, &part.image_mime_type) else {
+                    continue;
+                };
+                if data.len() > limit {
+                    large.push(LargePart {
+                        message: message_index,
+                        part: part_index,
+                        data: data.clone(),
+                        mime_type: mime.clone(),
+                        file_uri: None,
+                    });
+                }
+            }
+        }
+        large
+    }
+
+    /// Uploads a part found by `large_parts`, recording its file's URI.
+    pub(crate) async fn upload_large_part(&self, part: &mut LargePart) -> Result<()> {
+        let file = self.upload_file(&part.data, &part.mime_type).await?;
+        part.file_uri = Some(file.uri);
+        Ok(())
+    }
+
+    /// Resolves the Code Assist project now, onboarding the account if
+    /// needed, instead of on the first request. The result is cached.
+    pub async fn resolve_project_id(&self) -> Result<String> {
+        let result = match self.oauth_manager.ensure_authenticated(false).await {
+            Ok(access_token) => self.ensure_project_id(&access_token).await,
+            Err(e) => Err(e),
+        };
+        self.observe(result)
+    }
+
+    /// The cached Code Assist project, once resolved.
+    pub async fn project_id(&self) -> Option<String> {
+        self.project_id.lock().await.clone()
+    }
+
     async fn ensure_project_id(&self, access_token: &str) -> Result<String> {
         {
             let guard = self.project_id.lock().await;
@@ -180,30 +798,33 @@ impl GeminiBackend {
                 return Ok(pid.clone());
             }
         }
-
         let env_project_id = self.oauth_manager.get_project_id();
+        if let Some(pid) = self.oauth_manager.cached_project_id() {
+            if env_project_id.as_ref().is_none_or(|env| *env == pid) {
+                *self.project_id.lock().await = Some(pid.clone());
+                return Ok(pid);
+            }
+            // The configured project changed since this one was cached.
+            self.oauth_manager.cache_project_id(None);
+        }
 
-        let client_metadata = json!({
-            "ideType": "IDE_UNSPECIFIED",
-            "platform": "PLATFORM_UNSPECIFIED",
-            "pluginType": "GEMINI",
-            "duetProject": env_project_id
-        });
+        let client_metadata = self.client_metadata.to_value(env_project_id.as_deref());
 
         let load_request = json!({
             "cloudaicompanionProject": env_project_id,
             "metadata": client_metadata
         });
 
-        let url = format!("{}:loadCodeAssist", self.oauth_manager.get_api_endpoint());
+        let url = format!("{}:loadCodeAssist", self.api_endpoint);
 
-        let response = self
+        let request = self
             .http_client
             .post(&url)
             .header("Authorization", format!("Bearer {}", access_token))
             .header("Content-Type", "application/json")
-            .json(&load_request)
-            .send()
+            .json(&load_request);
+        let response = self
+            .execute(request, &uuid::Uuid::new_v4().to_string(), 0)
             .await?;
 
         if !response.status().is_success() {
@@ -225,10 +846,7 @@ impl GeminiBackend {
             let project_id = project_from_api
                 .or(env_project_id)
                 .unwrap_or_default();
-
-            let mut guard = self.project_id.lock().await;
-            *guard = Some(project_id.clone());
-            return Ok(project_id);
+            return Ok(self.remember_project_id(project_id).await);
         }
 
         // Need to onboard
@@ -256,12 +874,7 @@ impl GeminiBackend {
         env_project_id: Option<String>,
         tier_id: &str,
     ) -> Result<String> {
-        let client_metadata = json!({
-            "ideType": "IDE_UNSPECIFIED",
-            "platform": "PLATFORM_UNSPECIFIED",
-            "pluginType": "GEMINI",
-            "duetProject": env_project_id
-        });
+        let client_metadata = self.client_metadata.to_value(env_project_id.as_deref());
 
         let onboard_request = if tier_id == "free-tier" {
             json!({
@@ -277,23 +890,41 @@ impl GeminiBackend {
             })
         };
 
-        let url = format!("{}:onboardUser", self.oauth_manager.get_api_endpoint());
+        let url = format!("{}:onboardUser", self.api_endpoint);
+
+        let policy = &self.onboarding;
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let mut operation: Option<String> = None;
+        for attempt in 0..policy.max_polls {
+            if attempt > 0 {
+                let wait = tokio::time::sleep(policy.poll_interval);
+                match &policy.cancel {
+                    Some(cancel) => tokio::select! {
+                        _ = cancel.cancelled() => {
+                            return Err(GeminiSDKError::cancellation("Onboarding cancelled"));
+                        }
+                        _ = wait => {}
+                    },
+                    None => wait.await,
+                }
+            }
 
-        for _ in 0..ONBOARD_MAX_RETRIES {
-            let response = self
+            let request = self
                 .http_client
                 .post(&url)
                 .header("Authorization", format!("Bearer {}", access_token))
                 .header("Content-Type", "application/json")
-                .json(&onboard_request)
-                .send()
-                .await?;
+                .json(&onboard_request);
+            let response = self.execute(request, &request_id, attempt).await?;
 
             if !response.status().is_success() {
                 return Err(GeminiSDKError::onboarding("Onboard request failed"));
             }
 
             let lro_data: Value = response.json().await?;
+            if let Some(name) = lro_data.get("name").and_then(|v| v.as_str()) {
+                operation = Some(name.to_string());
+            }
 
             if lro_data.get("done").and_then(|v| v.as_bool()).unwrap_or(false) {
                 if let Some(project_id) = lro_data
@@ -302,38 +933,52 @@ impl GeminiBackend {
                     .and_then(|c| c.get("id"))
                     .and_then(|v| v.as_str())
                 {
-                    let mut guard = self.project_id.lock().await;
-                    *guard = Some(project_id.to_string());
-                    return Ok(project_id.to_string());
+                    return Ok(self.remember_project_id(project_id.to_string()).await);
                 }
-                break;
+                if tier_id == "free-tier" {
+                    return Ok(self.remember_project_id(String::new()).await);
+                }
+                return Err(GeminiSDKError::Onboarding {
+                    message: "Onboarding finished without a project".to_string(),
+                    tier_id: Some(tier_id.to_string()),
+                    operation,
+                });
             }
-
-            tokio::time::sleep(Duration::from_secs(ONBOARD_SLEEP_SECONDS)).await;
         }
 
         if tier_id == "free-tier" {
-            let mut guard = self.project_id.lock().await;
-            *guard = Some(String::new());
+            // Free-tier requests work without a project; only this backend
+            // uses the fallback, so the next one resumes polling.
+            *self.project_id.lock().await = Some(String::new());
             return Ok(String::new());
         }
 
         Err(GeminiSDKError::Onboarding {
-            message: "Failed to complete onboarding".to_string(),
+            message: format!(
+                "Onboarding still in progress after {} poll(s) of operation {}",
+                policy.max_polls,
+                operation.as_deref().unwrap_or("<unnamed>")
+            ),
             tier_id: Some(tier_id.to_string()),
+            operation,
         })
     }
 
+    /// Caches a discovered project in memory and in the credential store.
+    async fn remember_project_id(&self, project_id: String) -> String {
+        self.oauth_manager.cache_project_id(Some(&project_id));
+        *self.project_id.lock().await = Some(project_id.clone());
+        project_id
+    }
+
     fn build_request_payload(
         &self,
         model: &str,
         messages: &[Message],
-        generation_config: Option<&GenerationConfig>,
-        thinking_config: Option<&ThinkingConfig>,
-        tools: Option<&[Tool]>,
+        options: RequestOptions<'_>,
         project_id: &str,
     ) -> Value {
-        let gen_config = generation_config.cloned().unwrap_or_default();
+        let gen_config = options.generation_config.cloned().unwrap_or_default();
 
         let mut generation_cfg = json!({
             "temperature": gen_config.temperature
@@ -351,8 +996,26 @@ impl GeminiBackend {
         if let Some(stops) = &gen_config.stop_sequences {
             generation_cfg["stopSequences"] = json!(stops);
         }
+        if let Some(penalty) = gen_config.presence_penalty {
+            generation_cfg["presencePenalty"] = json!(penalty);
+        }
+        if let Some(penalty) = gen_config.frequency_penalty {
+            generation_cfg["frequencyPenalty"] = json!(penalty);
+        }
+        if let Some(seed) = gen_config.seed {
+            generation_cfg["seed"] = json!(seed);
+        }
+        if let Some(count) = gen_config.candidate_count {
+            generation_cfg["candidateCount"] = json!(count);
+        }
+        if let Some(mime_type) = &gen_config.response_mime_type {
+            generation_cfg["responseMimeType"] = json!(mime_type);
+        }
+        if let Some(schema) = &gen_config.response_schema {
+            generation_cfg["responseSchema"] = schema.clone();
+        }
 
-        if let Some(thinking) = thinking_config {
+        if let Some(thinking) = options.thinking_config {
             if thinking.include_thoughts {
                 let mut thinking_cfg = json!({"includeThoughts": true});
                 if let Some(budget) = thinking.thinking_budget {
@@ -367,10 +1030,31 @@ impl GeminiBackend {
             "generationConfig": generation_cfg
         });
 
-        if let Some(tools) = tools {
-            if let Some(prepared) = self.prepare_tools(tools) {
-                request_body["tools"] = json!(prepared);
+        if let Some(system_instruction) = self.prepare_system_instruction(messages) {
+            request_body["systemInstruction"] = system_instruction;
+        }
+
+        let mut prepared_tools = options
+            .tools
+            .and_then(|tools| self.prepare_tools(tools))
+            .unwrap_or_default();
+        if let Some(builtin) = &gen_config.builtin_tools {
+            prepared_tools.extend(builtin.iter().map(BuiltinTool::to_value));
+        }
+        if !prepared_tools.is_empty() {
+            request_body["tools"] = json!(prepared_tools);
+        }
+
+        if let Some(tool_config) = &gen_config.tool_config {
+            let mut calling_config = json!({"mode": tool_config.mode});
+            if let Some(names) = &tool_config.allowed_function_names {
+                calling_config["allowedFunctionNames"] = json!(names);
             }
+            request_body["toolConfig"] = json!({"functionCallingConfig": calling_config});
+        }
+
+        if let Some(settings) = options.safety_settings.filter(|s| !s.is_empty()) {
+            request_body["safetySettings"] = json!(settings);
         }
 
         let mut payload = json!({
@@ -385,284 +1069,402 @@ impl GeminiBackend {
         payload
     }
 
-    fn parse_completion_response(&self, data: &Value) -> LLMChunk {
-        let response_data = data.get("response").unwrap_or(data);
-        let candidates = response_data
-            .get("candidates")
-            .and_then(|v| v.as_array())
-            .cloned()
-            .unwrap_or_default();
-
-        if candidates.is_empty() {
-            return LLMChunk::default();
+    fn encode_payload(&self, payload: &Value) -> Result<Vec<u8>> {
+        let body = serde_json::to_vec(payload)?;
+        if body.len() > self.max_request_bytes {
+            return Err(GeminiSDKError::Validation {
+                message: format!(
+                    "Request body is {} bytes, exceeding the limit of {} bytes",
+                    body.len(),
+                    self.max_request_bytes
+                ),
+                field: Some("max_request_bytes".to_string()),
+                value: Some(body.len().to_string()),
+            });
         }
+        Ok(body)
+    }
 
-        let candidate = &candidates[0];
-        let empty_obj = json!({});
-        let content_obj = candidate.get("content").unwrap_or(&empty_obj);
-        let parts = content_obj
-            .get("parts")
+    /// Parses a `generateContent` response. A prompt the API refused comes
+    /// back as a chunk with `block_reason` set; a response with no candidates
+    /// and no reason is an error rather than an empty answer.
+    fn parse_completion_response(&self, data: &Value) -> Result<LLMChunk> {
+        let response_data = data.get("response").unwrap_or(data);
+        let has_candidates = response_data
+            .get("candidates")
             .and_then(|v| v.as_array())
-            .cloned()
-            .unwrap_or_default();
-
-        let mut text_content = String::new();
-        let mut reasoning_content: Option<String> = None;
-        let mut tool_calls: Vec<ToolCall> = Vec::new();
-
-        for part in &parts {
-            if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
-                text_content.push_str(text);
-            }
-            if let Some(thought) = part.get("thought").and_then(|v| v.as_str()) {
-                reasoning_content = Some(thought.to_string());
-            }
-            if let Some(fc) = part.get("functionCall") {
-                let name = fc.get("name").and_then(|v| v.as_str()).unwrap_or("");
-                let args = fc
-                    .get("args")
-                    .or_else(|| fc.get("arguments"))
-                    .cloned()
-                    .unwrap_or(json!({}));
-
-                tool_calls.push(ToolCall {
-                    id: Uuid::new_v4().to_string(),
-                    call_type: "function".to_string(),
-                    function: FunctionCall {
-                        name: name.to_string(),
-                        arguments: args,
-                    },
-                });
-            }
-        }
-
-        let usage_data = data
-            .get("usageMetadata")
-            .or_else(|| response_data.get("usageMetadata"));
-
-        let usage = usage_data.map(|u| LLMUsage {
-            prompt_tokens: u
-                .get("promptTokenCount")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0),
-            completion_tokens: u
-                .get("candidatesTokenCount")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0),
-            total_tokens: u
-                .get("totalTokenCount")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0),
-        });
-
-        LLMChunk {
-            content: text_content,
-            reasoning_content,
-            tool_calls: if tool_calls.is_empty() {
-                None
-            } else {
-                Some(tool_calls)
-            },
-            usage,
-            finish_reason: candidate
-                .get("finishReason")
-                .and_then(|v| v.as_str())
-                .map(String::from),
+            .is_some_and(|candidates| !candidates.is_empty());
+        if !has_candidates && prompt_block_reason(data).is_none() {
+            return Err(GeminiSDKError::api_error(
+                "Response contained no candidates and no block reason",
+                200,
+            ));
         }
+        Ok(parse_chunk(data))
     }
 
     pub async fn complete(
         &self,
         model: &str,
         messages: &[Message],
-        generation_config: Option<&GenerationConfig>,
-        thinking_config: Option<&ThinkingConfig>,
-        tools: Option<&[Tool]>,
+        options: RequestOptions<'_>,
     ) -> Result<LLMChunk> {
-        self.complete_impl(model, messages, generation_config, thinking_config, tools, 0)
-            .await
-    }
-
-    fn complete_impl<'a>(
-        &'a self,
-        model: &'a str,
-        messages: &'a [Message],
-        generation_config: Option<&'a GenerationConfig>,
-        thinking_config: Option<&'a ThinkingConfig>,
-        tools: Option<&'a [Tool]>,
-        retry_count: u32,
-    ) -> Pin<Box<dyn std::future::Future<Output = Result<LLMChunk>> + Send + 'a>> {
-        Box::pin(async move {
-            let headers = self.get_auth_headers(retry_count > 0).await?;
-            let access_token = headers
-                .iter()
-                .find(|(k, _)| k == "Authorization")
-                .map(|(_, v)| v.replace("Bearer ", ""))
-                .unwrap_or_default();
-
-            let project_id = self.ensure_project_id(&access_token).await?;
-            let url = format!("{}:generateContent", self.oauth_manager.get_api_endpoint());
-
-            let payload = self.build_request_payload(
-                model,
-                messages,
-                generation_config,
-                thinking_config,
-                tools,
-                &project_id,
-            );
+        let _permit = self.acquire_slot().await;
+        let response = self
+            .send_request("generateContent", |project_id| {
+                self.build_request_payload(model, messages, options, project_id)
+            })
+            .await?;
 
-            let mut request = self.http_client.post(&url);
-            for (key, value) in &headers {
-                request = request.header(key.as_str(), value.as_str());
-            }
+        let data = read_json_body(response).await?;
+        self.parse_completion_response(&data)
+    }
 
-            let response = request.json(&payload).send().await?;
-            let status = response.status().as_u16();
+    /// Like `complete`, but returns every candidate in the response, e.g.
+    /// with `candidate_count` set to get alternatives for re-ranking.
+    pub async fn complete_n(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: RequestOptions<'_>,
+    ) -> Result<Vec<LLMChunk>> {
+        let _permit = self.acquire_slot().await;
+        let response = self
+            .send_request("generateContent", |project_id| {
+                self.build_request_payload(model, messages, options, project_id)
+            })
+            .await?;
 
-            if (status == HTTP_UNAUTHORIZED || status == HTTP_FORBIDDEN) && retry_count == 0 {
-                self.oauth_manager.invalidate_credentials();
-                return self
-                    .complete_impl(model, messages, generation_config, thinking_config, tools, 1)
-                    .await;
-            }
+        let data = read_json_body(response).await?;
+        Ok(parse_candidates(&data))
+    }
 
-            if !response.status().is_success() {
-                let body = response.text().await.unwrap_or_default();
-                return Err(self.handle_http_error(status, &body));
-            }
+    /// Like `complete`, but also returns the exact request payload that got
+    /// the response (the last attempt, after any retries) and the raw
+    /// response JSON, for debugging responses that parse unexpectedly.
+    pub async fn complete_verbose(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: RequestOptions<'_>,
+    ) -> Result<(Value, Value, LLMChunk)> {
+        let _permit = self.acquire_slot().await;
+        let sent = std::sync::Mutex::new(Value::Null);
+        let response = self
+            .send_request("generateContent", |project_id| {
+                let payload = self.build_request_payload(model, messages, options, project_id);
+                *sent.lock().unwrap() = payload.clone();
+                payload
+            })
+            .await?;
 
-            let data: Value = response.json().await?;
-            Ok(self.parse_completion_response(&data))
-        })
+        let data = read_json_body(response).await?;
+        let chunk = self.parse_completion_response(&data)?;
+        let request = sent.into_inner().unwrap_or_else(|e| e.into_inner());
+        Ok((request, data, chunk))
     }
 
     pub async fn complete_streaming(
         &self,
         model: &str,
         messages: &[Message],
-        generation_config: Option<&GenerationConfig>,
-        thinking_config: Option<&ThinkingConfig>,
-        tools: Option<&[Tool]>,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<LLMChunk>> + Send>>> {
-        self.complete_streaming_impl(model, messages, generation_config, thinking_config, tools, 0)
-            .await
-    }
-
-    fn complete_streaming_impl<'a>(
-        &'a self,
-        model: &'a str,
-        messages: &'a [Message],
-        generation_config: Option<&'a GenerationConfig>,
-        thinking_config: Option<&'a ThinkingConfig>,
-        tools: Option<&'a [Tool]>,
-        retry_count: u32,
-    ) -> Pin<Box<dyn std::future::Future<Output = Result<Pin<Box<dyn Stream<Item = Result<LLMChunk>> + Send>>>> + Send + 'a>> {
-        Box::pin(async move {
-            let headers = self.get_auth_headers(retry_count > 0).await?;
-            let access_token = headers
+        options: RequestOptions<'_>,
+    ) -> Result<LLMChunkStream> {
+        let permit = self.acquire_slot().await;
+        let response = self
+            .send_request("streamGenerateContent?alt=sse", |project_id| {
+                self.build_request_payload(model, messages, options, project_id)
+            })
+            .await?;
+
+        // The slot stays taken until the stream is exhausted or dropped.
+        let stream =
+            parse_sse_stream(response.bytes_stream(), self.strict_sse_parsing).map(move |chunk| {
+                let _held = &permit;
+                chunk
+            });
+        Ok(Box::pin(stream) as LLMChunkStream)
+    }
+
+    /// Waits for a slot under `max_concurrent_requests`; `None` when
+    /// unlimited.
+    async fn acquire_slot(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.limiter {
+            Some(limiter) => limiter.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+
+    /// Embeds each of `texts` with an embedding model such as
+    /// `gemini-embedding-001` (see `get_gemini_embedding_models`), returning the vectors in the same order. A
+    /// single text uses `embedContent`; several are sent together through
+    /// `batchEmbedContents`, in as few calls as the batch limit allows.
+    pub async fn embed_content(&self, model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let model_path = format!("models/{}", model.trim_start_matches("models/"));
+        let embed_request = |text: &str| {
+            json!({
+                "model": model_path,
+                "content": {"parts": [{"text": text}]}
+            })
+        };
+
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBED_BATCH_LIMIT) {
+            let (method, request) = match batch {
+                [text] => ("embedContent", embed_request(text)),
+                _ => (
+                    "batchEmbedContents",
+                    json!({"requests": batch.iter().map(|t| embed_request(t)).collect::<Vec<_>>()}),
+                ),
+            };
+            let response = self
+                .send_request(method, |project_id| {
+                    let mut payload = json!({"model": model, "request": request});
+                    if !project_id.is_empty() {
+                        payload["project"] = json!(project_id);
+                    }
+                    payload
+                })
+                .await?;
+
+            let data = read_json_body(response).await?;
+            let embeddings = parse_embeddings(data.get("response").unwrap_or(&data));
+            if embeddings.len() != batch.len() {
+                return Err(GeminiSDKError::api_error(
+                    format!(
+                        "{} response has {} embeddings for {} texts",
+                        method,
+                        embeddings.len(),
+                        batch.len()
+                    ),
+                    200,
+                ));
+            }
+            vectors.extend(embeddings);
+        }
+        Ok(vectors)
+    }
+
+    /// Counts the prompt tokens the given conversation would consume.
+    pub async fn count_tokens(
+        &self,
+        model: &str,
+        messages: &[Message],
+        tools: Option<&[Tool]>,
+    ) -> Result<u64> {
+        let response = self
+            .send_request("countTokens", |project_id| {
+                let options = RequestOptions {
+                    tools,
+                    ..Default::default()
+                };
+                self.build_request_payload(model, messages, options, project_id)
+            })
+            .await?;
+
+        let data = read_json_body(response).await?;
+        data.get("totalTokens")
+            .or_else(|| data.get("response").and_then(|r| r.get("totalTokens")))
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| {
+                GeminiSDKError::api_error("countTokens response is missing totalTokens", 200)
+            })
+    }
+
+    /// POSTs to an API method, refreshing credentials once on 401/403 and
+    /// retrying transient failures according to the retry policy.
+    async fn send_request<F>(&self, method: &str, build_payload: F) -> Result<reqwest::Response>
+    where
+        F: Fn(&str) -> Value,
+    {
+        let result = self.send_request_inner(method, build_payload).await;
+        if result.as_ref().is_err_and(is_project_error) {
+            // The cached project may be stale; discover it again next time.
+            *self.project_id.lock().await = None;
+            self.oauth_manager.cache_project_id(None);
+        }
+        self.observe(result)
+    }
+
+    async fn send_request_inner<F>(
+        &self,
+        method: &str,
+        build_payload: F,
+    ) -> Result<reqwest::Response>
+    where
+        F: Fn(&str) -> Value,
+    {
+        let url = format!("{}:{}", self.api_endpoint, method);
+        // Ties together the log lines of every attempt of this request.
+        let request_id = uuid::Uuid::new_v4().to_string();
+        let mut auth_retried = false;
+        let mut force_refresh = false;
+        let mut attempt: u32 = 0;
+        // Whether the token sent before any auth retry was still valid by the
+        // local clock, so a rejection of it may point at clock skew.
+        let mut token_locally_valid = false;
+
+        loop {
+            if !auth_retried {
+                token_locally_valid = self
+                    .oauth_manager
+                    .token_expires_in()
+                    .await
+                    .is_some_and(|remaining| !remaining.is_zero());
+            }
+            let headers = self.get_auth_headers(force_refresh).await?;
+            force_refresh = false;
+            let access_token = headers
                 .iter()
                 .find(|(k, _)| k == "Authorization")
                 .map(|(_, v)| v.replace("Bearer ", ""))
                 .unwrap_or_default();
 
             let project_id = self.ensure_project_id(&access_token).await?;
-            let url = format!(
-                "{}:streamGenerateContent?alt=sse",
-                self.oauth_manager.get_api_endpoint()
-            );
-
-            let payload = self.build_request_payload(
-                model,
-                messages,
-                generation_config,
-                thinking_config,
-                tools,
-                &project_id,
-            );
+            let body = self.encode_payload(&build_payload(&project_id))?;
 
             let mut request = self.http_client.post(&url);
             for (key, value) in &headers {
                 request = request.header(key.as_str(), value.as_str());
             }
 
-            let response = request.json(&payload).send().await?;
+            let response = match self.execute(request.body(body), &request_id, attempt).await {
+                Ok(response) => response,
+                Err(e)
+                    if (e.is_connect() || e.is_timeout())
+                        && attempt < self.retry_policy.max_retries =>
+                {
+                    let delay = self.retry_policy.delay_for_attempt(attempt);
+                    log::warn!(
+                        request_id = request_id.as_str(),
+                        endpoint = url.as_str(),
+                        attempt = attempt + 1,
+                        max_retries = self.retry_policy.max_retries,
+                        delay_ms = delay.as_millis() as u64,
+                        error = e.to_string().as_str();
+                        "Request to {} failed ({}), retrying in {:?} (attempt {}/{})",
+                        method,
+                        e,
+                        delay,
+                        attempt + 1,
+                        self.retry_policy.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
             let status = response.status().as_u16();
 
-            if (status == HTTP_UNAUTHORIZED || status == HTTP_FORBIDDEN) && retry_count == 0 {
+            if (status == HTTP_UNAUTHORIZED || status == HTTP_FORBIDDEN) && !auth_retried {
                 self.oauth_manager.invalidate_credentials();
-                return self
-                    .complete_streaming_impl(
-                        model,
-                        messages,
-                        generation_config,
-                        thinking_config,
-                        tools,
-                        1,
-                    )
-                    .await;
+                auth_retried = true;
+                force_refresh = true;
+                continue;
             }
 
-            if !response.status().is_success() {
-                let body = response.text().await.unwrap_or_default();
-                return Err(self.handle_http_error(status, &body));
-            }
-
-            let bytes_stream = response.bytes_stream();
-            let stream = bytes_stream.map(move |chunk_result| {
-                match chunk_result {
-                    Ok(bytes) => {
-                        let text = String::from_utf8_lossy(&bytes);
-                        let mut chunks = Vec::new();
-
-                        for line in text.lines() {
-                            let trimmed = line.trim();
-                            if trimmed.is_empty() || trimmed.starts_with(':') {
-                                continue;
-                            }
-
-                            if let Some(data) = trimmed.strip_prefix("data:") {
-                                let data = data.trim();
-                                if data == "[DONE]" {
-                                    continue;
-                                }
-
-                                if let Ok(parsed) = serde_json::from_str::<Value>(data) {
-                                    let chunk = parse_chunk(&parsed);
-                                    chunks.push(Ok(chunk));
-                                }
-                            }
-                        }
+            if response.status().is_success() {
+                return Ok(response);
+            }
 
-                        if chunks.is_empty() {
-                            Ok(LLMChunk::default())
-                        } else {
-                            chunks.into_iter().next().unwrap_or(Ok(LLMChunk::default()))
-                        }
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+
+            if RetryPolicy::is_retryable_status(status) && attempt < self.retry_policy.max_retries {
+                // Retrying cannot help once the daily quota is used up.
+                if status == 429 {
+                    let body = response.text().await.unwrap_or_default();
+                    if is_quota_exhausted(&error_object(&body)) {
+                        return Err(self.handle_http_error(status, &body, retry_after, None));
                     }
-                    Err(e) => Err(GeminiSDKError::stream(e.to_string())),
                 }
-            });
+                let delay = self
+                    .retry_policy
+                    .delay_with_retry_after(attempt, retry_after);
+                log::warn!(
+                    request_id = request_id.as_str(),
+                    endpoint = url.as_str(),
+                    attempt = attempt + 1,
+                    max_retries = self.retry_policy.max_retries,
+                    delay_ms = delay.as_millis() as u64,
+                    status = status;
+                    "Request to {} returned {}, retrying in {:?} (attempt {}/{})",
+                    method,
+                    status,
+                    delay,
+                    attempt + 1,
+                    self.retry_policy.max_retries
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
 
-            Ok(Box::pin(stream) as Pin<Box<dyn Stream<Item = Result<LLMChunk>> + Send>>)
-        })
+            // A rejected token that is valid by the local clock often means
+            // the clock is wrong.
+            let clock_skew = if (status == HTTP_UNAUTHORIZED || status == HTTP_FORBIDDEN)
+                && token_locally_valid
+            {
+                self.oauth_manager.clock_skew_hint(
+                    response
+                        .headers()
+                        .get(reqwest::header::DATE)
+                        .and_then(|v| v.to_str().ok()),
+                )
+            } else {
+                None
+            };
+
+            let body = response.text().await.unwrap_or_default();
+            return Err(self.handle_http_error(status, &body, retry_after, clock_skew));
+        }
     }
 
-    fn handle_http_error(&self, status: u16, body: &str) -> GeminiSDKError {
-        let error_msg = if let Ok(data) = serde_json::from_str::<Value>(body) {
-            data.get("error")
-                .and_then(|e| e.get("message"))
-                .and_then(|m| m.as_str())
-                .unwrap_or(body)
-                .to_string()
-        } else {
-            body.to_string()
-        };
+    fn handle_http_error(
+        &self,
+        status: u16,
+        body: &str,
+        retry_after: Option<u64>,
+        clock_skew: Option<String>,
+    ) -> GeminiSDKError {
+        let error = error_object(body);
+        let mut error_msg = error
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or(body)
+            .to_string();
+        if let Some(hint) = clock_skew {
+            error_msg = format!("{} ({})", error_msg, hint);
+        }
 
         match status {
-            429 => GeminiSDKError::rate_limit(format!("Rate limit exceeded: {}", error_msg)),
-            403 => GeminiSDKError::permission_denied(format!("Permission denied: {}", error_msg)),
-            _ => GeminiSDKError::api_error(format!("API error: {}", error_msg), status),
+            429 if is_quota_exhausted(&error) => GeminiSDKError::QuotaExceeded {
+                message: format!("Quota exceeded: {}", error_msg),
+                status_code: status,
+                reset_time: quota_reset_time(&error),
+                response_body: Some(body.to_string()),
+            },
+            429 => GeminiSDKError::RateLimit {
+                message: format!("Rate limit exceeded: {}", error_msg),
+                status_code: status,
+                retry_after,
+                response_body: Some(body.to_string()),
+            },
+            403 => GeminiSDKError::PermissionDenied {
+                message: format!("Permission denied: {}", error_msg),
+                status_code: status,
+                response_body: Some(body.to_string()),
+            },
+            _ => GeminiSDKError::Api {
+                message: format!("API error: {}", error_msg),
+                status_code: status,
+                response_body: Some(body.to_string()),
+                endpoint: None,
+            },
         }
     }
 
@@ -674,19 +1476,321 @@ impl GeminiBackend {
     }
 }
 
+/// The vectors of an `embedContent` (`embedding`) or `batchEmbedContents`
+/// (`embeddings`) response.
+fn parse_embeddings(data: &Value) -> Vec<Vec<f32>> {
+    let embeddings = match data.get("embeddings").and_then(|e| e.as_array()) {
+        Some(items) => items.iter().collect(),
+        None => data.get("embedding").into_iter().collect::<Vec<_>>(),
+    };
+    embeddings
+        .into_iter()
+        .filter_map(|embedding| embedding.get("values")?.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|v| v.as_f64())
+                .map(|v| v as f32)
+                .collect()
+        })
+        .collect()
+}
+
+/// The `error` object of a Google API error body, or `Null`.
+fn error_object(body: &str) -> Value {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|data| data.get("error").cloned())
+        .unwrap_or(Value::Null)
+}
+
+/// The `details` entries of a Google API error with the given `@type` suffix,
+/// e.g. `ErrorInfo` or `QuotaFailure`.
+fn error_details<'a>(error: &'a Value, kind: &'a str) -> impl Iterator<Item = &'a Value> + 'a {
+    error
+        .get("details")
+        .and_then(|d| d.as_array())
+        .into_iter()
+        .flatten()
+        .filter(move |d| {
+            d.get("@type")
+                .and_then(|t| t.as_str())
+                .is_some_and(|t| t.ends_with(&format!(".{}", kind)))
+        })
+}
+
+/// Whether a `RESOURCE_EXHAUSTED` error is an exhausted (daily) quota rather
+/// than short-term throttling: an `ErrorInfo` with reason `QUOTA_EXHAUSTED`
+/// or a `QuotaFailure` violation on a per-day quota.
+fn is_quota_exhausted(error: &Value) -> bool {
+    if error.get("status").and_then(|s| s.as_str()) != Some("RESOURCE_EXHAUSTED") {
+        return false;
+    }
+
+    let exhausted_reason = error_details(error, "ErrorInfo")
+        .any(|info| info.get("reason").and_then(|r| r.as_str()) == Some("QUOTA_EXHAUSTED"));
+    let daily_violation = error_details(error, "QuotaFailure")
+        .filter_map(|failure| failure.get("violations").and_then(|v| v.as_array()))
+        .flatten()
+        .any(|violation| {
+            ["quotaId", "description"].iter().any(|key| {
+                violation
+                    .get(*key)
+                    .and_then(|v| v.as_str())
+                    .is_some_and(|v| v.contains("PerDay") || v.to_lowercase().contains("per day"))
+            })
+        });
+    exhausted_reason || daily_violation
+}
+
+/// When an exhausted quota resets: the `ErrorInfo` reset timestamp, or else
+/// the `RetryInfo` delay (e.g. `"3600s"`).
+fn quota_reset_time(error: &Value) -> Option<String> {
+    error_details(error, "ErrorInfo")
+        .find_map(|info| info.get("metadata")?.get("quotaResetTimeStamp")?.as_str())
+        .or_else(|| {
+            error_details(error, "RetryInfo").find_map(|info| info.get("retryDelay")?.as_str())
+        })
+        .map(String::from)
+}
+
+/// Reads a successful response as JSON, reporting an empty or malformed body
+/// as an API error instead of a bare decode error.
+async fn read_json_body(response: reqwest::Response) -> Result<Value> {
+    let status = response.status().as_u16();
+    let endpoint = response.url().to_string();
+    let body = response.text().await?;
+
+    let message = if body.trim().is_empty() {
+        format!("Backend returned an empty response body (HTTP {})", status)
+    } else {
+        match serde_json::from_str(&body) {
+            Ok(data) => return Ok(data),
+            Err(e) => format!(
+                "Backend returned an invalid JSON response body (HTTP {}): {}",
+                status, e
+            ),
+        }
+    };
+
+    Err(GeminiSDKError::Api {
+        message,
+        status_code: status,
+        response_body: Some(body),
+        endpoint: Some(endpoint),
+    })
+}
+
+struct SseState<S> {
+    inner: S,
+    buffer: Vec<u8>,
+    pending: VecDeque<Result<LLMChunk>>,
+    /// `data:` payload that did not parse yet, awaiting its continuation.
+    partial: Option<String>,
+    strict: bool,
+    done: bool,
+}
+
+impl<S> SseState<S> {
+    fn handle_line(&mut self, line: &str) {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            // End of event: anything still unparsed is malformed.
+            self.flush_partial();
+            return;
+        }
+        if trimmed.starts_with(':') {
+            return;
+        }
+
+        let data = match trimmed.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => return,
+        };
+        if data == "[DONE]" {
+            return;
+        }
+
+        if let Some(partial) = self.partial.take() {
+            let joined = format!("{}{}", partial, data);
+            if let Ok(parsed) = serde_json::from_str::<Value>(&joined) {
+                self.pending.push_back(Ok(parse_chunk(&parsed)));
+                return;
+            }
+            if serde_json::from_str::<Value>(data).is_err() {
+                self.partial = Some(joined);
+                return;
+            }
+            // This line stands on its own, so the buffered one was garbage.
+            self.partial = Some(partial);
+            self.flush_partial();
+        }
+
+        match serde_json::from_str::<Value>(data) {
+            Ok(parsed) => self.pending.push_back(Ok(parse_chunk(&parsed))),
+            Err(_) => self.partial = Some(data.to_string()),
+        }
+    }
+
+    fn flush_partial(&mut self) {
+        let data = match self.partial.take() {
+            Some(data) => data,
+            None => return,
+        };
+        if self.strict {
+            self.pending.push_back(Err(GeminiSDKError::stream(format!(
+                "Malformed SSE data line: {}",
+                data
+            ))));
+        } else {
+            log::warn!("Skipping malformed SSE data line: {}", data);
+        }
+    }
+}
+
+/// Turns a raw SSE byte stream into parsed chunks.
+///
+/// Lines are buffered across network reads, so events split over several
+/// reads and several events within one read are both handled. A `data:`
+/// payload that isn't valid JSON is held and joined with the next `data:`
+/// line, since a payload may be split across lines; if the event ends
+/// without it becoming valid, it is skipped, or reported as an error when
+/// `strict` is set.
+fn parse_sse_stream<S, B>(inner: S, strict: bool) -> impl Stream<Item = Result<LLMChunk>> + Send
+where
+    S: Stream<Item = reqwest::Result<B>> + Send + Unpin,
+    B: AsRef<[u8]>,
+{
+    let state = SseState {
+        inner,
+        buffer: Vec::new(),
+        pending: VecDeque::new(),
+        partial: None,
+        strict,
+        done: false,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(item) = state.pending.pop_front() {
+                return Some((item, state));
+            }
+            if state.done {
+                return None;
+            }
+
+            match state.inner.next().await {
+                Some(Ok(bytes)) => {
+                    state.buffer.extend_from_slice(bytes.as_ref());
+                    while let Some(pos) = state.buffer.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = state.buffer.drain(..=pos).collect();
+                        state.handle_line(&String::from_utf8_lossy(&line));
+                    }
+                }
+                Some(Err(e)) => {
+                    state.done = true;
+                    state.pending.push_back(Err(GeminiSDKError::from(e)));
+                }
+                None => {
+                    state.done = true;
+                    let rest = std::mem::take(&mut state.buffer);
+                    state.handle_line(&String::from_utf8_lossy(&rest));
+                    state.flush_partial();
+                }
+            }
+        }
+    })
+}
+
+/// Parses a `Retry-After` header value, given either as delay seconds or as
+/// an HTTP-date, into the number of seconds to wait.
+fn parse_retry_after(value: &str) -> Option<u64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(seconds);
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let delta = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(delta.num_seconds().max(0) as u64)
+}
+
 fn parse_chunk(data: &Value) -> LLMChunk {
+    parse_candidates(data)
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| LLMChunk {
+            usage: parse_usage(data),
+            ..Default::default()
+        })
+}
+
+/// Why the prompt was rejected, from `promptFeedback.blockReason`.
+fn prompt_block_reason(data: &Value) -> Option<BlockReason> {
+    let response_data = data.get("response").unwrap_or(data);
+    response_data
+        .get("promptFeedback")
+        .and_then(|f| f.get("blockReason"))
+        .and_then(|v| v.as_str())
+        .map(BlockReason::from_prompt_feedback)
+}
+
+/// One chunk per candidate in the response. Usage covers the whole request,
+/// so every chunk carries the same totals.
+fn parse_candidates(data: &Value) -> Vec<LLMChunk> {
     let response_data = data.get("response").unwrap_or(data);
     let candidates = response_data
         .get("candidates")
         .and_then(|v| v.as_array())
         .cloned()
         .unwrap_or_default();
+    let usage = parse_usage(data);
 
+    // A rejected prompt gets no candidates; report it as one blocked chunk.
     if candidates.is_empty() {
-        return LLMChunk::default();
+        if let Some(reason) = prompt_block_reason(data) {
+            log::warn!("Prompt blocked by the backend: {:?}", reason);
+            return vec![LLMChunk {
+                usage,
+                block_reason: Some(reason),
+                ..Default::default()
+            }];
+        }
     }
 
-    let candidate = &candidates[0];
+    candidates
+        .iter()
+        .map(|candidate| parse_candidate(candidate, usage.clone()))
+        .collect()
+}
+
+fn parse_usage(data: &Value) -> Option<LLMUsage> {
+    let response_data = data.get("response").unwrap_or(data);
+    let usage_data = data
+        .get("usageMetadata")
+        .or_else(|| response_data.get("usageMetadata"));
+
+    usage_data.map(|u| LLMUsage {
+        prompt_tokens: u
+            .get("promptTokenCount")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+        completion_tokens: u
+            .get("candidatesTokenCount")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+        total_tokens: u
+            .get("totalTokenCount")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+        thoughts_tokens: u
+            .get("thoughtsTokenCount")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0),
+    })
+}
+
+fn parse_candidate(candidate: &Value, usage: Option<LLMUsage>) -> LLMChunk {
     let empty_obj = json!({});
     let content_obj = candidate.get("content").unwrap_or(&empty_obj);
     let parts = content_obj
@@ -698,13 +1802,66 @@ fn parse_chunk(data: &Value) -> LLMChunk {
     let mut text_content = String::new();
     let mut reasoning_content: Option<String> = None;
     let mut tool_calls: Vec<ToolCall> = Vec::new();
+    let mut code_execution: Vec<CodeExecution> = Vec::new();
 
     for part in &parts {
-        if let Some(text) = part.get("text").and_then(|v| v.as_str()) {
-            text_content.push_str(text);
+        if let Some(code) = part.get("executableCode") {
+            code_execution.push(CodeExecution {
+                language: code
+                    .get("language")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                code: code
+                    .get("code")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                outcome: None,
+                output: None,
+            });
+        }
+        if let Some(result) = part.get("codeExecutionResult") {
+            let outcome = result
+                .get("outcome")
+                .and_then(|v| v.as_str())
+                .map(String::from);
+            let output = result
+                .get("output")
+                .and_then(|v| v.as_str())
+                .map(String::from);
+            match code_execution.last_mut() {
+                Some(last) if last.outcome.is_none() => {
+                    last.outcome = outcome;
+                    last.output = output;
+                }
+                _ => code_execution.push(CodeExecution {
+                    outcome,
+                    output,
+                    ..Default::default()
+                }),
+            }
         }
-        if let Some(thought) = part.get("thought").and_then(|v| v.as_str()) {
-            reasoning_content = Some(thought.to_string());
+        let text = part.get("text").and_then(|v| v.as_str());
+        match part.get("thought") {
+            // Gemini flags reasoning parts with `thought: true` on the text.
+            Some(Value::Bool(true)) => {
+                if let Some(text) = text {
+                    reasoning_content
+                        .get_or_insert_with(String::new)
+                        .push_str(text);
+                }
+            }
+            thought => {
+                if let Some(text) = text {
+                    text_content.push_str(text);
+                }
+                if let Some(thought) = thought.and_then(|v| v.as_str()) {
+                    reasoning_content
+                        .get_or_insert_with(String::new)
+                        .push_str(thought);
+                }
+            }
         }
         if let Some(fc) = part.get("functionCall") {
             let name = fc.get("name").and_then(|v| v.as_str()).unwrap_or("");
@@ -725,26 +1882,17 @@ fn parse_chunk(data: &Value) -> LLMChunk {
         }
     }
 
-    let usage_data = data
-        .get("usageMetadata")
-        .or_else(|| response_data.get("usageMetadata"));
-
-    let usage = usage_data.map(|u| LLMUsage {
-        prompt_tokens: u
-            .get("promptTokenCount")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0),
-        completion_tokens: u
-            .get("candidatesTokenCount")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0),
-        total_tokens: u
-            .get("totalTokenCount")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0),
-    });
+    let finish_reason = candidate
+        .get("finishReason")
+        .and_then(|v| v.as_str())
+        .map(String::from);
 
     LLMChunk {
+        thinking_truncated: is_thinking_truncated(
+            finish_reason.as_deref(),
+            &text_content,
+            reasoning_content.as_deref(),
+        ),
         content: text_content,
         reasoning_content,
         tool_calls: if tool_calls.is_empty() {
@@ -753,14 +1901,1856 @@ fn parse_chunk(data: &Value) -> LLMChunk {
             Some(tool_calls)
         },
         usage,
-        finish_reason: candidate
-            .get("finishReason")
-            .and_then(|v| v.as_str())
-            .map(String::from),
+        block_reason: BlockReason::from_finish_reason(finish_reason.as_deref()),
+        finish_reason,
+        grounding: candidate.get("groundingMetadata").map(parse_grounding),
+        code_execution: if code_execution.is_empty() {
+            None
+        } else {
+            Some(code_execution)
+        },
     }
 }
 
+fn parse_grounding(metadata: &Value) -> GroundingMetadata {
+    let web_search_queries = metadata
+        .get("webSearchQueries")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|q| q.as_str().map(String::from))
+        .collect();
+    let sources = metadata
+        .get("groundingChunks")
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|chunk| {
+            let web = chunk.get("web")?;
+            Some(GroundingSource {
+                uri: web.get("uri")?.as_str()?.to_string(),
+                title: web.get("title").and_then(|t| t.as_str()).map(String::from),
+            })
+        })
+        .collect();
+    GroundingMetadata {
+        web_search_queries,
+        sources,
+    }
+}
+
+/// Reassembles tool calls whose arguments are streamed in pieces.
+///
+/// Object arguments are complete on arrival. String arguments are treated as
+/// JSON fragments and appended to the pending call with the same name (or to
+/// the last pending call when the fragment has no name) until the buffer
+/// parses as JSON. Calls are released by `finish`, once the stream reports a
+/// finish reason or ends.
+#[derive(Debug, Default)]
+pub(crate) struct ToolCallAccumulator {
+    calls: Vec<ToolCall>,
+    fragments: Vec<Option<String>>,
+}
+
+impl ToolCallAccumulator {
+    pub(crate) fn push(&mut self, tool_calls: Vec<ToolCall>) {
+        for call in tool_calls {
+            let fragment = match &call.function.arguments {
+                Value::String(fragment) => fragment.clone(),
+                _ => {
+                    self.calls.push(call);
+                    self.fragments.push(None);
+                    continue;
+                }
+            };
+
+            let pending = self.fragments.iter().rposition(|buffer| {
+                buffer
+                    .as_deref()
+                    .is_some_and(|b| serde_json::from_str::<Value>(b).is_err())
+            });
+            let continues = pending.filter(|&i| {
+                call.function.name.is_empty() || self.calls[i].function.name == call.function.name
+            });
+
+            match continues {
+                Some(i) => {
+                    if let Some(buffer) = self.fragments[i].as_mut() {
+                        buffer.push_str(&fragment);
+                    }
+                }
+                None => {
+                    self.calls.push(call);
+                    self.fragments.push(Some(fragment));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn finish(&mut self) -> Vec<ToolCall> {
+        let fragments = std::mem::take(&mut self.fragments);
+        std::mem::take(&mut self.calls)
+            .into_iter()
+            .zip(fragments)
+            .map(|(mut call, fragment)| {
+                if let Some(buffer) = fragment {
+                    call.function.arguments = if buffer.trim().is_empty() {
+                        json!({})
+                    } else {
+                        serde_json::from_str(&buffer).unwrap_or_else(|e| {
+                            log::warn!(
+                                "Arguments for tool '{}' are not valid JSON: {}",
+                                call.function.name,
+                                e
+                            );
+                            Value::String(buffer)
+                        })
+                    };
+                }
+                call
+            })
+            .collect()
+    }
+}
+
+/// Whether the backend rejected the request because the model itself is
+/// unknown or not enabled for the account: a `NOT_FOUND` error that isn't
+/// about the project.
+pub(crate) fn is_model_unavailable(err: &GeminiSDKError) -> bool {
+    let GeminiSDKError::Api {
+        status_code: 404,
+        response_body: Some(body),
+        ..
+    } = err
+    else {
+        return false;
+    };
+    error_object(body).get("status").and_then(|s| s.as_str()) == Some("NOT_FOUND")
+        && !is_project_error(err)
+}
+
+/// `ErrorInfo` reasons Google APIs give for a project that can't be used.
+const PROJECT_ERROR_REASONS: &[&str] = &[
+    "CONSUMER_INVALID",
+    "CONSUMER_SUSPENDED",
+    "USER_PROJECT_DENIED",
+    "SERVICE_DISABLED",
+];
+
+/// Whether the backend rejected the request's project, e.g. one cached for
+/// an account that has since lost access to it: a 403/404 whose `ErrorInfo`
+/// reason or `ResourceInfo` names the project.
+fn is_project_error(err: &GeminiSDKError) -> bool {
+    let body = match err {
+        GeminiSDKError::Api {
+            status_code: 403 | 404,
+            response_body: Some(body),
+            ..
+        }
+        | GeminiSDKError::PermissionDenied {
+            response_body: Some(body),
+            ..
+        } => body,
+        _ => return false,
+    };
+    let error = error_object(body);
+    let bad_reason = error_details(&error, "ErrorInfo").any(|info| {
+        info.get("reason")
+            .and_then(|r| r.as_str())
+            .is_some_and(|reason| PROJECT_ERROR_REASONS.contains(&reason))
+    });
+    let missing_project = error_details(&error, "ResourceInfo").any(|info| {
+        info.get("resourceType")
+            .and_then(|t| t.as_str())
+            .is_some_and(|t| t.eq_ignore_ascii_case("project") || t.ends_with("/Project"))
+    });
+    bad_reason || missing_project
+}
+
+/// The model ran out of budget while still reasoning: generation stopped on
+/// `MAX_TOKENS` after thoughts were produced but before any answer text.
+pub(crate) fn is_thinking_truncated(
+    finish_reason: Option<&str>,
+    content: &str,
+    reasoning: Option<&str>,
+) -> bool {
+    finish_reason == Some("MAX_TOKENS")
+        && content.trim().is_empty()
+        && reasoning.is_some_and(|r| !r.is_empty())
+}
+
+/// The `fileData` or `inlineData` part for a part's media, if it has any.
+fn media_part(part: &ContentPart) -> Option<Value> {
+    let mime = part.image_mime_type.as_deref();
+    if let Some(uri) = &part.file_uri {
+        let mut file_data = json!({"fileUri": uri});
+        if let Some(mime) = mime {
+            file_data["mimeType"] = json!(mime);
+        }
+        return Some(json!({"fileData": file_data}));
+    }
+    let (Some(data), Some(mime)) = (&part.image_data, mime) else {
+        return None;
+    };
+    Some(json!({
+        "inlineData": {
+            "mimeType": mime,
+            "data": base64_encode(data)
+        }
+    }))
+}
+
 fn base64_encode(data: &[u8]) -> String {
     use base64::{Engine as _, engine::general_purpose::STANDARD};
     STANDARD.encode(data)
 }
+
+#[cfg(test)]
+impl GeminiBackend {
+    /// Builds a backend pointed at a mock server, with test credentials and a
+    /// pre-resolved project so no onboarding calls are made.
+    pub(crate) fn for_tests(base_url: &str) -> Self {
+        let mut backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(base_url.to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.project_id = Arc::new(Mutex::new(Some("test-project".to_string())));
+        backend
+    }
+
+    /// Forgets the test project so the next request resolves it again.
+    #[cfg(test)]
+    pub(crate) fn without_project(mut self) -> Self {
+        self.project_id = Arc::new(Mutex::new(None));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{text_response, MockResponse, MockServer, OffsetClock};
+    use crate::types::{
+        ContentPart, FunctionCallingMode, HarmBlockThreshold, HarmCategory, ToolConfig,
+    };
+
+    fn text_message(role: Role, text: &str) -> Message {
+        Message {
+            role,
+            content: MessageContent::Text(text.to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        }
+    }
+
+    #[test]
+    fn test_tool_config_is_serialized() {
+        let backend = GeminiBackend::new(BackendOptions::default()).unwrap();
+        let config = GenerationConfig {
+            tool_config: Some(ToolConfig {
+                mode: FunctionCallingMode::Any,
+                allowed_function_names: Some(vec!["extract_invoice".to_string()]),
+            }),
+            ..Default::default()
+        };
+
+        let payload = backend.build_request_payload(
+            "gemini-2.5-pro",
+            &[],
+            RequestOptions {
+                generation_config: Some(&config),
+                ..Default::default()
+            },
+            "",
+        );
+
+        assert_eq!(
+            payload["request"]["toolConfig"],
+            json!({"functionCallingConfig": {"mode": "ANY", "allowedFunctionNames": ["extract_invoice"]}})
+        );
+        assert!(payload["request"]["generationConfig"]
+            .get("toolConfig")
+            .is_none());
+    }
+
+    #[test]
+    fn test_sampling_penalties_and_seed_are_serialized() {
+        let backend = GeminiBackend::new(BackendOptions::default()).unwrap();
+        let config = GenerationConfig::builder()
+            .presence_penalty(0.5)
+            .frequency_penalty(-0.25)
+            .seed(42)
+            .build();
+
+        let payload = backend.build_request_payload(
+            "gemini-2.5-pro",
+            &[],
+            RequestOptions {
+                generation_config: Some(&config),
+                ..Default::default()
+            },
+            "",
+        );
+        let generation = &payload["request"]["generationConfig"];
+        assert_eq!(generation["seed"], 42);
+        assert_eq!(generation["presencePenalty"], 0.5);
+        assert_eq!(generation["frequencyPenalty"], -0.25);
+
+        let payload =
+            backend.build_request_payload("gemini-2.5-pro", &[], RequestOptions::default(), "");
+        let generation = &payload["request"]["generationConfig"];
+        for key in ["seed", "presencePenalty", "frequencyPenalty"] {
+            assert!(generation.get(key).is_none(), "{} in {}", key, generation);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_structured_output() {
+        #[derive(serde::Deserialize)]
+        struct Person {
+            name: String,
+            age: u32,
+        }
+
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            text_response(r#"{"name": "Ada", "age": 36}"#),
+        )])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+        let schema = json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}, "age": {"type": "integer"}}
+        });
+        let config = GenerationConfig {
+            response_mime_type: Some("application/json".to_string()),
+            response_schema: Some(schema.clone()),
+            ..Default::default()
+        };
+
+        let chunk = backend
+            .complete(
+                "gemini-2.5-pro",
+                &[],
+                RequestOptions {
+                    generation_config: Some(&config),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let sent = server.requests()[0].json();
+        assert_eq!(
+            sent["request"]["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+        assert_eq!(
+            sent["request"]["generationConfig"]["responseSchema"],
+            schema
+        );
+
+        let person: Person = chunk.parse_json().unwrap();
+        assert_eq!(person.name, "Ada");
+        assert_eq!(person.age, 36);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_is_rejected_before_sending() {
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hi"))]).await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.max_request_bytes = 1024;
+
+        let messages = vec![Message {
+            role: Role::User,
+            content: MessageContent::Parts(vec![ContentPart {
+                text: Some("Describe this image".to_string()),
+                image_url: None,
+                image_data: Some(vec![0u8; 4096]),
+                image_mime_type: Some("image/png".to_string()),
+                file_uri: None,
+            }]),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        }];
+
+        let result = backend
+            .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await;
+        assert!(matches!(result, Err(GeminiSDKError::Validation { .. })));
+        assert!(server.requests().is_empty());
+    }
+
+    fn fast_retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_model_unavailable_needs_not_found_status() {
+        let backend = GeminiBackend::for_tests("http://localhost");
+        let error = |status: u16, body: Value| {
+            backend.handle_http_error(status, &body.to_string(), None, None)
+        };
+
+        assert!(is_model_unavailable(&error(
+            404,
+            json!({"error": {"code": 404, "message": "Requested entity was not found.", "status": "NOT_FOUND"}}),
+        )));
+        assert!(!is_model_unavailable(&error(
+            400,
+            json!({"error": {
+                "code": 400,
+                "message": "Invalid value at 'generation_config.temperature': model limit is 2",
+                "status": "INVALID_ARGUMENT"
+            }}),
+        )));
+        assert!(!is_model_unavailable(&error(
+            404,
+            json!({"error": {"code": 404, "message": "Tuned model not found"}}),
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_daily_quota_is_not_retried() {
+        let server = MockServer::start(vec![MockResponse::json(
+            429,
+            json!({"error": {
+                "code": 429,
+                "message": "Quota exceeded for quota metric 'Requests per day'",
+                "status": "RESOURCE_EXHAUSTED",
+                "details": [
+                    {
+                        "@type": "type.googleapis.com/google.rpc.QuotaFailure",
+                        "violations": [{"quotaId": "GenerateRequestsPerDayPerProjectPerModel"}]
+                    },
+                    {
+                        "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                        "reason": "QUOTA_EXHAUSTED",
+                        "metadata": {"quotaResetTimeStamp": "2025-01-02T00:00:00Z"}
+                    }
+                ]
+            }}),
+        )])
+        .await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.retry_policy = fast_retry_policy();
+
+        let err = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap_err();
+
+        match err {
+            GeminiSDKError::QuotaExceeded {
+                status_code,
+                reset_time,
+                ..
+            } => {
+                assert_eq!(status_code, 429);
+                assert_eq!(reset_time.as_deref(), Some("2025-01-02T00:00:00Z"));
+            }
+            other => panic!("expected QuotaExceeded, got {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_throttling_429_is_rate_limit() {
+        let server = MockServer::start(vec![MockResponse::json(
+            429,
+            json!({"error": {
+                "code": 429,
+                "message": "Resource has been exhausted (e.g. check quota).",
+                "status": "RESOURCE_EXHAUSTED",
+                "details": [{
+                    "@type": "type.googleapis.com/google.rpc.RetryInfo",
+                    "retryDelay": "2s"
+                }]
+            }}),
+        )
+        .with_header("Retry-After", "2")])
+        .await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.retry_policy = RetryPolicy::none();
+
+        let err = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(
+                err,
+                GeminiSDKError::RateLimit {
+                    retry_after: Some(2),
+                    ..
+                }
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_complete_n_returns_every_candidate() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"response": {
+                "candidates": [
+                    {"content": {"role": "model", "parts": [{"text": "Paris"}]}, "finishReason": "STOP"},
+                    {"content": {"role": "model", "parts": [{"text": "It's Paris."}]}, "finishReason": "STOP"}
+                ],
+                "usageMetadata": {"promptTokenCount": 4, "candidatesTokenCount": 5, "totalTokenCount": 9}
+            }}),
+        )])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+        let config = GenerationConfig {
+            candidate_count: Some(2),
+            ..Default::default()
+        };
+
+        let chunks = backend
+            .complete_n(
+                "gemini-2.5-pro",
+                &[],
+                RequestOptions {
+                    generation_config: Some(&config),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.requests()[0].json()["request"]["generationConfig"]["candidateCount"],
+            2
+        );
+        let contents: Vec<&str> = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(contents, ["Paris", "It's Paris."]);
+        assert_eq!(chunks[1].usage.as_ref().unwrap().total_tokens, 9);
+    }
+
+    #[tokio::test]
+    async fn test_google_search_tool_and_grounding() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"response": {"candidates": [{
+                "content": {"role": "model", "parts": [{"text": "Spain won."}]},
+                "finishReason": "STOP",
+                "groundingMetadata": {
+                    "webSearchQueries": ["euro 2024 winner"],
+                    "groundingChunks": [
+                        {"web": {"uri": "https://example.com/euro", "title": "example.com"}}
+                    ]
+                }
+            }]}}),
+        )])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+        let config = GenerationConfig::builder()
+            .builtin_tool(BuiltinTool::GoogleSearch)
+            .build();
+        let tools = vec![crate::tools::create_tool(
+            "lookup",
+            "Look something up",
+            None,
+        )];
+
+        let chunk = backend
+            .complete(
+                "gemini-2.5-pro",
+                &[],
+                RequestOptions {
+                    generation_config: Some(&config),
+                    tools: Some(&tools),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let sent = server.requests()[0].json();
+        assert_eq!(
+            sent["request"]["tools"][0]["functionDeclarations"][0]["name"],
+            "lookup"
+        );
+        assert_eq!(sent["request"]["tools"][1], json!({"googleSearch": {}}));
+        assert_eq!(
+            chunk.grounding,
+            Some(GroundingMetadata {
+                web_search_queries: vec!["euro 2024 winner".to_string()],
+                sources: vec![GroundingSource {
+                    uri: "https://example.com/euro".to_string(),
+                    title: Some("example.com".to_string()),
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_code_execution_parts_are_parsed() {
+        let chunk = parse_chunk(&json!({"response": {"candidates": [{
+            "content": {"role": "model", "parts": [
+                {"text": "Let me compute that."},
+                {"executableCode": {"language": "PYTHON", "code": "print(2 ** 10)"}},
+                {"codeExecutionResult": {"outcome": "OUTCOME_OK", "output": "1024\n"}},
+                {"text": "It is 1024."}
+            ]},
+            "finishReason": "STOP"
+        }]}}));
+
+        assert_eq!(chunk.content, "Let me compute that.It is 1024.");
+        assert_eq!(
+            chunk.code_execution,
+            Some(vec![CodeExecution {
+                language: "PYTHON".to_string(),
+                code: "print(2 ** 10)".to_string(),
+                outcome: Some("OUTCOME_OK".to_string()),
+                output: Some("1024\n".to_string()),
+            }])
+        );
+
+        let backend = GeminiBackend::for_tests("http://localhost");
+        let config = GenerationConfig::builder()
+            .builtin_tool(BuiltinTool::CodeExecution)
+            .build();
+        let payload = backend.build_request_payload(
+            "gemini-2.5-pro",
+            &[],
+            RequestOptions {
+                generation_config: Some(&config),
+                ..Default::default()
+            },
+            "p",
+        );
+        assert_eq!(payload["request"]["tools"], json!([{"codeExecution": {}}]));
+    }
+
+    #[tokio::test]
+    async fn test_large_inline_data_is_uploaded_once() {
+        // The upload URL handed out by the start request may point anywhere.
+        let session = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"file": {
+                "name": "files/abc",
+                "uri": "https://files.example/abc",
+                "mimeType": "application/pdf",
+                "sizeBytes": "64"
+            }}),
+        )])
+        .await;
+        let server = MockServer::start(vec![MockResponse::json(200, json!({})).with_header(
+            "X-Goog-Upload-URL",
+            &format!("{}/upload-session", session.url()),
+        )])
+        .await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.files_endpoint = server.url();
+        backend.inline_data_limit = Some(32);
+
+        let part = |size: usize| ContentPart {
+            text: None,
+            image_url: None,
+            image_data: Some(vec![7u8; size]),
+            image_mime_type: Some("application/pdf".to_string()),
+            file_uri: None,
+        };
+        let mut messages = vec![Message {
+            role: Role::User,
+            content: MessageContent::Parts(vec![part(64), part(16)]),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        }];
+
+        for _ in 0..2 {
+            for mut part in backend.large_parts(&messages) {
+                backend.upload_large_part(&mut part).await.unwrap();
+                part.apply(&mut messages);
+            }
+        }
+
+        let start = server.requests();
+        assert_eq!(start.len(), 1);
+        assert_eq!(start[0].path, "/upload/v1beta/files");
+        assert_eq!(start[0].header("x-goog-upload-protocol"), Some("resumable"));
+        assert_eq!(
+            start[0].header("x-goog-upload-header-content-length"),
+            Some("64")
+        );
+        let upload = session.requests();
+        assert_eq!(upload.len(), 1);
+        assert_eq!(upload[0].path, "/upload-session");
+        assert_eq!(
+            upload[0].header("x-goog-upload-command"),
+            Some("upload, finalize")
+        );
+        assert_eq!(upload[0].body.len(), 64);
+
+        let contents = backend.prepare_messages(&messages);
+        assert_eq!(
+            contents[0]["parts"][0],
+            json!({"fileData": {"fileUri": "https://files.example/abc", "mimeType": "application/pdf"}})
+        );
+        assert!(contents[0]["parts"][1]["inlineData"].is_object());
+
+        // A part replaced while its copy was uploading keeps the new data.
+        let mut edited = vec![Message {
+            content: MessageContent::Parts(vec![part(64)]),
+            ..messages[0].clone()
+        }];
+        let mut stale = backend.large_parts(&edited).remove(0);
+        stale.file_uri = Some("https://files.example/old".to_string());
+        edited[0].content = MessageContent::Parts(vec![ContentPart {
+            image_data: Some(vec![9u8; 64]),
+            ..part(0)
+        }]);
+        stale.apply(&mut edited);
+        assert!(backend.prepare_messages(&edited)[0]["parts"][0]["inlineData"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        let server = MockServer::start(vec![
+            MockResponse::json(503, json!({"error": {"message": "unavailable"}})),
+            MockResponse::json(429, json!({"error": {"message": "slow down"}}))
+                .with_header("Retry-After", "0"),
+            MockResponse::json(200, text_response("recovered")),
+        ])
+        .await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.retry_policy = fast_retry_policy();
+
+        let chunk = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(chunk.content, "recovered");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        calls: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl RequestObserver for RecordingObserver {
+        fn on_request(&self, request: &RequestInfo) {
+            let auth = request
+                .headers
+                .iter()
+                .find(|(k, _)| k == "authorization")
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default();
+            self.calls.lock().unwrap().push(format!(
+                "request {} {} attempt={} auth={}",
+                request.method,
+                request.url.rsplit(':').next().unwrap_or_default(),
+                request.attempt,
+                auth
+            ));
+        }
+
+        fn on_response(&self, response: &ResponseInfo) {
+            self.calls.lock().unwrap().push(format!(
+                "response {} attempt={}",
+                response.status, response.attempt
+            ));
+        }
+
+        fn on_error(&self, error: &GeminiSDKError) {
+            self.calls.lock().unwrap().push(format!("error {}", error));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_observer_sees_each_attempt_and_final_error() {
+        let server = MockServer::start(vec![
+            MockResponse::json(503, json!({"error": {"message": "unavailable"}})),
+            MockResponse::json(200, text_response("recovered")),
+            MockResponse::json(400, json!({"error": {"message": "bad request"}})),
+        ])
+        .await;
+        let observer = Arc::new(RecordingObserver::default());
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.retry_policy = fast_retry_policy();
+        backend.observer = Some(observer.clone());
+
+        backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap();
+        backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap_err();
+
+        let calls = observer.calls.lock().unwrap().clone();
+        assert_eq!(
+            calls[..4],
+            [
+                "request POST generateContent attempt=0 auth=[REDACTED]",
+                "response 503 attempt=0",
+                "request POST generateContent attempt=1 auth=[REDACTED]",
+                "response 200 attempt=1",
+            ]
+        );
+        assert_eq!(
+            calls[4],
+            "request POST generateContent attempt=0 auth=[REDACTED]"
+        );
+        assert_eq!(calls[5], "response 400 attempt=0");
+        assert!(calls[6].starts_with("error "), "{:?}", calls);
+        assert!(calls[6].contains("bad request"), "{:?}", calls);
+        assert_eq!(calls.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_retry_attempts_are_logged_with_metadata() {
+        let logs = crate::test_support::capture_logs();
+        let server = MockServer::start(vec![
+            MockResponse::json(503, json!({"error": {"message": "unavailable"}})),
+            MockResponse::json(429, json!({"error": {"message": "slow down"}}))
+                .with_header("Retry-After", "0"),
+            MockResponse::json(200, text_response("recovered")),
+        ])
+        .await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.retry_policy = fast_retry_policy();
+
+        backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap();
+
+        let retries: Vec<_> = logs
+            .records()
+            .into_iter()
+            .filter(|r| {
+                r.kvs
+                    .get("endpoint")
+                    .is_some_and(|e| e.starts_with(&server.url()))
+            })
+            .collect();
+        assert_eq!(retries.len(), 2);
+        assert!(retries.iter().all(|r| r.level == log::Level::Warn));
+        assert_eq!(retries[0].kvs["attempt"], "1");
+        assert_eq!(retries[0].kvs["status"], "503");
+        assert!(retries[0].kvs["delay_ms"].parse::<u64>().unwrap() <= 5);
+        assert_eq!(retries[1].kvs["attempt"], "2");
+        assert_eq!(retries[1].kvs["status"], "429");
+        assert_eq!(retries[1].kvs["delay_ms"], "0");
+        assert_eq!(retries[0].kvs["request_id"], retries[1].kvs["request_id"]);
+    }
+
+    #[tokio::test]
+    async fn test_complete_verbose_returns_exchange() {
+        let response = text_response("Bonjour");
+        let server = MockServer::start(vec![MockResponse::json(200, response.clone())]).await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let (request, raw, chunk) = backend
+            .complete_verbose(
+                "gemini-2.5-flash",
+                &[text_message(Role::User, "Hello in French")],
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(request, server.requests()[0].json());
+        assert_eq!(request["model"], "gemini-2.5-flash");
+        assert_eq!(raw, response);
+        assert_eq!(chunk.content, "Bonjour");
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_retries() {
+        let server = MockServer::start(vec![MockResponse::json(
+            500,
+            json!({"error": {"message": "boom"}}),
+        )])
+        .await;
+        let mut backend = GeminiBackend::for_tests(&server.url());
+        backend.retry_policy = fast_retry_policy();
+
+        let result = backend
+            .complete_streaming("gemini-2.5-pro", &[], RequestOptions::default())
+            .await;
+        assert!(matches!(
+            result,
+            Err(GeminiSDKError::Api {
+                status_code: 500,
+                ..
+            })
+        ));
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_thought_flagged_parts_are_reasoning() {
+        let parts = json!([
+            {"text": "The user wants a capital. ", "thought": true},
+            {"text": "France's is Paris.", "thought": true},
+            {"text": "Paris"}
+        ]);
+        let body = json!({
+            "response": {
+                "candidates": [{
+                    "content": {"role": "model", "parts": parts},
+                    "finishReason": "STOP"
+                }]
+            }
+        });
+        let server = MockServer::start(vec![
+            MockResponse::json(200, body.clone()),
+            MockResponse::sse(&[body]),
+        ])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+        let messages = [text_message(Role::User, "Capital of France?")];
+
+        let chunk = backend
+            .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(chunk.content, "Paris");
+        assert_eq!(
+            chunk.reasoning_content.as_deref(),
+            Some("The user wants a capital. France's is Paris.")
+        );
+
+        let chunks: Vec<LLMChunk> = backend
+            .complete_streaming("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks[0].content, "Paris");
+        assert_eq!(
+            chunks[0].reasoning_content.as_deref(),
+            Some("The user wants a capital. France's is Paris.")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_thinking_budget_exhaustion_is_flagged() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({
+                "response": {
+                    "candidates": [{
+                        "content": {"role": "model", "parts": [{"thought": "Considering the options"}]},
+                        "finishReason": "MAX_TOKENS"
+                    }]
+                }
+            }),
+        )])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let chunk = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap();
+        assert!(chunk.thinking_truncated);
+
+        assert!(!is_thinking_truncated(Some("STOP"), "", Some("thought")));
+        assert!(!is_thinking_truncated(
+            Some("MAX_TOKENS"),
+            "partial answer",
+            Some("thought")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_safety_settings_and_block_reason() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({
+                "response": {
+                    "candidates": [{
+                        "content": {"role": "model", "parts": []},
+                        "finishReason": "SAFETY"
+                    }]
+                }
+            }),
+        )])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let settings = [SafetySetting {
+            category: HarmCategory::DangerousContent,
+            threshold: HarmBlockThreshold::BlockOnlyHigh,
+        }];
+        let chunk = backend
+            .complete(
+                "gemini-2.5-pro",
+                &[],
+                RequestOptions {
+                    safety_settings: Some(&settings),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(chunk.block_reason, Some(BlockReason::Safety));
+        let sent = server.requests()[0].json();
+        assert_eq!(
+            sent["request"]["safetySettings"],
+            json!([{"category": "HARM_CATEGORY_DANGEROUS_CONTENT", "threshold": "BLOCK_ONLY_HIGH"}])
+        );
+        assert_eq!(
+            BlockReason::from_finish_reason(Some("RECITATION")),
+            Some(BlockReason::Recitation)
+        );
+        assert_eq!(BlockReason::from_finish_reason(Some("STOP")), None);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_prompt_and_empty_response() {
+        let blocked = json!({"response": {
+            "promptFeedback": {"blockReason": "PROHIBITED_CONTENT"},
+            "usageMetadata": {"promptTokenCount": 7, "totalTokenCount": 7}
+        }});
+        let server = MockServer::start(vec![
+            MockResponse::json(200, blocked.clone()),
+            MockResponse::sse(&[blocked]),
+            MockResponse::json(200, json!({"response": {"candidates": []}})),
+        ])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let chunk = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(chunk.block_reason, Some(BlockReason::ProhibitedContent));
+        assert_eq!(chunk.usage.unwrap().prompt_tokens, 7);
+
+        let chunks: Vec<LLMChunk> = backend
+            .complete_streaming("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].block_reason, Some(BlockReason::ProhibitedContent));
+
+        let err = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no candidates"), "{}", err);
+    }
+
+    fn call(name: &str, arguments: Value) -> ToolCall {
+        ToolCall {
+            id: Uuid::new_v4().to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments,
+            },
+        }
+    }
+
+    #[test]
+    fn test_tool_call_accumulator() {
+        let mut acc = ToolCallAccumulator::default();
+        acc.push(vec![call("get_weather", json!("{\"city\": \"Pa"))]);
+        acc.push(vec![call("", json!("ris\", \"days\": 3}"))]);
+        acc.push(vec![call("get_weather", json!({"city": "Rome"}))]);
+        acc.push(vec![call("get_weather", json!("{\"city\": \"Oslo\"}"))]);
+
+        let calls = acc.finish();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(
+            calls[0].function.arguments,
+            json!({"city": "Paris", "days": 3})
+        );
+        assert_eq!(calls[1].function.arguments, json!({"city": "Rome"}));
+        assert_eq!(calls[2].function.arguments, json!({"city": "Oslo"}));
+        assert!(acc.finish().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_count_tokens() {
+        let server =
+            MockServer::start(vec![MockResponse::json(200, json!({"totalTokens": 42}))]).await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let messages = vec![text_message(Role::User, "How many tokens is this?")];
+        let count = backend
+            .count_tokens("gemini-2.5-pro", &messages, None)
+            .await
+            .unwrap();
+
+        assert_eq!(count, 42);
+        let requests = server.requests();
+        assert!(requests[0].path.ends_with(":countTokens"));
+        assert_eq!(requests[0].json()["model"], "gemini-2.5-pro");
+    }
+
+    #[tokio::test]
+    async fn test_empty_success_body_is_api_error() {
+        let server = MockServer::start(vec![MockResponse::raw(200, "")]).await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let messages = vec![text_message(Role::User, "Hello")];
+        let err = backend
+            .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await
+            .unwrap_err();
+
+        match err {
+            GeminiSDKError::Api {
+                message,
+                status_code,
+                ..
+            } => {
+                assert_eq!(status_code, 200);
+                assert!(message.contains("empty response body"), "{}", message);
+            }
+            other => panic!("expected Api error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_non_json_success_body_is_api_error() {
+        let server = MockServer::start(vec![MockResponse::raw(200, "<html>gateway</html>")]).await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let messages = vec![text_message(Role::User, "Hello")];
+        let err = backend
+            .count_tokens("gemini-2.5-pro", &messages, None)
+            .await
+            .unwrap_err();
+
+        match err {
+            GeminiSDKError::Api {
+                message,
+                response_body,
+                ..
+            } => {
+                assert!(message.contains("invalid JSON"), "{}", message);
+                assert_eq!(response_body.as_deref(), Some("<html>gateway</html>"));
+            }
+            other => panic!("expected Api error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_function_responses_follow_their_calls() {
+        let backend = GeminiBackend::new(BackendOptions::default()).unwrap();
+        let calls = vec![
+            call("get_weather", json!({"city": "Paris"})),
+            call("get_time", json!({"city": "Rome"})),
+        ];
+        // Results carry only the call id; the name comes from the call.
+        let result = |call: &ToolCall, text: &str| Message {
+            tool_call_id: Some(call.id.clone()),
+            reasoning: None,
+            ..text_message(Role::User, text)
+        };
+        let messages = vec![
+            text_message(Role::System, "Be brief."),
+            text_message(Role::User, "Weather in Paris and Rome?"),
+            Message {
+                tool_calls: Some(calls.clone()),
+                ..text_message(Role::Assistant, "")
+            },
+            result(&calls[0], "Sunny"),
+            result(&calls[1], "14:00"),
+            text_message(Role::Assistant, "Sunny in Paris; it's 14:00 in Rome."),
+        ];
+
+        assert_eq!(
+            json!(backend.prepare_messages(&messages)),
+            json!([
+                {"role": "user", "parts": [{"text": "Weather in Paris and Rome?"}]},
+                {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}},
+                    {"functionCall": {"name": "get_time", "args": {"city": "Rome"}}}
+                ]},
+                {"role": "user", "parts": [
+                    {"functionResponse": {"name": "get_weather", "response": {"result": "Sunny"}}},
+                    {"functionResponse": {"name": "get_time", "response": {"result": "14:00"}}}
+                ]},
+                {"role": "model", "parts": [{"text": "Sunny in Paris; it's 14:00 in Rome."}]}
+            ])
+        );
+    }
+
+    async fn parse_sse(body: &str, strict: bool) -> Vec<Result<LLMChunk>> {
+        let reads: Vec<reqwest::Result<Vec<u8>>> = vec![Ok(body.as_bytes().to_vec())];
+        parse_sse_stream(futures::stream::iter(reads), strict)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_sse_payload_split_across_lines_is_buffered() {
+        let event = text_response("Hello").to_string();
+        let (head, tail) = event.split_at(event.len() / 2);
+        let body = format!("data: {}\ndata: {}\n\n", head, tail);
+
+        for strict in [false, true] {
+            let chunks = parse_sse(&body, strict).await;
+            assert_eq!(chunks.len(), 1);
+            assert_eq!(chunks[0].as_ref().unwrap().content, "Hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_sse_line() {
+        let body = format!("data: {{not json\n\ndata: {}\n\n", text_response("after"));
+
+        let lenient = parse_sse(&body, false).await;
+        assert_eq!(lenient.len(), 1);
+        assert_eq!(lenient[0].as_ref().unwrap().content, "after");
+
+        let strict = parse_sse(&body, true).await;
+        assert_eq!(strict.len(), 2);
+        match &strict[0] {
+            Err(GeminiSDKError::Stream { message, .. }) => {
+                assert!(message.contains("{not json"), "{}", message)
+            }
+            other => panic!("expected a stream error, got {:?}", other),
+        }
+        assert_eq!(strict[1].as_ref().unwrap().content, "after");
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_reports_clock_skew() {
+        let server_date = chrono::Utc::now().to_rfc2822();
+        let unauthorized = MockResponse::json(
+            401,
+            json!({"error": {"code": 401, "message": "Request had invalid authentication credentials"}}),
+        )
+        .with_header("Date", &server_date);
+        let server = MockServer::start(vec![
+            unauthorized.clone(),
+            MockResponse::json(
+                200,
+                json!({"access_token": "refreshed", "expires_in": 3600}),
+            ),
+            unauthorized,
+        ])
+        .await;
+
+        // Two hours behind: the stored token still looks valid locally.
+        let mut backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            clock: Some(Arc::new(OffsetClock(-2 * 3_600_000))),
+            retry_policy: RetryPolicy::none(),
+            base_url: Some(server.url()),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.project_id = Arc::new(Mutex::new(Some("test-project".to_string())));
+        backend
+            .oauth_manager
+            .set_token_endpoint(format!("{}/token", server.url()));
+
+        let err = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("clock skew"), "{}", message);
+        assert!(message.contains("behind"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_clock_skew_is_not_blamed_for_a_locally_expired_token() {
+        let server_date = chrono::Utc::now().to_rfc2822();
+        let unauthorized = MockResponse::json(
+            401,
+            json!({"error": {"code": 401, "message": "Request had invalid authentication credentials"}}),
+        )
+        .with_header("Date", &server_date);
+        let refreshed = MockResponse::json(
+            200,
+            json!({"access_token": "refreshed", "expires_in": 3600}),
+        );
+        let server = MockServer::start(vec![
+            refreshed.clone(),
+            unauthorized.clone(),
+            refreshed,
+            unauthorized,
+        ])
+        .await;
+
+        // Two hours ahead: the stored token has already expired locally and
+        // is refreshed before the first request.
+        let mut backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            clock: Some(Arc::new(OffsetClock(2 * 3_600_000))),
+            retry_policy: RetryPolicy::none(),
+            base_url: Some(server.url()),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.project_id = Arc::new(Mutex::new(Some("test-project".to_string())));
+        backend
+            .oauth_manager
+            .set_token_endpoint(format!("{}/token", server.url()));
+
+        let err = backend
+            .complete("gemini-2.5-pro", &[], RequestOptions::default())
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.status_code(), Some(401));
+        assert!(!err.to_string().contains("clock skew"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_client_metadata_sent_during_onboarding() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"allowedTiers": [{"id": "free-tier", "isDefault": true}]}),
+            ),
+            MockResponse::json(
+                200,
+                json!({"done": true, "response": {"cloudaicompanionProject": {"id": "proj-1"}}}),
+            ),
+            MockResponse::json(200, text_response("hi")),
+        ])
+        .await;
+        let backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            client_metadata: ClientMetadata {
+                ide_type: "VSCODE".to_string(),
+                platform: "LINUX_AMD64".to_string(),
+                plugin_type: "CLOUD_CODE".to_string(),
+                duet_project: Some("duet-1".to_string()),
+            },
+            base_url: Some(server.url()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        backend
+            .complete(
+                "gemini-2.5-pro",
+                &[text_message(Role::User, "Hi")],
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert!(requests[0].path.ends_with(":loadCodeAssist"));
+        assert!(requests[1].path.ends_with(":onboardUser"));
+        let expected = json!({
+            "ideType": "VSCODE",
+            "platform": "LINUX_AMD64",
+            "pluginType": "CLOUD_CODE",
+            "duetProject": "duet-1"
+        });
+        assert_eq!(requests[0].json()["metadata"], expected);
+        assert_eq!(requests[1].json()["metadata"], expected);
+        assert_eq!(requests[2].json()["project"], "proj-1");
+    }
+
+    #[tokio::test]
+    async fn test_discovered_project_is_cached_in_credential_store() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"currentTier": {"id": "standard-tier"}, "cloudaicompanionProject": "proj-cached"}),
+            ),
+            MockResponse::json(200, text_response("one")),
+            MockResponse::json(200, text_response("two")),
+            MockResponse::json(
+                404,
+                json!({"error": {
+                    "code": 404,
+                    "message": "Project proj-cached not found",
+                    "status": "NOT_FOUND",
+                    "details": [{
+                        "@type": "type.googleapis.com/google.rpc.ResourceInfo",
+                        "resourceType": "project",
+                        "resourceName": "proj-cached"
+                    }]
+                }}),
+            ),
+        ])
+        .await;
+        let oauth_path = crate::test_support::write_test_credentials();
+        let new_backend = || {
+            GeminiBackend::new(BackendOptions {
+                oauth_path: Some(oauth_path.clone()),
+                base_url: Some(server.url()),
+                retry_policy: RetryPolicy::none(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let messages = [text_message(Role::User, "Hi")];
+
+        new_backend()
+            .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await
+            .unwrap();
+        let store = crate::auth::FileCredentialStore::new(Some(&oauth_path));
+        assert_eq!(store.load_project_id().as_deref(), Some("proj-cached"));
+
+        let second = new_backend();
+        second
+            .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].path.ends_with(":generateContent"));
+        assert_eq!(requests[2].json()["project"], "proj-cached");
+
+        assert!(second
+            .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+            .await
+            .is_err());
+        assert_eq!(store.load_project_id(), None);
+        assert_eq!(second.project_id().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_configured_project_replaces_a_different_cached_one() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"currentTier": {"id": "standard-tier"}, "cloudaicompanionProject": "proj-env"}),
+            ),
+            MockResponse::json(200, text_response("hi")),
+        ])
+        .await;
+        let oauth_path = crate::test_support::write_test_credentials();
+        let store = crate::auth::FileCredentialStore::new(Some(&oauth_path));
+        store.save_project_id(Some("proj-cached")).unwrap();
+        let backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(oauth_path.clone()),
+            base_url: Some(server.url()),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.oauth_manager.set_project_id("proj-env".to_string());
+
+        backend
+            .complete(
+                "gemini-2.5-pro",
+                &[text_message(Role::User, "Hi")],
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert!(requests[0].path.ends_with(":loadCodeAssist"));
+        assert_eq!(requests[0].json()["cloudaicompanionProject"], "proj-env");
+        assert_eq!(requests[1].json()["project"], "proj-env");
+        assert_eq!(store.load_project_id().as_deref(), Some("proj-env"));
+    }
+
+    #[test]
+    fn test_project_error_needs_a_project_reason() {
+        let backend = GeminiBackend::for_tests("http://localhost");
+        let error = |status: u16, body: Value| {
+            backend.handle_http_error(status, &body.to_string(), None, None)
+        };
+
+        assert!(is_project_error(&error(
+            403,
+            json!({"error": {
+                "code": 403,
+                "message": "Permission denied on resource project proj-1.",
+                "status": "PERMISSION_DENIED",
+                "details": [{
+                    "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                    "reason": "CONSUMER_INVALID"
+                }]
+            }}),
+        )));
+        assert!(!is_project_error(&error(
+            403,
+            json!({"error": {"code": 403, "message": "The caller does not have permission on this project"}}),
+        )));
+        assert!(!is_project_error(&error(
+            404,
+            json!({"error": {"code": 404, "message": "Model not found in project", "status": "NOT_FOUND"}}),
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_onboarding_gives_up_with_operation_and_resumes() {
+        let load = MockResponse::json(
+            200,
+            json!({"allowedTiers": [{"id": "standard-tier", "isDefault": true}]}),
+        );
+        let pending = MockResponse::json(200, json!({"name": "operations/onb-1", "done": false}));
+        let server = MockServer::start(vec![
+            load.clone(),
+            pending.clone(),
+            load.clone(),
+            MockResponse::json(
+                200,
+                json!({"name": "operations/onb-1", "done": true, "response": {"cloudaicompanionProject": {"id": "proj-1"}}}),
+            ),
+        ])
+        .await;
+        let backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(server.url()),
+            onboarding: OnboardingPolicy::no_wait(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        match backend.resolve_project_id().await.unwrap_err() {
+            GeminiSDKError::Onboarding {
+                tier_id, operation, ..
+            } => {
+                assert_eq!(tier_id.as_deref(), Some("standard-tier"));
+                assert_eq!(operation.as_deref(), Some("operations/onb-1"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(backend.project_id().await, None);
+        assert_eq!(backend.resolve_project_id().await.unwrap(), "proj-1");
+        assert_eq!(server.requests().len(), 4);
+
+        let server = MockServer::start(vec![load, pending]).await;
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(server.url()),
+            onboarding: OnboardingPolicy {
+                max_polls: 5,
+                poll_interval: Duration::from_secs(60),
+                cancel: Some(cancel),
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(5), backend.resolve_project_id())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            matches!(err, GeminiSDKError::Cancellation { .. }),
+            "{:?}",
+            err
+        );
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_free_tier_onboarding_that_gives_up_continues_without_project() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"allowedTiers": [{"id": "free-tier", "isDefault": true}]}),
+            ),
+            MockResponse::json(200, json!({"name": "operations/onb-1", "done": false})),
+        ])
+        .await;
+        let backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(server.url()),
+            onboarding: OnboardingPolicy::no_wait(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(backend.resolve_project_id().await.unwrap(), "");
+        assert_eq!(backend.project_id().await.as_deref(), Some(""));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_requests_use_configured_endpoint_and_version() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"allowedTiers": [{"id": "free-tier", "isDefault": true}]}),
+            ),
+            MockResponse::json(
+                200,
+                json!({"done": true, "response": {"cloudaicompanionProject": {"id": "proj-1"}}}),
+            ),
+            MockResponse::json(200, text_response("hi")),
+        ])
+        .await;
+        let backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(format!("{}/", server.url())),
+            api_version: Some("v1staging".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        backend
+            .complete(
+                "gemini-2.5-pro",
+                &[text_message(Role::User, "Hi")],
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1staging:loadCodeAssist");
+        assert_eq!(requests[1].path, "/v1staging:onboardUser");
+        assert_eq!(requests[2].path, "/v1staging:generateContent");
+    }
+
+    #[tokio::test]
+    async fn test_custom_http_client_is_used() {
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hi"))]).await;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-shared-client", "yes".parse().unwrap());
+        let client = Client::builder().default_headers(headers).build().unwrap();
+        let mut backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(server.url()),
+            http_client: Some(client),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.project_id = Arc::new(Mutex::new(Some("test-project".to_string())));
+
+        backend
+            .complete(
+                "gemini-2.5-pro",
+                &[text_message(Role::User, "Hi")],
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(server.requests()[0].header("x-shared-client"), Some("yes"));
+    }
+
+    #[test]
+    fn test_invalid_proxy_is_a_configuration_error() {
+        let result = GeminiBackend::new(BackendOptions {
+            proxy: Some("not a proxy url".to_string()),
+            ..Default::default()
+        });
+
+        assert!(matches!(result, Err(GeminiSDKError::Configuration { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_sent_through_proxy() {
+        let proxy = MockServer::start(vec![MockResponse::json(200, text_response("hi"))]).await;
+        let mut backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some("http://codeassist.invalid".to_string()),
+            proxy: Some(proxy.url()),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.project_id = Arc::new(Mutex::new(Some("test-project".to_string())));
+
+        backend
+            .complete(
+                "gemini-2.5-pro",
+                &[text_message(Role::User, "Hi")],
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            proxy.requests()[0].path,
+            "http://codeassist.invalid/v1internal:generateContent"
+        );
+        assert!(matches!(
+            build_http_client(None, Some("not a url")),
+            Err(GeminiSDKError::Configuration { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_limited() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, text_response("hi")).with_delay(Duration::from_millis(50))
+        ])
+        .await;
+        let mut backend = GeminiBackend::new(BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(server.url()),
+            max_concurrent_requests: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        backend.project_id = Arc::new(Mutex::new(Some("test-project".to_string())));
+        let backend = Arc::new(backend);
+
+        let calls = (0..6).map(|_| {
+            let backend = backend.clone();
+            tokio::spawn(async move {
+                let messages = [text_message(Role::User, "Hi")];
+                backend
+                    .complete("gemini-2.5-pro", &messages, RequestOptions::default())
+                    .await
+            })
+        });
+        for call in futures::future::join_all(calls).await {
+            call.unwrap().unwrap();
+        }
+
+        assert_eq!(server.requests().len(), 6);
+        assert_eq!(server.peak_concurrency(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_embed_content_single_and_batch() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"embedding": {"values": [0.5, -1.0]}}}),
+            ),
+            MockResponse::json(
+                200,
+                json!({"embeddings": [{"values": [1.0, 0.0]}, {"values": [0.0, 1.0]}]}),
+            ),
+            MockResponse::json(
+                200,
+                json!({"embeddings": [{"values": [1.0, 0.0]}, {"values": [0.0, 1.0]}]}),
+            ),
+        ])
+        .await;
+        let backend = GeminiBackend::for_tests(&server.url());
+
+        let single = backend
+            .embed_content("gemini-embedding-001", &["one".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(single, vec![vec![0.5, -1.0]]);
+
+        let batch = backend
+            .embed_content("gemini-embedding-001", &["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(batch, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1internal:embedContent");
+        assert_eq!(requests[0].json()["project"], "test-project");
+        assert_eq!(
+            requests[0].json()["request"]["content"]["parts"][0]["text"],
+            "one"
+        );
+        assert_eq!(requests[1].path, "/v1internal:batchEmbedContents");
+        let batch_requests = requests[1].json()["request"]["requests"].clone();
+        assert_eq!(batch_requests.as_array().unwrap().len(), 2);
+        assert_eq!(batch_requests[1]["model"], "models/gemini-embedding-001");
+
+        match backend
+            .embed_content(
+                "gemini-embedding-001",
+                &["a".to_string(), "b".to_string(), "c".to_string()],
+            )
+            .await
+            .unwrap_err()
+        {
+            GeminiSDKError::Api { message, .. } => {
+                assert!(
+                    message.contains("has 2 embeddings for 3 texts"),
+                    "{}",
+                    message
+                );
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_retry_delay_is_capped() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+        let first = policy.delay_for_attempt(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        assert!(policy.delay_for_attempt(8) <= Duration::from_secs(1));
+        assert!(policy.delay_for_attempt(8) >= Duration::from_millis(500));
+
+        assert_eq!(policy.delay_with_retry_after(0, Some(0)), Duration::ZERO);
+        assert_eq!(
+            policy.delay_with_retry_after(0, Some(86_400)),
+            Duration::from_secs(1)
+        );
+        assert!(policy.delay_with_retry_after(0, None) <= Duration::from_millis(100));
+
+        // Concurrent retries shouldn't all wake at the same moment.
+        let delays: std::collections::HashSet<Duration> =
+            (0..20).map(|_| policy.delay_for_attempt(3)).collect();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(120));
+        assert_eq!(parse_retry_after(" 7 "), Some(7));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(0));
+
+        let future = (chrono::Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let parsed = parse_retry_after(&future).unwrap();
+        assert!((85..=90).contains(&parsed));
+
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_system_messages_become_system_instruction() {
+        let backend = GeminiBackend::new(BackendOptions::default()).unwrap();
+        let messages = vec![
+            text_message(Role::System, "Be concise."),
+            text_message(Role::User, "Hello"),
+            text_message(Role::System, "Answer in English."),
+        ];
+
+        let payload = backend.build_request_payload(
+            "gemini-2.5-pro",
+            &messages,
+            RequestOptions::default(),
+            "",
+        );
+        let request = &payload["request"];
+
+        assert_eq!(
+            request["systemInstruction"]["parts"][0]["text"],
+            "Be concise.\n\nAnswer in English."
+        );
+        let contents = request["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 1);
+        assert_eq!(contents[0]["role"], "user");
+    }
+}
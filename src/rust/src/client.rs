@@ -1,45 +1,197 @@
 //! GeminiSDK Client - Main entry point for the Gemini SDK.
 
-use crate::auth::GeminiOAuthManager;
-use crate::backend::{BackendOptions, GeminiBackend};
+use crate::auth::{CredentialStore, GeminiOAuthManager, ServiceAccountKey};
+use crate::backend::{build_http_client, BackendOptions, GeminiBackend, RequestObserver};
 use crate::errors::{GeminiSDKError, Result};
-use crate::session::GeminiSession;
+use crate::session::{BackendSlot, EventSink, GeminiSession, SessionMap, SessionTable};
 use crate::types::{
-    get_gemini_cli_models, ConnectionState, GeminiClientOptions, ModelCapabilities, ModelInfo,
-    ModelLimits, ModelSupports, SessionConfig, SessionMetadata,
+    get_gemini_cli_model, get_gemini_cli_models, get_geminicli_profile_path, is_valid_profile_name,
+    ConnectionState, GeminiClientOptions, GeminiModelInfo, Message, MessageContent,
+    ModelCapabilities, ModelInfo, ModelLimits, ModelSupports, ModelVisionLimits, Role,
+    SerializedSession, SessionConfig, SessionIdCollision, SessionMetadata,
+    DEFAULT_CREDENTIAL_PROFILE, GEMINI_DEFAULT_MODEL,
 };
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
+const DEFAULT_AUTO_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Called with the new state on every `ConnectionState` transition.
+pub type ConnectionStateListener = Arc<dyn Fn(ConnectionState) + Send + Sync>;
+
+/// The client's connection state, the auth error behind an `Error` state and
+/// the listeners told about changes. Clones share all three, so background
+/// tasks can update the state too.
+#[derive(Clone)]
+struct StateTracker {
+    state: Arc<Mutex<ConnectionState>>,
+    last_error: Arc<std::sync::Mutex<Option<String>>>,
+    listeners: Arc<std::sync::RwLock<Vec<ConnectionStateListener>>>,
+}
+
+impl StateTracker {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ConnectionState::Disconnected)),
+            last_error: Arc::new(std::sync::Mutex::new(None)),
+            listeners: Arc::new(std::sync::RwLock::new(Vec::new())),
+        }
+    }
+
+    fn last_error(&self) -> Option<String> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Records `error` and moves to `Error`.
+    async fn fail(&self, error: &GeminiSDKError) {
+        *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(error.to_string());
+        self.set(ConnectionState::Error).await;
+    }
+
+    async fn get(&self) -> ConnectionState {
+        *self.state.lock().await
+    }
+
+    /// Moves to `new`, notifying listeners if that is a change. Reconnecting
+    /// clears the recorded error.
+    async fn set(&self, new: ConnectionState) {
+        if new == ConnectionState::Connected {
+            *self.last_error.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        let previous = std::mem::replace(&mut *self.state.lock().await, new);
+        if previous == new {
+            return;
+        }
+        let listeners = self
+            .listeners
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for listener in listeners {
+            listener(new);
+        }
+    }
+}
+
+/// Shortest pause between idle-session sweeps, however small the TTL.
+const MIN_REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Removes and destroys the idle, expired sessions in `sessions`.
+async fn reap_idle(sessions: &SessionTable, ttl: Duration) -> Vec<String> {
+    let Some(cutoff) = chrono::Duration::from_std(ttl)
+        .ok()
+        .and_then(|ttl| chrono::Utc::now().checked_sub_signed(ttl))
+    else {
+        return Vec::new();
+    };
+    let reaped: Vec<(String, Arc<GeminiSession>)> = {
+        let mut sessions = sessions.lock().await;
+        let mut expired = Vec::new();
+        for (id, session) in sessions.iter() {
+            // Closing checks for a send in flight under the session's own
+            // lock, so none can start between the check and the close.
+            if session.modified_time().await < cutoff && session.close_if_idle().await {
+                expired.push(id.clone());
+            }
+        }
+        expired
+            .into_iter()
+            .filter_map(|id| sessions.remove(&id).map(|session| (id, session)))
+            .collect()
+    };
+
+    let mut ids = Vec::with_capacity(reaped.len());
+    for (id, session) in reaped {
+        log::debug!("Deleting idle session {}", id);
+        session.destroy().await;
+        ids.push(id);
+    }
+    ids
+}
+
 pub struct GeminiClient {
     options: GeminiClientOptions,
-    state: Arc<Mutex<ConnectionState>>,
-    backend: Arc<Mutex<Option<Arc<GeminiBackend>>>>,
+    state: StateTracker,
+    backend: BackendSlot,
     oauth_manager: Arc<Mutex<Option<GeminiOAuthManager>>>,
-    sessions: Arc<Mutex<HashMap<String, Arc<GeminiSession>>>>,
+    sessions: SessionMap,
     started: Arc<Mutex<bool>>,
+    credential_store: Option<Arc<dyn CredentialStore>>,
+    event_sink: Option<Arc<dyn EventSink>>,
+    http_client: Option<reqwest::Client>,
+    observer: Option<Arc<dyn RequestObserver>>,
+    refresh_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    reaper_task: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl GeminiClient {
     pub fn new(options: GeminiClientOptions) -> Self {
         Self {
             options,
-            state: Arc::new(Mutex::new(ConnectionState::Disconnected)),
+            state: StateTracker::new(),
             backend: Arc::new(Mutex::new(None)),
             oauth_manager: Arc::new(Mutex::new(None)),
             sessions: Arc::new(Mutex::new(HashMap::new())),
             started: Arc::new(Mutex::new(false)),
+            credential_store: None,
+            event_sink: None,
+            http_client: None,
+            observer: None,
+            refresh_task: Arc::new(Mutex::new(None)),
+            reaper_task: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Loads and saves OAuth credentials through `store` instead of the
+    /// credentials file.
+    pub fn with_credential_store(mut self, store: Arc<dyn CredentialStore>) -> Self {
+        self.credential_store = Some(store);
+        self
+    }
+
+    /// Writes every event from every session this client creates to `sink`.
+    pub fn with_event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.event_sink = Some(sink);
+        self
+    }
+
+    /// Sends every API and token request through `client` instead of one
+    /// built from the options; `timeout` then has to be set on `client`.
+    pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    /// Reports every Code Assist HTTP call to `observer`.
+    pub fn with_request_observer(mut self, observer: Arc<dyn RequestObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
     pub fn with_defaults() -> Self {
         Self::new(GeminiClientOptions::default())
     }
 
     pub async fn state(&self) -> ConnectionState {
-        *self.state.lock().await
+        self.state.get().await
+    }
+
+    /// Registers `listener` to be called on every connection state change,
+    /// including a failed auto-refresh moving the client to `Error` and a
+    /// later successful one moving it back to `Connected`.
+    pub fn on_state_change(&self, listener: ConnectionStateListener) {
+        self.state
+            .listeners
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(listener);
     }
 
     pub async fn start(&self) -> Result<()> {
@@ -47,24 +199,77 @@ impl GeminiClient {
         if *started {
             return Ok(());
         }
+        let oauth_path = self.credential_path()?;
+        let service_account = self
+            .options
+            .service_account_path
+            .as_deref()
+            .map(ServiceAccountKey::from_file)
+            .transpose()?;
 
-        {
-            let mut state = self.state.lock().await;
-            *state = ConnectionState::Connecting;
+        self.state.set(ConnectionState::Connecting).await;
+        if let Err(e) = self.connect(oauth_path, service_account).await {
+            self.state.fail(&e).await;
+            return Err(e);
         }
+        self.state.set(ConnectionState::Connected).await;
+
+        *started = true;
+
+        // Start auto-refresh if enabled
+        if self.options.auto_refresh.unwrap_or(true) {
+            self.start_auto_refresh().await;
+        }
+
+        if let Some(ttl) = self.options.session_ttl {
+            self.start_session_reaper(ttl).await;
+        }
+
+        Ok(())
+    }
 
-        let oauth_manager = GeminiOAuthManager::new(
-            self.options.oauth_path.clone(),
+    /// Builds the OAuth manager and backend, checking the credentials.
+    async fn connect(
+        &self,
+        oauth_path: Option<String>,
+        service_account: Option<ServiceAccountKey>,
+    ) -> Result<()> {
+        let mut oauth_manager = GeminiOAuthManager::new(
+            oauth_path.clone(),
             self.options.client_id.clone(),
             self.options.client_secret.clone(),
-        );
+            self.credential_store.clone(),
+        )
+        .with_additional_scopes(self.options.additional_scopes.clone().unwrap_or_default());
+        if let Some(key) = &service_account {
+            oauth_manager = oauth_manager.with_service_account(key.clone());
+        }
+        if let Some(client) = &self.http_client {
+            oauth_manager = oauth_manager.with_http_client(client.clone());
+        } else if let Some(proxy) = self.options.proxy.as_deref() {
+            oauth_manager = oauth_manager.with_http_client(build_http_client(None, Some(proxy))?);
+        }
 
         let backend = GeminiBackend::new(BackendOptions {
-            timeout: self.options.timeout.map(|t| std::time::Duration::from_secs_f64(t)),
-            oauth_path: self.options.oauth_path.clone(),
+            timeout: self.options.timeout.map(std::time::Duration::from_secs_f64),
+            oauth_path,
             client_id: self.options.client_id.clone(),
             client_secret: self.options.client_secret.clone(),
-        });
+            credential_store: self.credential_store.clone(),
+            additional_scopes: self.options.additional_scopes.clone().unwrap_or_default(),
+            base_url: self.options.base_url.clone(),
+            api_version: self.options.api_version.clone(),
+            http_client: self.http_client.clone(),
+            proxy: self.options.proxy.clone(),
+            observer: self.observer.clone(),
+            files_base_url: self.options.files_base_url.clone(),
+            inline_data_limit: self.options.inline_data_limit,
+            max_concurrent_requests: self.options.max_concurrent_requests,
+            retry_policy: self.options.retry_policy.clone().unwrap_or_default(),
+            onboarding: self.options.onboarding.clone().unwrap_or_default(),
+            service_account,
+            ..Default::default()
+        })?;
 
         // Verify authentication
         oauth_manager.ensure_authenticated(false).await?;
@@ -79,46 +284,112 @@ impl GeminiClient {
             *be = Some(Arc::new(backend));
         }
 
-        {
-            let mut state = self.state.lock().await;
-            *state = ConnectionState::Connected;
+        Ok(())
+    }
+
+    async fn start_session_reaper(&self, ttl: Duration) {
+        let sessions = self.sessions.clone();
+        let interval = (ttl / 2).max(MIN_REAP_INTERVAL);
+
+        let handle = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                reap_idle(&sessions, ttl).await;
+            }
+        });
+
+        if let Some(previous) = self.reaper_task.lock().await.replace(handle) {
+            previous.abort();
         }
+    }
 
-        *started = true;
+    /// Deletes every session unmodified for longer than `session_ttl` that
+    /// has no request in flight, returning their ids. Does nothing without
+    /// a TTL.
+    pub async fn reap_idle_sessions(&self) -> Vec<String> {
+        match self.options.session_ttl {
+            Some(ttl) => reap_idle(&self.sessions, ttl).await,
+            None => Vec::new(),
+        }
+    }
 
-        // Start auto-refresh if enabled
-        if self.options.auto_refresh.unwrap_or(true) {
-            self.start_auto_refresh();
+    /// The credential profile in use, or `None` when credentials come from
+    /// an explicit `oauth_path`, a credential store or a service account.
+    pub fn profile(&self) -> Option<&str> {
+        if self.options.oauth_path.is_some()
+            || self.credential_store.is_some()
+            || self.options.service_account_path.is_some()
+        {
+            return None;
         }
+        Some(
+            self.options
+                .profile
+                .as_deref()
+                .unwrap_or(DEFAULT_CREDENTIAL_PROFILE),
+        )
+    }
 
-        Ok(())
+    /// Credentials file to use: `oauth_path`, else the selected profile's.
+    fn credential_path(&self) -> Result<Option<String>> {
+        if self.options.oauth_path.is_some() {
+            return Ok(self.options.oauth_path.clone());
+        }
+        match self.options.profile.as_deref() {
+            Some(profile) if !is_valid_profile_name(profile) => {
+                Err(GeminiSDKError::Configuration {
+                    message: format!(
+                        "Invalid credential profile {:?}; use letters, digits, '-' and '_'",
+                        profile
+                    ),
+                    config_key: Some("profile".to_string()),
+                })
+            }
+            Some(profile) => Ok(Some(get_geminicli_profile_path(profile))),
+            None => Ok(None),
+        }
     }
 
-    fn start_auto_refresh(&self) {
+    async fn start_auto_refresh(&self) {
         let oauth_manager = self.oauth_manager.clone();
+        let state = self.state.clone();
+        let interval = self
+            .options
+            .auto_refresh_interval
+            .unwrap_or(DEFAULT_AUTO_REFRESH_INTERVAL);
 
-        tokio::spawn(async move {
+        let handle = tokio::spawn(async move {
             loop {
-                tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+                tokio::time::sleep(interval).await;
 
-                if let Some(ref manager) = *oauth_manager.lock().await {
-                    let _ = manager.ensure_authenticated(false).await;
+                let refreshed = match *oauth_manager.lock().await {
+                    Some(ref manager) => manager.ensure_authenticated(false).await,
+                    None => continue,
+                };
+                match refreshed {
+                    Ok(_) => state.set(ConnectionState::Connected).await,
+                    Err(e) => {
+                        log::warn!("Automatic token refresh failed: {}", e);
+                        state.fail(&e).await;
+                    }
                 }
             }
         });
+
+        if let Some(previous) = self.refresh_task.lock().await.replace(handle) {
+            previous.abort();
+        }
     }
 
+    /// Disconnects the client. Sessions are kept and resume using the new
+    /// backend after `start()`; until then their requests fail with a
+    /// session error. Use `close()` to also destroy all sessions.
     pub async fn stop(&self) -> Result<()> {
-        // Destroy all sessions
-        let sessions: Vec<Arc<GeminiSession>> = {
-            let mut sessions_guard = self.sessions.lock().await;
-            let sessions: Vec<_> = sessions_guard.values().cloned().collect();
-            sessions_guard.clear();
-            sessions
-        };
-
-        for session in sessions {
-            session.destroy().await;
+        if let Some(task) = self.refresh_task.lock().await.take() {
+            task.abort();
+        }
+        if let Some(task) = self.reaper_task.lock().await.take() {
+            task.abort();
         }
 
         {
@@ -131,10 +402,7 @@ impl GeminiClient {
             *oauth = None;
         }
 
-        {
-            let mut state = self.state.lock().await;
-            *state = ConnectionState::Disconnected;
-        }
+        self.state.set(ConnectionState::Disconnected).await;
 
         {
             let mut started = self.started.lock().await;
@@ -144,7 +412,19 @@ impl GeminiClient {
         Ok(())
     }
 
+    /// Stops the client and destroys all of its sessions.
     pub async fn close(&self) -> Result<()> {
+        let sessions: Vec<Arc<GeminiSession>> = {
+            let mut sessions_guard = self.sessions.lock().await;
+            let sessions: Vec<_> = sessions_guard.values().cloned().collect();
+            sessions_guard.clear();
+            sessions
+        };
+
+        for session in sessions {
+            session.destroy().await;
+        }
+
         self.stop().await
     }
 
@@ -153,32 +433,76 @@ impl GeminiClient {
             self.start().await?;
         }
 
-        let backend = {
-            let be = self.backend.lock().await;
-            be.as_ref()
-                .ok_or_else(|| GeminiSDKError::configuration("Client not connected"))?
-                .clone()
-        };
+        if self.backend.lock().await.is_none() {
+            return Err(GeminiSDKError::configuration("Client not connected"));
+        }
 
-        let session_id = config.session_id.unwrap_or_else(|| Uuid::new_v4().to_string());
-        let model = config.model.unwrap_or_else(|| "gemini-2.5-pro".to_string());
-
-        let session = Arc::new(GeminiSession::new(
-            session_id.clone(),
-            model,
-            backend,
-            config.tools.unwrap_or_default(),
-            config.system_message,
-            config.generation_config,
-            config.thinking_config,
-            config.streaming.unwrap_or(true),
-        ));
+        let session_id = config
+            .session_id
+            .clone()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
 
-        {
+        let mut session =
+            GeminiSession::with_backend_slot(session_id.clone(), self.backend.clone(), config);
+        session.set_session_map(&self.sessions);
+        if let Some(sink) = &self.event_sink {
+            session.set_event_sink(sink.clone());
+        }
+        let session = Arc::new(session);
+        self.register_session(session_id, session.clone()).await?;
+        Ok(session)
+    }
+
+    /// Adds `session` to the session map, applying the configured
+    /// `SessionIdCollision` policy when its id is already in use.
+    async fn register_session(
+        &self,
+        session_id: String,
+        session: Arc<GeminiSession>,
+    ) -> Result<()> {
+        let replaced = {
             let mut sessions = self.sessions.lock().await;
-            sessions.insert(session_id, session.clone());
+            if sessions.contains_key(&session_id)
+                && self.options.session_id_collision.unwrap_or_default()
+                    == SessionIdCollision::Error
+            {
+                return Err(GeminiSDKError::Session {
+                    message: format!("Session already exists: {}", session_id),
+                    session_id: Some(session_id),
+                });
+            }
+            sessions.insert(session_id, session)
+        };
+
+        if let Some(old) = replaced {
+            old.destroy().await;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds a session saved with `GeminiSession::to_metadata_with_messages`,
+    /// keeping its id and history. Tool handlers must be registered again,
+    /// with `register_tool_handler` on the returned session.
+    pub async fn restore_session(
+        &self,
+        serialized: SerializedSession,
+    ) -> Result<Arc<GeminiSession>> {
+        if !*self.started.lock().await {
+            self.start().await?;
         }
 
+        if self.backend.lock().await.is_none() {
+            return Err(GeminiSDKError::configuration("Client not connected"));
+        }
+
+        let session_id = serialized.metadata.session_id.clone();
+        let mut session = GeminiSession::restore(self.backend.clone(), serialized);
+        session.set_session_map(&self.sessions);
+        if let Some(sink) = &self.event_sink {
+            session.set_event_sink(sink.clone());
+        }
+        let session = Arc::new(session);
+        self.register_session(session_id, session.clone()).await?;
         Ok(session)
     }
 
@@ -195,13 +519,7 @@ impl GeminiClient {
         let mut result = Vec::new();
 
         for session in sessions.values() {
-            result.push(SessionMetadata {
-                session_id: session.session_id().to_string(),
-                start_time: session.start_time().to_rfc3339(),
-                modified_time: session.modified_time().await.to_rfc3339(),
-                summary: None,
-                model: session.model().to_string(),
-            });
+            result.push(session.metadata().await);
         }
 
         result
@@ -220,11 +538,37 @@ impl GeminiClient {
         Ok(())
     }
 
+    /// How long the access token stays valid before the client refreshes
+    /// it. `None` before `start()` or when the expiry is unknown.
+    pub async fn token_expires_in(&self) -> Option<Duration> {
+        let oauth = self.oauth_manager.lock().await;
+        oauth.as_ref()?.token_expires_in().await
+    }
+
+    /// Whether the access token can be used without refreshing it first.
+    pub async fn is_token_valid(&self) -> bool {
+        self.token_expires_in()
+            .await
+            .is_some_and(|remaining| !remaining.is_zero())
+    }
+
+    /// Reports the profile, connection `state` and `last_error` (the failure
+    /// that put the client in `Error`, e.g. a revoked refresh token), plus the
+    /// token details while authenticated.
     pub async fn get_auth_status(&self) -> HashMap<String, serde_json::Value> {
         let mut status = HashMap::new();
+        status.insert("profile".to_string(), serde_json::json!(self.profile()));
+        let state = self.state.get().await;
+        status.insert("state".to_string(), serde_json::json!(state));
+        status.insert(
+            "last_error".to_string(),
+            serde_json::json!(self.state.last_error()),
+        );
 
+        // A failed refresh leaves stale credentials behind; don't report them.
         let oauth = self.oauth_manager.lock().await;
-        if let Some(ref manager) = *oauth {
+        let manager = oauth.as_ref().filter(|_| state != ConnectionState::Error);
+        if let Some(manager) = manager {
             if let Ok(credentials) = manager.get_credentials().await {
                 status.insert("authenticated".to_string(), serde_json::json!(true));
                 status.insert(
@@ -247,25 +591,66 @@ impl GeminiClient {
         let models = get_gemini_cli_models();
         models
             .into_iter()
-            .map(|(id, info)| ModelInfo {
-                id,
-                name: info.name,
-                capabilities: ModelCapabilities {
-                    supports: ModelSupports {
-                        vision: false,
-                        tools: info.supports_native_tools,
-                        thinking: info.supports_thinking,
-                    },
-                    limits: ModelLimits {
-                        max_prompt_tokens: Some(info.context_window),
-                        max_context_window_tokens: Some(info.context_window),
-                        vision: None,
-                    },
-                },
-            })
+            .map(|(id, info)| to_model_info(id, info))
             .collect()
     }
 
+    /// Capabilities of one model; `"auto"` describes the default model.
+    pub async fn get_model(&self, id: &str) -> Option<ModelInfo> {
+        get_gemini_cli_model(id).map(|info| to_model_info(info.id.clone(), info))
+    }
+
+    /// Starts the client if needed and resolves the Code Assist project (and
+    /// with it the user's tier) up front, so the first request doesn't pay for
+    /// `loadCodeAssist` and onboarding.
+    pub async fn warm_up(&self) -> Result<()> {
+        self.start().await?;
+        let backend = self
+            .backend
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| GeminiSDKError::configuration("Client not connected"))?;
+        backend.resolve_project_id().await?;
+        Ok(())
+    }
+
+    /// Confirms the API is reachable with working credentials: refreshes the
+    /// token if needed, then counts the tokens of a one-word prompt, which is
+    /// much cheaper than a completion. A failed refresh moves the client to
+    /// `Error` as a failed auto-refresh does; a passing check moves it back.
+    pub async fn health_check(&self) -> Result<()> {
+        let backend = self
+            .backend
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| GeminiSDKError::configuration("Client not connected"))?;
+        if let Some(ref manager) = *self.oauth_manager.lock().await {
+            if let Err(e) = manager.ensure_authenticated(false).await {
+                self.state.fail(&e).await;
+                return Err(e);
+            }
+        }
+
+        let ping = Message {
+            role: Role::User,
+            content: MessageContent::Text("ping".to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        };
+        backend
+            .count_tokens(GEMINI_DEFAULT_MODEL, &[ping], None)
+            .await?;
+        if self.state.get().await == ConnectionState::Error {
+            self.state.set(ConnectionState::Connected).await;
+        }
+        Ok(())
+    }
+
     pub async fn refresh_auth(&self) -> Result<()> {
         let oauth = self.oauth_manager.lock().await;
         if let Some(ref manager) = *oauth {
@@ -274,3 +659,738 @@ impl GeminiClient {
         Ok(())
     }
 }
+
+impl Drop for GeminiClient {
+    /// Background tasks only hold clones of the client's state, so abort them
+    /// here; otherwise a client dropped without `stop()` keeps refreshing
+    /// tokens.
+    fn drop(&mut self) {
+        for task in [&self.refresh_task, &self.reaper_task] {
+            if let Ok(mut task) = task.try_lock() {
+                if let Some(task) = task.take() {
+                    task.abort();
+                }
+            }
+        }
+    }
+}
+
+fn to_model_info(id: String, info: GeminiModelInfo) -> ModelInfo {
+    ModelInfo {
+        id,
+        name: info.name,
+        capabilities: ModelCapabilities {
+            supports: ModelSupports {
+                vision: info.supports_vision,
+                tools: info.supports_native_tools,
+                thinking: info.supports_thinking,
+            },
+            limits: ModelLimits {
+                max_prompt_tokens: Some(info.context_window),
+                max_context_window_tokens: Some(info.context_window),
+                vision: info.supports_vision.then(gemini_vision_limits),
+            },
+        },
+    }
+}
+
+/// Image limits shared by the Gemini models: the inline-data formats the API
+/// accepts, its per-prompt image cap, and the 20 MB inline request limit.
+fn gemini_vision_limits() -> ModelVisionLimits {
+    ModelVisionLimits {
+        supported_media_types: Some(
+            [
+                "image/png",
+                "image/jpeg",
+                "image/webp",
+                "image/heic",
+                "image/heif",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        ),
+        max_prompt_images: Some(3000),
+        max_prompt_image_size: Some(20 * 1024 * 1024),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{OnboardingPolicy, RetryPolicy};
+    use crate::test_support::{text_response, write_test_credentials, MockResponse, MockServer};
+    use crate::types::{EventType, MessageOptions, SessionEvent, GEMINI_DEFAULT_MODEL};
+
+    fn test_client() -> GeminiClient {
+        GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(false),
+            ..Default::default()
+        })
+    }
+
+    async fn point_backend_at(client: &GeminiClient, server: &MockServer) {
+        *client.backend.lock().await = Some(Arc::new(GeminiBackend::for_tests(&server.url())));
+    }
+
+    fn prompt(text: &str) -> MessageOptions {
+        MessageOptions {
+            prompt: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_aborts_auto_refresh_task() {
+        let client = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(true),
+            auto_refresh_interval: Some(Duration::from_millis(5)),
+            ..Default::default()
+        });
+
+        let mut finished_tasks = Vec::new();
+        for _ in 0..3 {
+            client.start().await.unwrap();
+            let task = client
+                .refresh_task
+                .lock()
+                .await
+                .as_ref()
+                .unwrap()
+                .abort_handle();
+            client.stop().await.unwrap();
+            finished_tasks.push(task);
+        }
+
+        tokio::task::yield_now().await;
+        assert!(client.refresh_task.lock().await.is_none());
+        assert!(finished_tasks.iter().all(|t| t.is_finished()));
+    }
+
+    #[tokio::test]
+    async fn test_token_expiry_accessors() {
+        let client = test_client();
+        assert_eq!(client.token_expires_in().await, None);
+        assert!(!client.is_token_valid().await);
+
+        client.start().await.unwrap();
+        assert!(client.is_token_valid().await);
+        assert!(client.token_expires_in().await.unwrap() > Duration::from_secs(50 * 60));
+    }
+
+    #[tokio::test]
+    async fn test_credential_profile_selection() {
+        let client = GeminiClient::new(GeminiClientOptions {
+            profile: Some("work".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(client.profile(), Some("work"));
+        assert_eq!(
+            client.credential_path().unwrap(),
+            Some(get_geminicli_profile_path("work"))
+        );
+        assert_eq!(client.get_auth_status().await["profile"], "work");
+
+        let client = test_client();
+        assert_eq!(client.profile(), None);
+        client.start().await.unwrap();
+        assert_eq!(
+            client.get_auth_status().await["profile"],
+            serde_json::Value::Null
+        );
+        assert_eq!(client.get_auth_status().await["authenticated"], true);
+        assert_eq!(
+            client.get_auth_status().await["last_error"],
+            serde_json::Value::Null
+        );
+
+        let client = GeminiClient::new(GeminiClientOptions {
+            profile: Some("../work".to_string()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            client.start().await,
+            Err(GeminiSDKError::Configuration { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reap_idle_sessions_keeps_busy_ones() {
+        let server =
+            MockServer::start(vec![MockResponse::json(200, text_response("hello"))
+                .with_delay(Duration::from_millis(300))])
+            .await;
+        let client = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(false),
+            session_ttl: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        let config = || SessionConfig {
+            streaming: Some(false),
+            ..Default::default()
+        };
+        let idle = client.create_session(config()).await.unwrap();
+        let busy = client.create_session(config()).await.unwrap();
+        let sending = busy.clone();
+        let send = tokio::spawn(async move { sending.send(prompt("slow")).await });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(busy.is_busy());
+        let fresh = client.create_session(config()).await.unwrap();
+
+        let reaped = client.reap_idle_sessions().await;
+        assert_eq!(reaped, vec![idle.session_id().to_string()]);
+        assert!(client.get_session(idle.session_id()).await.is_err());
+        assert!(client.get_session(busy.session_id()).await.is_ok());
+        assert!(client.get_session(fresh.session_id()).await.is_ok());
+
+        send.await.unwrap().unwrap();
+        assert!(!busy.is_busy());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut reaped = client.reap_idle_sessions().await;
+        reaped.sort();
+        let mut expected = vec![
+            busy.session_id().to_string(),
+            fresh.session_id().to_string(),
+        ];
+        expected.sort();
+        assert_eq!(reaped, expected);
+    }
+
+    #[tokio::test]
+    async fn test_fork_branches_history_and_registers_session() {
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hello"))]).await;
+        let client = test_client();
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        let session = client
+            .create_session(SessionConfig {
+                model: Some("gemini-2.5-flash".to_string()),
+                system_message: Some("Be brief".to_string()),
+                streaming: Some(false),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        session.send(prompt("first")).await.unwrap();
+        let events = Arc::new(std::sync::Mutex::new(0));
+        let counter = events.clone();
+        session
+            .on(Arc::new(move |_: SessionEvent| {
+                *counter.lock().unwrap() += 1
+            }))
+            .await;
+
+        let fork = session.fork().await;
+        assert_ne!(fork.session_id(), session.session_id());
+        assert_eq!(fork.model(), "gemini-2.5-flash");
+        assert_eq!(fork.messages().await.len(), session.messages().await.len());
+        assert!(client.get_session(fork.session_id()).await.is_ok());
+
+        fork.send(prompt("branch")).await.unwrap();
+        assert_eq!(
+            fork.messages().await.len(),
+            session.messages().await.len() + 2
+        );
+        assert_eq!(*events.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_no_token_refreshes_after_stop_or_drop() {
+        let token_server = MockServer::start(vec![MockResponse::json(
+            200,
+            serde_json::json!({"access_token": "short-lived", "expires_in": 1}),
+        )])
+        .await;
+        // Expired credentials, and replacements that stay inside the refresh
+        // buffer, so every auto-refresh tick hits the token endpoint.
+        let expiring_manager = || {
+            let path = write_test_credentials();
+            let mut creds: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            creds["expiry_date"] = serde_json::json!(1);
+            std::fs::write(&path, creds.to_string()).unwrap();
+            let mut manager = GeminiOAuthManager::new(Some(path), None, None, None);
+            manager.set_token_endpoint(format!("{}/token", token_server.url()));
+            manager
+        };
+        let options = GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh_interval: Some(Duration::from_millis(5)),
+            ..Default::default()
+        };
+
+        let client = GeminiClient::new(options.clone());
+        client.start().await.unwrap();
+        *client.oauth_manager.lock().await = Some(expiring_manager());
+        let refreshing = async {
+            while token_server.requests().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), refreshing)
+            .await
+            .expect("auto-refresh never ran");
+
+        client.stop().await.unwrap();
+        // Even with a manager to refresh, the stopped task stays quiet.
+        *client.oauth_manager.lock().await = Some(expiring_manager());
+        let after_stop = token_server.requests().len();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(token_server.requests().len(), after_stop);
+
+        let client = GeminiClient::new(options);
+        client.start().await.unwrap();
+        *client.oauth_manager.lock().await = Some(expiring_manager());
+        let before = token_server.requests().len();
+        let refreshing = async {
+            while token_server.requests().len() == before {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), refreshing)
+            .await
+            .expect("auto-refresh never ran");
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let after_drop = token_server.requests().len();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(token_server.requests().len(), after_drop);
+    }
+
+    #[tokio::test]
+    async fn test_state_change_listeners_see_every_transition() {
+        let token_server = MockServer::start(vec![MockResponse::json(
+            400,
+            serde_json::json!({"error": "invalid_grant"}),
+        )])
+        .await;
+        let client = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh_interval: Some(Duration::from_millis(5)),
+            ..Default::default()
+        });
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        client.on_state_change(Arc::new(move |state| recorder.lock().unwrap().push(state)));
+
+        client.start().await.unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![ConnectionState::Connecting, ConnectionState::Connected]
+        );
+
+        // A refresh that fails flips the client to Error.
+        let path = write_test_credentials();
+        let mut creds: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        creds["expiry_date"] = serde_json::json!(1);
+        std::fs::write(&path, creds.to_string()).unwrap();
+        let mut manager = GeminiOAuthManager::new(Some(path), None, None, None);
+        manager.set_token_endpoint(format!("{}/token", token_server.url()));
+        *client.oauth_manager.lock().await = Some(manager);
+        let failing = async {
+            while !seen.lock().unwrap().contains(&ConnectionState::Error) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), failing)
+            .await
+            .expect("refresh failure was never reported");
+        assert_eq!(client.state().await, ConnectionState::Error);
+        let status = client.get_auth_status().await;
+        assert_eq!(status["state"], "error");
+        assert_eq!(status["authenticated"], false);
+        assert!(status["last_error"]
+            .as_str()
+            .unwrap()
+            .contains("Token refresh failed"));
+
+        client.stop().await.unwrap();
+        assert_eq!(
+            seen.lock().unwrap().last(),
+            Some(&ConnectionState::Disconnected)
+        );
+        // Repeated failures don't re-announce the same state.
+        let errors = seen
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| **s == ConnectionState::Error)
+            .count();
+        assert_eq!(errors, 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_survives_client_restart() {
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hello"))]).await;
+        let client = test_client();
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        let session = client
+            .create_session(SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        session.send(prompt("first")).await.unwrap();
+
+        client.stop().await.unwrap();
+        let err = session.send(prompt("while stopped")).await.unwrap_err();
+        assert!(err.to_string().contains("Client is stopped"));
+
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+        session.send(prompt("after restart")).await.unwrap();
+
+        assert!(client.get_session(session.session_id()).await.is_ok());
+        assert_eq!(session.messages().await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_session_id_collision_policy() {
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hi"))]).await;
+        let config = || SessionConfig {
+            session_id: Some("fixed".to_string()),
+            streaming: Some(false),
+            ..Default::default()
+        };
+
+        let client = test_client();
+        client.start().await.unwrap();
+        let first = client.create_session(config()).await.unwrap();
+        let err = client.create_session(config()).await.err().unwrap();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+        assert!(Arc::ptr_eq(
+            &client.get_session("fixed").await.unwrap(),
+            &first
+        ));
+
+        let client = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(false),
+            session_id_collision: Some(SessionIdCollision::Replace),
+            ..Default::default()
+        });
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+        let old = client.create_session(config()).await.unwrap();
+        let new = client.create_session(config()).await.unwrap();
+
+        assert!(Arc::ptr_eq(
+            &client.get_session("fixed").await.unwrap(),
+            &new
+        ));
+        assert!(old.send_message(prompt("hello")).await.is_err());
+        new.send_message(prompt("hello")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_event_sink_receives_session_events() {
+        #[derive(Default)]
+        struct MemorySink(std::sync::Mutex<Vec<SessionEvent>>);
+
+        #[async_trait::async_trait]
+        impl EventSink for MemorySink {
+            async fn write(&self, event: &SessionEvent) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hello"))]).await;
+        let sink = Arc::new(MemorySink::default());
+        let client = test_client().with_event_sink(sink.clone());
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        let session = client
+            .create_session(SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        session.send(prompt("hi")).await.unwrap();
+
+        let events = sink.0.lock().unwrap().clone();
+        let kinds: Vec<EventType> = events.iter().map(|e| e.event_type).collect();
+        assert_eq!(
+            kinds,
+            vec![EventType::AssistantMessage, EventType::SessionIdle]
+        );
+        assert_eq!(events[0].data["content"], "hello");
+        assert!(events.iter().all(|e| e.session_id == session.session_id()));
+    }
+
+    #[tokio::test]
+    async fn test_list_models_reports_vision_support() {
+        let models = test_client().list_models().await;
+
+        let pro = models.iter().find(|m| m.id == "gemini-2.5-pro").unwrap();
+        assert!(pro.capabilities.supports.vision);
+        let limits = pro.capabilities.limits.vision.as_ref().unwrap();
+        assert!(limits
+            .supported_media_types
+            .as_ref()
+            .unwrap()
+            .contains(&"image/png".to_string()));
+        assert_eq!(limits.max_prompt_image_size, Some(20 * 1024 * 1024));
+        assert!(!models.iter().any(|m| m.id == "gemini-embedding-001"));
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_resolves_project_id() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            serde_json::json!({"currentTier": {"id": "free-tier"}, "cloudaicompanionProject": "proj-7"}),
+        )])
+        .await;
+        let client = test_client();
+        client.start().await.unwrap();
+        let backend = Arc::new(GeminiBackend::for_tests(&server.url()).without_project());
+        *client.backend.lock().await = Some(backend.clone());
+
+        client.warm_up().await.unwrap();
+
+        assert_eq!(backend.project_id().await.as_deref(), Some("proj-7"));
+        assert_eq!(server.requests().len(), 1);
+        assert!(server.requests()[0].path.ends_with(":loadCodeAssist"));
+    }
+
+    #[tokio::test]
+    async fn test_health_check_counts_tokens() {
+        let client = test_client();
+        assert!(client.health_check().await.is_err());
+
+        let server = MockServer::start(vec![
+            MockResponse::json(200, serde_json::json!({"totalTokens": 1})),
+            MockResponse::json(
+                400,
+                serde_json::json!({"error": {"message": "Invalid model"}}),
+            ),
+        ])
+        .await;
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        client.health_check().await.unwrap();
+        let request = &server.requests()[0];
+        assert!(request.path.ends_with(":countTokens"));
+        assert_eq!(
+            request.json()["request"]["contents"][0]["parts"][0]["text"],
+            "ping"
+        );
+
+        let err = client.health_check().await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                GeminiSDKError::Api {
+                    status_code: 400,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_policy_option_reaches_the_backend() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                serde_json::json!({"currentTier": {"id": "standard-tier"}, "cloudaicompanionProject": "proj-1"}),
+            ),
+            MockResponse::json(503, serde_json::json!({"error": {"message": "Unavailable"}})),
+            MockResponse::json(200, serde_json::json!({"totalTokens": 1})),
+        ])
+        .await;
+        let client = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(false),
+            base_url: Some(server.url()),
+            retry_policy: Some(RetryPolicy::none()),
+            ..Default::default()
+        });
+        client.start().await.unwrap();
+
+        let err = client.health_check().await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                GeminiSDKError::Api {
+                    status_code: 503,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_onboarding_option_reaches_the_backend() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                serde_json::json!({"allowedTiers": [{"id": "standard-tier", "isDefault": true}]}),
+            ),
+            MockResponse::json(
+                200,
+                serde_json::json!({"name": "operations/onb-1", "done": false}),
+            ),
+        ])
+        .await;
+        let client = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(false),
+            base_url: Some(server.url()),
+            onboarding: Some(OnboardingPolicy::no_wait()),
+            ..Default::default()
+        });
+        client.start().await.unwrap();
+
+        let err = client.health_check().await.unwrap_err();
+        assert!(matches!(err, GeminiSDKError::Onboarding { .. }), "{err:?}");
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_get_model_resolves_auto() {
+        let client = test_client();
+
+        let flash = client.get_model("gemini-2.5-flash").await.unwrap();
+        assert_eq!(flash.id, "gemini-2.5-flash");
+        assert_eq!(
+            flash.capabilities.limits.max_context_window_tokens,
+            Some(1_048_576)
+        );
+
+        let auto = client.get_model("auto").await.unwrap();
+        assert_eq!(auto.id, GEMINI_DEFAULT_MODEL);
+        assert!(client.get_model("gemini-1.0-ultra").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_restore_session_round_trip() {
+        let server = MockServer::start(vec![MockResponse::json(200, text_response("hello"))]).await;
+        let client = test_client();
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        let session = client
+            .create_session(SessionConfig {
+                model: Some("gemini-2.5-flash".to_string()),
+                system_message: Some("Be brief.".to_string()),
+                streaming: Some(false),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        session.send(prompt("first")).await.unwrap();
+
+        let saved = serde_json::to_string(&session.to_metadata_with_messages().await).unwrap();
+        client.close().await.unwrap();
+
+        let restarted = test_client();
+        restarted.start().await.unwrap();
+        point_backend_at(&restarted, &server).await;
+        let restored = restarted
+            .restore_session(serde_json::from_str(&saved).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(restored.session_id(), session.session_id());
+        assert_eq!(restored.model(), "gemini-2.5-flash");
+        assert_eq!(
+            restored.start_time().timestamp(),
+            session.start_time().timestamp()
+        );
+        assert_eq!(restored.messages().await.len(), 3);
+
+        restored.send(prompt("second")).await.unwrap();
+        let contents = server.requests().last().unwrap().json()["request"]["contents"].clone();
+        assert_eq!(contents.as_array().unwrap().len(), 3);
+        assert_eq!(contents[0]["parts"][0]["text"], "first");
+        assert_eq!(contents[2]["parts"][0]["text"], "second");
+
+        let duplicate = serde_json::from_str(&saved).unwrap();
+        assert!(restarted.restore_session(duplicate).await.is_err());
+
+        let replacing = GeminiClient::new(GeminiClientOptions {
+            oauth_path: Some(write_test_credentials()),
+            auto_refresh: Some(false),
+            session_id_collision: Some(SessionIdCollision::Replace),
+            ..Default::default()
+        });
+        replacing.start().await.unwrap();
+        point_backend_at(&replacing, &server).await;
+        let old = replacing
+            .restore_session(serde_json::from_str(&saved).unwrap())
+            .await
+            .unwrap();
+        let new = replacing
+            .restore_session(serde_json::from_str(&saved).unwrap())
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(
+            &replacing.get_session(session.session_id()).await.unwrap(),
+            &new
+        ));
+        assert!(old.send_message(prompt("hello")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restored_session_runs_registered_tool_handlers() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            serde_json::json!({"response": {"candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": {}}}
+                ]},
+                "finishReason": "STOP"
+            }]}}),
+        )])
+        .await;
+        let client = test_client();
+        client.start().await.unwrap();
+        point_backend_at(&client, &server).await;
+
+        let session = client
+            .create_session(SessionConfig {
+                streaming: Some(false),
+                tools: Some(vec![crate::tools::create_tool(
+                    "get_weather",
+                    "Current weather",
+                    None,
+                )]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let saved = session.to_metadata_with_messages().await;
+        client.close().await.unwrap();
+
+        let restarted = test_client();
+        restarted.start().await.unwrap();
+        point_backend_at(&restarted, &server).await;
+        let restored = restarted.restore_session(saved).await.unwrap();
+        restored.register_tool_handler(
+            "get_weather".to_string(),
+            Arc::new(|_| Box::pin(async { crate::tools::success_result("Sunny") })),
+        );
+
+        restored.send(prompt("Weather?")).await.unwrap();
+        let messages = restored.messages().await;
+        assert_eq!(messages.last().unwrap().content.as_text(), Some("Sunny"));
+    }
+}
@@ -1,6 +1,7 @@
 //! Custom errors for GeminiSDK Rust
 
 use std::collections::HashMap;
+use std::time::Duration;
 use thiserror::Error;
 
 /// Base error type for all GeminiSDK errors
@@ -130,6 +131,8 @@ pub enum GeminiSDKError {
     Onboarding {
         message: String,
         tier_id: Option<String>,
+        /// Name of the `onboardUser` long-running operation, once known.
+        operation: Option<String>,
     },
 
     #[error("IO error: {0}")]
@@ -214,6 +217,14 @@ impl GeminiSDKError {
         }
     }
 
+    pub fn validation(message: impl Into<String>, field: Option<String>) -> Self {
+        Self::Validation {
+            message: message.into(),
+            field,
+            value: None,
+        }
+    }
+
     pub fn configuration(message: impl Into<String>) -> Self {
         Self::Configuration {
             message: message.into(),
@@ -228,12 +239,84 @@ impl GeminiSDKError {
         }
     }
 
+    pub fn cancellation(message: impl Into<String>) -> Self {
+        Self::Cancellation {
+            message: message.into(),
+        }
+    }
+
     pub fn onboarding(message: impl Into<String>) -> Self {
         Self::Onboarding {
             message: message.into(),
             tier_id: None,
+            operation: None,
+        }
+    }
+
+    /// Whether retrying the same call may succeed: rate limits, timeouts,
+    /// connection failures and 5xx API errors.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::RateLimit { .. } | Self::Timeout { .. } | Self::Connection { .. } => true,
+            Self::Api { status_code, .. } => *status_code >= 500,
+            Self::Http(e) => {
+                e.is_timeout() || e.is_connect() || e.status().is_some_and(|s| s.is_server_error())
+            }
+            _ => false,
+        }
+    }
+
+    /// The HTTP status behind this error, if it came from an HTTP response.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            Self::Api { status_code, .. }
+            | Self::RateLimit { status_code, .. }
+            | Self::QuotaExceeded { status_code, .. }
+            | Self::PermissionDenied { status_code, .. }
+            | Self::NotFound { status_code, .. } => Some(*status_code),
+            Self::TokenRefresh { status_code, .. } => *status_code,
+            Self::Http(e) => e.status().map(|s| s.as_u16()),
+            _ => None,
+        }
+    }
+
+    /// How long the server asked to wait before retrying, from `Retry-After`.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimit { retry_after, .. } => retry_after.map(Duration::from_secs),
+            _ => None,
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, GeminiSDKError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_helpers() {
+        let rate_limited = GeminiSDKError::RateLimit {
+            message: "slow down".to_string(),
+            status_code: 429,
+            retry_after: Some(7),
+            response_body: None,
+        };
+        assert!(rate_limited.is_retryable());
+        assert_eq!(rate_limited.status_code(), Some(429));
+        assert_eq!(rate_limited.retry_after(), Some(Duration::from_secs(7)));
+
+        assert!(GeminiSDKError::api_error("unavailable", 503).is_retryable());
+        assert!(!GeminiSDKError::api_error("bad request", 400).is_retryable());
+        assert_eq!(
+            GeminiSDKError::api_error("bad request", 400).status_code(),
+            Some(400)
+        );
+
+        let validation = GeminiSDKError::validation("bad", None);
+        assert!(!validation.is_retryable());
+        assert_eq!(validation.status_code(), None);
+        assert_eq!(validation.retry_after(), None);
+    }
+}
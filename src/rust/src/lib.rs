@@ -29,8 +29,7 @@
 //!     // Send a message
 //!     let response = session.send_and_wait(MessageOptions {
 //!         prompt: "Hello, Gemini!".to_string(),
-//!         attachments: None,
-//!         context: None,
+//!         ..Default::default()
 //!     }).await?;
 //!
 //!     println!("Response: {:?}", response);
@@ -40,51 +39,71 @@
 //! }
 //! ```
 
+// Lets `#[derive(ToolSchema)]` refer to `::geminisdk` from inside this crate.
+extern crate self as geminisdk;
+
 pub mod auth;
 pub mod backend;
 pub mod client;
 pub mod errors;
 pub mod session;
+pub mod structured;
 pub mod tools;
 pub mod types;
 
+#[cfg(test)]
+mod test_support;
+
 // Re-exports for convenience
-pub use auth::GeminiOAuthManager;
-pub use backend::{BackendOptions, GeminiBackend};
-pub use client::GeminiClient;
+pub use auth::{
+    Clock, CredentialStore, FileCredentialStore, GeminiOAuthManager, ServiceAccountKey, SystemClock,
+};
+pub use backend::{
+    BackendOptions, ClientMetadata, GeminiBackend, OnboardingPolicy, RequestInfo, RequestObserver,
+    RequestOptions, ResponseInfo, RetryPolicy,
+};
+pub use client::{ConnectionStateListener, GeminiClient};
 pub use errors::{GeminiSDKError, Result};
-pub use session::GeminiSession;
+pub use session::{
+    estimate_message_tokens, estimate_tokens, EventSink, GeminiSession, HandlerId, SendHandle,
+};
+pub use structured::{parse_partial_json, JsonStreamAssembler};
+pub use tokio_util::sync::CancellationToken;
 pub use tools::{
-    create_tool, declarative_tool, failure_result, normalize_tools_from_value, rejected_result,
-    success_result, ToolParameters, ToolRegistry,
+    coerce_integer_arguments, create_tool, declarative_tool, failure_result,
+    normalize_tools_from_value, rejected_result, success_result, validate_tool_arguments,
+    SchemaProperty, ToolParameters, ToolProperty, ToolRegistry, ToolSchema,
 };
 pub use types::{
+    get_gemini_cli_model,
+    get_gemini_cli_models,
+    get_gemini_embedding_models,
     // Constants
     get_geminicli_credential_path,
     get_geminicli_env_path,
-    get_gemini_cli_models,
-    GEMINI_CODE_ASSIST_API_VERSION,
-    GEMINI_CODE_ASSIST_ENDPOINT,
-    GEMINI_CREDENTIAL_FILENAME,
-    GEMINI_DIR,
-    GEMINI_ENV_FILENAME,
-    GEMINI_OAUTH_AUTH_ENDPOINT,
-    GEMINI_OAUTH_BASE_URL,
-    GEMINI_OAUTH_CLIENT_ID,
-    GEMINI_OAUTH_CLIENT_SECRET,
-    GEMINI_OAUTH_REDIRECT_URI,
-    GEMINI_OAUTH_SCOPES,
-    GEMINI_OAUTH_TOKEN_ENDPOINT,
+    get_geminicli_profile_path,
+    list_credential_profiles,
     // Types
     Attachment,
+    BlockReason,
+    BuiltinTool,
+    CodeExecution,
+    CompactionConfig,
     ConnectionState,
     ContentPart,
     EventType,
+    FileHandle,
     FunctionCall,
+    FunctionCallingMode,
     GeminiClientOptions,
     GeminiModelInfo,
     GeminiOAuthCredentials,
     GenerationConfig,
+    GenerationConfigBuilder,
+    GroundingMetadata,
+    GroundingSource,
+    HarmBlockThreshold,
+    HarmCategory,
     LLMChunk,
     LLMUsage,
     LogLevel,
@@ -97,13 +116,35 @@ pub use types::{
     ModelSupports,
     ModelVisionLimits,
     Role,
+    SafetySetting,
+    SerializedSession,
     SessionConfig,
+    SessionConfigBuilder,
     SessionEvent,
+    SessionEventData,
+    SessionIdCollision,
     SessionMetadata,
     ThinkingConfig,
     Tool,
     ToolCall,
+    ToolConfig,
     ToolInvocation,
     ToolResult,
     ToolResultType,
+    UsageEstimateConfig,
+    DEFAULT_CREDENTIAL_PROFILE,
+    GEMINI_CODE_ASSIST_API_VERSION,
+    GEMINI_CODE_ASSIST_ENDPOINT,
+    GEMINI_CREDENTIAL_FILENAME,
+    GEMINI_DEFAULT_MODEL,
+    GEMINI_DIR,
+    GEMINI_ENV_FILENAME,
+    GEMINI_FILES_ENDPOINT,
+    GEMINI_OAUTH_AUTH_ENDPOINT,
+    GEMINI_OAUTH_BASE_URL,
+    GEMINI_OAUTH_CLIENT_ID,
+    GEMINI_OAUTH_CLIENT_SECRET,
+    GEMINI_OAUTH_REDIRECT_URI,
+    GEMINI_OAUTH_SCOPES,
+    GEMINI_OAUTH_TOKEN_ENDPOINT,
 };
@@ -1,44 +1,303 @@
 //! GeminiSDK Session - Manages individual conversation sessions.
 
-use crate::backend::GeminiBackend;
+use crate::backend::{
+    is_model_unavailable, is_thinking_truncated, GeminiBackend, LLMChunkStream, RequestOptions,
+    ToolCallAccumulator,
+};
 use crate::errors::{GeminiSDKError, Result};
+use crate::tools::{
+    coerce_integer_arguments, failure_result, rejected_result, validate_tool_arguments,
+    ToolRegistry,
+};
 use crate::types::{
-    EventType, GenerationConfig, Message, MessageContent, MessageOptions, Role, SessionEvent,
-    ThinkingConfig, Tool, ToolCall, ToolInvocation, ToolResult,
+    get_gemini_cli_model, is_truncation, BlockReason, CodeExecution, CompactionConfig, ContentPart,
+    EventType, GenerationConfig, LLMChunk, LLMUsage, Message, MessageContent, MessageOptions, Role,
+    SafetySetting, SerializedSession, SessionConfig, SessionEvent, SessionMetadata, ThinkingConfig,
+    Tool, ToolCall, ToolInvocation, ToolResult, ToolResultType, UsageEstimateConfig,
+    GEMINI_DEFAULT_MODEL,
 };
 use chrono::{DateTime, Utc};
-use futures::StreamExt;
+use futures::{FutureExt, Stream, StreamExt};
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 pub type ToolHandler = Arc<
     dyn Fn(ToolInvocation) -> Pin<Box<dyn Future<Output = ToolResult> + Send>> + Send + Sync,
 >;
 pub type SessionEventHandler = Arc<dyn Fn(SessionEvent) + Send + Sync>;
 
+fn message_index_error(index: usize) -> GeminiSDKError {
+    GeminiSDKError::validation(
+        format!("No message at index {} in the session history", index),
+        Some("index".to_string()),
+    )
+}
+
+/// Failures worth retrying on the next of `fallback_models`.
+fn should_fall_back(err: &GeminiSDKError) -> bool {
+    err.is_retryable()
+        || matches!(err, GeminiSDKError::QuotaExceeded { .. })
+        || is_model_unavailable(err)
+}
+
+/// Marks a request as in flight on its session until dropped.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn enter(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Assistant text streamed so far in a turn. A turn that fails or is
+/// cancelled stores it with `store` before returning. Whatever it still
+/// holds when dropped (the caller stopped polling) is committed too, as long
+/// as the history lock is free; otherwise it is discarded rather than
+/// landing after messages from a later turn.
+struct PartialReply {
+    messages: Arc<RwLock<Vec<Message>>>,
+    content: String,
+}
+
+impl PartialReply {
+    fn new(messages: &Arc<RwLock<Vec<Message>>>) -> Self {
+        Self {
+            messages: messages.clone(),
+            content: String::new(),
+        }
+    }
+
+    /// Takes the text for a completed turn, leaving nothing to commit.
+    fn take(&mut self) -> String {
+        std::mem::take(&mut self.content)
+    }
+
+    /// The partial assistant message, if any text arrived.
+    fn take_message(&mut self) -> Option<Message> {
+        if self.content.is_empty() {
+            return None;
+        }
+        Some(text_message(Role::Assistant, &self.take()))
+    }
+
+    /// Appends the text received so far to the history.
+    async fn store(&mut self) {
+        if let Some(message) = self.take_message() {
+            self.messages.write().await.push(message);
+        }
+    }
+
+    /// Stores the text received so far and returns the error that ended the
+    /// stream, carrying that text.
+    async fn interrupt(&mut self, err: GeminiSDKError) -> GeminiSDKError {
+        let err = interrupted(err, &self.content);
+        self.store().await;
+        err
+    }
+}
+
+impl Drop for PartialReply {
+    fn drop(&mut self) {
+        let Some(message) = self.take_message() else {
+            return;
+        };
+        match self.messages.try_write() {
+            Ok(mut messages) => messages.push(message),
+            Err(_) => log::warn!("History busy; discarding a partial reply of a dropped stream"),
+        }
+    }
+}
+
+/// The error for a stream that failed after producing `partial` text; with
+/// no text yet the original error is kept so it can still be retried.
+fn interrupted(err: GeminiSDKError, partial: &str) -> GeminiSDKError {
+    if partial.is_empty() {
+        return err;
+    }
+    GeminiSDKError::Stream {
+        message: format!("Stream interrupted: {}", err),
+        partial_content: Some(partial.to_string()),
+    }
+}
+
+/// Cuts `content` at the earliest stop sequence it contains. A trailing
+/// fragment of a stop sequence is kept: the API reports a stop-sequence halt
+/// as `STOP`, the same as a natural end, so there is no telling the two apart.
+fn trim_stop_sequence(content: &mut String, stops: &[String]) {
+    let at = stops
+        .iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| content.find(stop.as_str()))
+        .min();
+    if let Some(at) = at {
+        content.truncate(at);
+    }
+}
+
+/// Failures of an open stream that reconnecting can get past: the connection
+/// dropped or timed out. A body that arrived garbled is not retried, since
+/// asking again is as likely to fail the same way.
+fn is_connection_drop(err: &GeminiSDKError) -> bool {
+    matches!(
+        err,
+        GeminiSDKError::Http(_)
+            | GeminiSDKError::Connection { .. }
+            | GeminiSDKError::Timeout { .. }
+    )
+}
+
+/// Identifies a handler registered with `GeminiSession::on`, for removing it
+/// with `off`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HandlerId(u64);
+
+/// Durable destination for session events, e.g. an audit log in a file or
+/// database. `emit` awaits each write, so a sink sees events in order.
+#[async_trait::async_trait]
+pub trait EventSink: Send + Sync {
+    async fn write(&self, event: &SessionEvent);
+}
+
+/// Decides whether a tool call may run; resolving to `false` rejects it.
+pub type ToolApprover =
+    Arc<dyn Fn(&ToolInvocation) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// Shared slot holding the client's current backend. Sessions resolve the
+/// backend through it on every request, so they follow a client restart.
+pub(crate) type BackendSlot = Arc<Mutex<Option<Arc<GeminiBackend>>>>;
+
+/// A client's sessions by id. Sessions hold it weakly so forks can register
+/// themselves without keeping the client's map alive.
+pub(crate) type SessionMap = Arc<SessionTable>;
+pub(crate) type SessionTable = Mutex<HashMap<String, Arc<GeminiSession>>>;
+
+/// One send's own state, passed down its call chain so that events, config
+/// overrides and cancellation stay with the turn that asked for them.
+#[derive(Clone, Copy, Default)]
+struct Turn<'a> {
+    /// Stops generation when it fires.
+    cancel: Option<&'a CancellationToken>,
+    /// Channel that `send_into` forwards the turn's events to.
+    events: Option<&'a mpsc::Sender<SessionEvent>>,
+    /// Overrides from the turn's `MessageOptions`.
+    generation_config: Option<&'a GenerationConfig>,
+    thinking_config: Option<&'a ThinkingConfig>,
+}
+
+impl<'a> Turn<'a> {
+    fn new(options: &'a MessageOptions) -> Self {
+        Self {
+            generation_config: options.generation_config.as_ref(),
+            thinking_config: options.thinking_config.as_ref(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A response stored in the history, with what produced it. Kept per call
+/// so that concurrent sends don't read each other's results.
+struct Reply {
+    message: Message,
+    /// The model that answered, after any fallback.
+    model: String,
+    finish_reason: Option<String>,
+}
+
+/// Tool calls from one turn that may run at the same time by default.
+const DEFAULT_MAX_PARALLEL_TOOLS: usize = 4;
+
+/// Model id that lets the backend route each request.
+const AUTO_MODEL: &str = "auto";
+
+/// Events buffered per subscriber before slow receivers start lagging.
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// User message sent for each `auto_continue` round.
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off.";
+
+/// Instructions for the request that summarizes history in `compact_history`.
+const COMPACTION_PROMPT: &str = "Summarize the conversation transcript you are given so it can \
+    replace the transcript in a continuing chat. Keep every fact, decision, open question, name, \
+    number and piece of code the rest of the conversation may rely on. Reply with the summary only.";
+
+/// `name` of the system message holding a conversation summary, so a later
+/// compaction folds it into the new summary instead of keeping both.
+const SUMMARY_MESSAGE_NAME: &str = "conversation_summary";
+
+/// Token usage and estimated spend summed over a session's responses.
+#[derive(Default)]
+struct UsageTotals {
+    usage: LLMUsage,
+    cost: f64,
+}
+
 pub struct GeminiSession {
     session_id: String,
     model: String,
-    backend: Arc<GeminiBackend>,
+    backend: BackendSlot,
     tools: Vec<Tool>,
-    tool_handlers: HashMap<String, ToolHandler>,
+    tool_handlers: std::sync::RwLock<HashMap<String, ToolHandler>>,
+    tool_approver: Option<ToolApprover>,
+    registry: Option<Arc<ToolRegistry>>,
     system_message: Option<String>,
     generation_config: Option<GenerationConfig>,
     thinking_config: Option<ThinkingConfig>,
     streaming: bool,
+    usage_estimate: Option<UsageEstimateConfig>,
+    coerce_integer_arguments: bool,
+    safety_settings: Option<Vec<SafetySetting>>,
+    examples: Vec<(String, String)>,
+    max_history_tokens: Option<u64>,
+    compaction: Option<CompactionConfig>,
+    auto_fallback_model: Option<String>,
+    max_parallel_tools: usize,
+    validate_tool_args: bool,
+    fallback_models: Vec<String>,
+    stream_reconnects: u32,
+    auto_continue: u32,
+    trim_stop_sequences: bool,
+    include_accumulated_content: bool,
+    last_model: Arc<RwLock<Option<String>>>,
+    last_finish_reason: Arc<RwLock<Option<String>>>,
+    in_flight: Arc<AtomicUsize>,
+    /// Bumped whenever messages already in the history are changed or
+    /// removed, so compaction can tell its snapshot went stale.
+    history_rewrites: AtomicU64,
+    session_map: Option<Weak<SessionTable>>,
+    normalize_whitespace: bool,
 
-    messages: Arc<Mutex<Vec<Message>>>,
-    event_handlers: Arc<Mutex<Vec<SessionEventHandler>>>,
+    // History, handlers and timestamps are read far more often than they are
+    // written (every emit reads the handlers; dashboards poll `messages()`),
+    // so they sit behind `RwLock`s and concurrent readers never queue behind
+    // each other. Writers hold the lock only for the push itself.
+    messages: Arc<RwLock<Vec<Message>>>,
+    event_handlers: Arc<RwLock<Vec<(HandlerId, SessionEventHandler)>>>,
+    next_handler_id: AtomicU64,
+    event_sink: Option<Arc<dyn EventSink>>,
+    event_tx: broadcast::Sender<SessionEvent>,
     closed: Arc<Mutex<bool>>,
     start_time: DateTime<Utc>,
-    modified_time: Arc<Mutex<DateTime<Utc>>>,
+    modified_time: Arc<RwLock<DateTime<Utc>>>,
+    usage_totals: Arc<RwLock<UsageTotals>>,
 }
 
 impl GeminiSession {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         session_id: String,
         model: String,
@@ -49,32 +308,84 @@ impl GeminiSession {
         thinking_config: Option<ThinkingConfig>,
         streaming: bool,
     ) -> Self {
-        let mut messages = Vec::new();
-        if let Some(ref sys_msg) = system_message {
-            messages.push(Message {
-                role: Role::System,
-                content: MessageContent::Text(sys_msg.clone()),
-                name: None,
-                tool_calls: None,
-                tool_call_id: None,
-            });
-        }
+        Self::with_config(
+            session_id,
+            backend,
+            SessionConfig {
+                model: Some(model),
+                tools: Some(tools),
+                system_message,
+                generation_config,
+                thinking_config,
+                streaming: Some(streaming),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Creates a session from a `SessionConfig`, applying the same defaults
+    /// as `GeminiClient::create_session`.
+    pub fn with_config(
+        session_id: String,
+        backend: Arc<GeminiBackend>,
+        config: SessionConfig,
+    ) -> Self {
+        Self::with_backend_slot(session_id, Arc::new(Mutex::new(Some(backend))), config)
+    }
+
+    pub(crate) fn with_backend_slot(
+        session_id: String,
+        backend: BackendSlot,
+        config: SessionConfig,
+    ) -> Self {
+        let examples = config.examples.unwrap_or_default();
+        let messages = seed_messages(config.system_message.as_deref(), &examples);
 
         Self {
             session_id,
-            model,
+            model: config
+                .model
+                .unwrap_or_else(|| GEMINI_DEFAULT_MODEL.to_string()),
             backend,
-            tools,
-            tool_handlers: HashMap::new(),
-            system_message,
-            generation_config,
-            thinking_config,
-            streaming,
-            messages: Arc::new(Mutex::new(messages)),
-            event_handlers: Arc::new(Mutex::new(Vec::new())),
+            tools: config.tools.unwrap_or_default(),
+            tool_handlers: std::sync::RwLock::new(HashMap::new()),
+            tool_approver: None,
+            registry: None,
+            system_message: config.system_message,
+            generation_config: config.generation_config,
+            thinking_config: config.thinking_config,
+            streaming: config.streaming.unwrap_or(true),
+            usage_estimate: config.usage_estimate,
+            coerce_integer_arguments: config.coerce_integer_arguments.unwrap_or(true),
+            safety_settings: config.safety_settings,
+            examples,
+            max_history_tokens: config.max_history_tokens,
+            compaction: config.compaction,
+            auto_fallback_model: config.auto_fallback_model,
+            max_parallel_tools: config
+                .max_parallel_tools
+                .unwrap_or(DEFAULT_MAX_PARALLEL_TOOLS),
+            validate_tool_args: config.validate_tool_args.unwrap_or(false),
+            fallback_models: config.fallback_models.unwrap_or_default(),
+            stream_reconnects: config.stream_reconnects.unwrap_or(0),
+            auto_continue: config.auto_continue.unwrap_or(0),
+            trim_stop_sequences: config.trim_stop_sequences.unwrap_or(false),
+            include_accumulated_content: config.include_accumulated_content.unwrap_or(false),
+            last_model: Arc::new(RwLock::new(None)),
+            last_finish_reason: Arc::new(RwLock::new(None)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            history_rewrites: AtomicU64::new(0),
+            session_map: None,
+            normalize_whitespace: config.normalize_whitespace.unwrap_or(false),
+            messages: Arc::new(RwLock::new(messages)),
+            event_handlers: Arc::new(RwLock::new(Vec::new())),
+            next_handler_id: AtomicU64::new(0),
+            event_sink: None,
+            event_tx: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
             closed: Arc::new(Mutex::new(false)),
+            usage_totals: Arc::new(RwLock::new(UsageTotals::default())),
             start_time: Utc::now(),
-            modified_time: Arc::new(Mutex::new(Utc::now())),
+            modified_time: Arc::new(RwLock::new(Utc::now())),
         }
     }
 
@@ -86,143 +397,1112 @@ impl GeminiSession {
         &self.model
     }
 
+    /// The model that produced the last response, which differs from
+    /// `model()` after a fallback. `None` before the first response.
+    pub async fn last_model(&self) -> Option<String> {
+        self.last_model.read().await.clone()
+    }
+
+    /// Why the last response stopped (`STOP`, `MAX_TOKENS`, `SAFETY`, ...).
+    /// `None` before the first response or after one that failed.
+    pub async fn last_finish_reason(&self) -> Option<String> {
+        self.last_finish_reason.read().await.clone()
+    }
+
+    /// Whether the last response was cut off at the output token limit.
+    pub async fn was_truncated(&self) -> bool {
+        is_truncation(self.last_finish_reason.read().await.as_deref())
+    }
+
     pub fn start_time(&self) -> DateTime<Utc> {
         self.start_time
     }
 
+    /// Returns the session's effective configuration, with defaults resolved.
+    pub fn config(&self) -> SessionConfig {
+        SessionConfig {
+            session_id: Some(self.session_id.clone()),
+            model: Some(self.model.clone()),
+            tools: Some(self.tools.clone()),
+            system_message: self.system_message.clone(),
+            generation_config: self.generation_config.clone(),
+            thinking_config: self.thinking_config.clone(),
+            streaming: Some(self.streaming),
+            usage_estimate: self.usage_estimate.clone(),
+            coerce_integer_arguments: Some(self.coerce_integer_arguments),
+            safety_settings: self.safety_settings.clone(),
+            examples: Some(self.examples.clone()),
+            max_history_tokens: self.max_history_tokens,
+            compaction: self.compaction.clone(),
+            auto_fallback_model: self.auto_fallback_model.clone(),
+            max_parallel_tools: Some(self.max_parallel_tools),
+            validate_tool_args: Some(self.validate_tool_args),
+            fallback_models: Some(self.fallback_models.clone()),
+            stream_reconnects: Some(self.stream_reconnects),
+            auto_continue: Some(self.auto_continue),
+            trim_stop_sequences: Some(self.trim_stop_sequences),
+            include_accumulated_content: Some(self.include_accumulated_content),
+            normalize_whitespace: Some(self.normalize_whitespace),
+        }
+    }
+
+    pub async fn metadata(&self) -> SessionMetadata {
+        SessionMetadata {
+            session_id: self.session_id.clone(),
+            start_time: self.start_time.to_rfc3339(),
+            modified_time: self.modified_time().await.to_rfc3339(),
+            summary: None,
+            model: self.model.clone(),
+        }
+    }
+
+    /// Snapshots the session (config and full history) in a serializable
+    /// form. Restore it with `GeminiClient::restore_session`.
+    pub async fn to_metadata_with_messages(&self) -> SerializedSession {
+        SerializedSession {
+            metadata: self.metadata().await,
+            config: self.config(),
+            messages: self.messages().await,
+        }
+    }
+
+    /// Lets `fork` register new sessions with the client owning `sessions`.
+    pub(crate) fn set_session_map(&mut self, sessions: &SessionMap) {
+        self.session_map = Some(Arc::downgrade(sessions));
+    }
+
+    /// Branches the conversation: returns a new session with a fresh id and
+    /// a copy of this session's history, model and configuration. Both share
+    /// the backend but keep separate histories, usage and event handlers.
+    /// Tool handlers are not copied. Sessions created by a client register
+    /// the fork with that client.
+    pub async fn fork(&self) -> Arc<GeminiSession> {
+        let config = SessionConfig {
+            session_id: None,
+            ..self.config()
+        };
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let mut forked = Self::with_backend_slot(session_id.clone(), self.backend.clone(), config);
+        forked.messages = Arc::new(RwLock::new(self.messages().await));
+        forked.event_sink = self.event_sink.clone();
+        forked.session_map = self.session_map.clone();
+        let forked = Arc::new(forked);
+
+        if let Some(sessions) = self.session_map.as_ref().and_then(Weak::upgrade) {
+            sessions.lock().await.insert(session_id, forked.clone());
+        }
+        forked
+    }
+
+    /// Rebuilds a session from a snapshot, keeping its id, history and
+    /// timestamps. Tool results saved as user messages by older versions
+    /// come back as `Role::Tool`.
+    pub(crate) fn restore(backend: BackendSlot, mut serialized: SerializedSession) -> Self {
+        let parse_time = |value: &str| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|t| t.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now())
+        };
+
+        let mut session = Self::with_backend_slot(
+            serialized.metadata.session_id.clone(),
+            backend,
+            serialized.config,
+        );
+        for message in &mut serialized.messages {
+            if message.role == Role::User && message.tool_call_id.is_some() {
+                message.role = Role::Tool;
+            }
+        }
+        session.messages = Arc::new(RwLock::new(serialized.messages));
+        session.start_time = parse_time(&serialized.metadata.start_time);
+        session.modified_time =
+            Arc::new(RwLock::new(parse_time(&serialized.metadata.modified_time)));
+        session
+    }
+
     pub async fn modified_time(&self) -> DateTime<Utc> {
-        *self.modified_time.lock().await
+        *self.modified_time.read().await
+    }
+
+    /// Whether a send, regenerate or stream is currently running.
+    pub fn is_busy(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) > 0
+    }
+
+    /// Closes the session unless a request is in flight. Both happen under
+    /// the `closed` lock, which every send takes after marking itself in
+    /// flight, so a send either keeps the session open or sees it closed.
+    pub(crate) async fn close_if_idle(&self) -> bool {
+        let mut closed = self.closed.lock().await;
+        if self.is_busy() {
+            return false;
+        }
+        *closed = true;
+        true
     }
 
     pub async fn messages(&self) -> Vec<Message> {
-        self.messages.lock().await.clone()
+        self.messages.read().await.clone()
+    }
+
+    async fn backend(&self) -> Result<Arc<GeminiBackend>> {
+        self.backend
+            .lock()
+            .await
+            .clone()
+            .ok_or_else(|| GeminiSDKError::Session {
+                message: "Client is stopped; call start() to reconnect before sending".to_string(),
+                session_id: Some(self.session_id.clone()),
+            })
+    }
+
+    /// Runs `handler` for the model's calls to tool `name`. Takes `&self`
+    /// so handlers can be added to shared sessions, such as the ones
+    /// `GeminiClient::restore_session` returns.
+    pub fn register_tool_handler(&self, name: String, handler: ToolHandler) {
+        self.tool_handlers
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name, handler);
+    }
+
+    /// Declares every tool in `registry` to the model and runs its handlers
+    /// for calls that have no handler registered on the session itself.
+    pub fn attach_registry(&mut self, registry: Arc<ToolRegistry>) {
+        for tool in registry.tools() {
+            if !self.tools.iter().any(|t| t.name == tool.name) {
+                self.tools.push(tool);
+            }
+        }
+        self.registry = Some(registry);
+    }
+
+    /// Sets a callback consulted before every tool call. Calls it rejects are
+    /// answered with a `Rejected` result instead of running the handler.
+    pub fn set_tool_approver(&mut self, approver: ToolApprover) {
+        self.tool_approver = Some(approver);
+    }
+
+    /// Writes every event this session emits to `sink`.
+    pub fn set_event_sink(&mut self, sink: Arc<dyn EventSink>) {
+        self.event_sink = Some(sink);
     }
 
-    pub fn register_tool_handler(&mut self, name: String, handler: ToolHandler) {
-        self.tool_handlers.insert(name, handler);
+    /// Registers a handler called with every event; keep the returned id to
+    /// remove it again with `off`.
+    pub async fn on(&self, handler: SessionEventHandler) -> HandlerId {
+        let id = HandlerId(self.next_handler_id.fetch_add(1, Ordering::Relaxed));
+        self.event_handlers.write().await.push((id, handler));
+        id
     }
 
-    pub async fn on(&self, handler: SessionEventHandler) {
-        self.event_handlers.lock().await.push(handler);
+    /// Removes a handler registered with `on`. Returns `false` if it was
+    /// already removed.
+    pub async fn off(&self, id: HandlerId) -> bool {
+        let mut handlers = self.event_handlers.write().await;
+        let before = handlers.len();
+        handlers.retain(|(handler_id, _)| *handler_id != id);
+        handlers.len() != before
     }
 
-    async fn emit(&self, event_type: EventType, data: serde_json::Value) {
+    async fn emit(&self, turn: Turn<'_>, event_type: EventType, data: serde_json::Value) {
         let event = SessionEvent {
             event_type,
             data,
             session_id: self.session_id.clone(),
         };
 
-        let handlers = self.event_handlers.lock().await;
-        for handler in handlers.iter() {
+        // Handlers run outside the lock so they can call `on` or `off`.
+        let handlers: Vec<SessionEventHandler> = self
+            .event_handlers
+            .read()
+            .await
+            .iter()
+            .map(|(_, handler)| handler.clone())
+            .collect();
+        for handler in handlers {
             handler(event.clone());
         }
+
+        if let Some(sink) = &self.event_sink {
+            sink.write(&event).await;
+        }
+
+        if let Some(tx) = turn.events {
+            // Waits for capacity; a dropped receiver just stops forwarding.
+            let _ = tx.send(event.clone()).await;
+        }
+
+        // No receivers is fine; the event simply isn't buffered.
+        let _ = self.event_tx.send(event);
+    }
+
+    /// Subscribes to every event emitted from now on. Unlike `on`, dropping
+    /// the receiver unsubscribes.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.event_tx.subscribe()
+    }
+
+    /// Resolves with the next event of the given type, e.g. the first
+    /// `ToolCall` of a turn. Subscribes when first polled, so start awaiting
+    /// it before (or concurrently with) the `send` that produces the event.
+    pub async fn next_event(&self, kind: EventType) -> Result<SessionEvent> {
+        let mut rx = self.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(event) if event.event_type == kind => return Ok(event),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(GeminiSDKError::Session {
+                        message: "Session event channel closed".to_string(),
+                        session_id: Some(self.session_id.clone()),
+                    })
+                }
+            }
+        }
     }
 
     pub async fn send(&self, options: MessageOptions) -> Result<()> {
+        self.send_message(options).await.map(|_| ())
+    }
+
+    /// Sends a message and returns the assistant message appended to the
+    /// history, without going through event handlers.
+    pub async fn send_message(&self, options: MessageOptions) -> Result<Message> {
+        self.send_message_inner(options, None, None).await
+    }
+
+    /// Like `send_message`, but stops generating once `cancel` fires.
+    ///
+    /// A cancelled call returns `GeminiSDKError::Cancellation` (also emitted
+    /// as `session.error`); whatever content had streamed in by then is kept
+    /// in the history as a partial assistant message.
+    pub async fn send_message_cancellable(
+        &self,
+        options: MessageOptions,
+        cancel: &CancellationToken,
+    ) -> Result<Message> {
+        self.send_message_inner(options, Some(cancel), None).await
+    }
+
+    /// Sends a message on a background task and returns a handle that can
+    /// cancel it, e.g. when the user hits "stop generating".
+    pub fn send_cancellable(self: &Arc<Self>, options: MessageOptions) -> SendHandle {
+        let token = CancellationToken::new();
+        let session = self.clone();
+        let cancel = token.clone();
+        let task =
+            tokio::spawn(async move { session.send_message_cancellable(options, &cancel).await });
+        SendHandle { token, task }
+    }
+
+    async fn send_message_inner(
+        &self,
+        options: MessageOptions,
+        cancel: Option<&CancellationToken>,
+        events: Option<&mpsc::Sender<SessionEvent>>,
+    ) -> Result<Message> {
+        let _busy = InFlight::enter(&self.in_flight);
+        let turn = Turn {
+            cancel,
+            events,
+            ..Turn::new(&options)
+        };
+        self.begin_turn(&options, turn).await?;
+        let model = options.model.as_deref().unwrap_or(&self.model);
+        let reply = self.respond_or_fall_back(model, turn).await?;
+        self.continue_truncated(reply, turn).await
+    }
+
+    /// Discards the last assistant turn, including any tool calls it made
+    /// and their results, and asks the model to answer the preceding user
+    /// message again. Fails if the history doesn't end with an assistant
+    /// turn.
+    pub async fn regenerate(&self) -> Result<Message> {
+        let _busy = InFlight::enter(&self.in_flight);
         if *self.closed.lock().await {
-            return Err(GeminiSDKError::session_closed(Some(self.session_id.clone())));
+            return Err(GeminiSDKError::session_closed(Some(
+                self.session_id.clone(),
+            )));
         }
 
-        let mut content = options.prompt.clone();
-        if let Some(context) = &options.context {
-            content = format!("{}\n\n{}", context, content);
+        {
+            let mut messages = self.messages.write().await;
+            self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+            let keep = messages
+                .iter()
+                .rposition(|m| !matches!(m.role, Role::Assistant | Role::Tool))
+                .map_or(0, |i| i + 1);
+            if keep == messages.len() || messages[keep].role != Role::Assistant {
+                return Err(GeminiSDKError::Session {
+                    message: "No assistant response to regenerate".to_string(),
+                    session_id: Some(self.session_id.clone()),
+                });
+            }
+            messages.truncate(keep);
         }
+        *self.modified_time.write().await = Utc::now();
 
-        let user_message = Message {
-            role: Role::User,
-            content: MessageContent::Text(content),
-            name: None,
-            tool_calls: None,
-            tool_call_id: None,
-        };
+        let turn = Turn::default();
+        let reply = self.respond_or_fall_back(&self.model, turn).await?;
+        self.continue_truncated(reply, turn).await
+    }
 
-        {
-            let mut messages = self.messages.lock().await;
-            messages.push(user_message);
+    /// With `auto_continue` set, asks the model to go on while `reply`
+    /// stopped at `MAX_TOKENS`, and returns the replies joined into one.
+    /// The joined message replaces the exchanges it was built from in the
+    /// history.
+    async fn continue_truncated(&self, mut reply: Reply, turn: Turn<'_>) -> Result<Message> {
+        for _ in 0..self.auto_continue {
+            if reply.message.tool_calls.is_some() || !is_truncation(reply.finish_reason.as_deref())
+            {
+                break;
+            }
+            self.messages
+                .write()
+                .await
+                .push(text_message(Role::User, CONTINUE_PROMPT));
+            let next = self.respond_or_fall_back(&reply.model, turn).await?;
+            let text = |m: &Message| m.content.as_text().unwrap_or_default().to_string();
+            let joined = Message {
+                content: MessageContent::Text(text(&reply.message) + &text(&next.message)),
+                reasoning: match (reply.message.reasoning, next.message.reasoning.clone()) {
+                    (Some(first), Some(rest)) => Some(first + &rest),
+                    (first, rest) => first.or(rest),
+                },
+                ..next.message.clone()
+            };
+            if !self.merge_continuation(&joined).await {
+                return Ok(next.message);
+            }
+            reply = Reply {
+                message: joined,
+                ..next
+            };
         }
+        Ok(reply.message)
+    }
 
-        {
-            let mut modified = self.modified_time.lock().await;
-            *modified = Utc::now();
+    /// Replaces the truncated reply, the continue prompt and the
+    /// continuation at the end of the history with `joined`. Returns `false`
+    /// and leaves the history alone if it no longer ends with that exchange.
+    async fn merge_continuation(&self, joined: &Message) -> bool {
+        let mut messages = self.messages.write().await;
+        let Some(start) = messages.len().checked_sub(3) else {
+            return false;
+        };
+        let tail = &messages[start..];
+        let is_exchange = tail[0].role == Role::Assistant
+            && tail[1].role == Role::User
+            && tail[1].content.as_text() == Some(CONTINUE_PROMPT)
+            && tail[2].role == Role::Assistant;
+        if !is_exchange {
+            return false;
         }
+        messages.truncate(start);
+        messages.push(joined.clone());
+        self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+        true
+    }
 
-        let result = if self.streaming {
-            self.stream_response().await
-        } else {
-            self.get_response().await
+    async fn respond_or_fall_back(&self, model: &str, turn: Turn<'_>) -> Result<Reply> {
+        let mut current = model.to_string();
+        let mut result = match self.respond(model, turn).await {
+            Err(e) => match self.auto_fallback(model, e) {
+                Ok(fallback) => {
+                    current = fallback;
+                    self.respond(&current, turn).await
+                }
+                Err(e) => Err(e),
+            },
+            result => result,
         };
 
+        let mut chain = self.fallback_models.iter().filter(|m| m.as_str() != model);
+        while let Err(e) = &result {
+            let Some(next) = chain.next().filter(|_| should_fall_back(e)) else {
+                break;
+            };
+            self.note_fallback(turn, &current, next, e).await;
+            current = next.clone();
+            result = self.respond(&current, turn).await;
+        }
+
+        if result.is_ok() {
+            *self.last_model.write().await = Some(current.clone());
+        }
         if let Err(ref e) = result {
-            self.emit(EventType::SessionError, json!({"error": e.to_string()}))
-                .await;
+            let mut data = json!({"error": e.to_string()});
+            if let GeminiSDKError::Stream {
+                partial_content: Some(partial),
+                ..
+            } = e
+            {
+                data["partialContent"] = json!(partial);
+            }
+            self.emit(turn, EventType::SessionError, data).await;
         }
 
-        result
+        result.map(|(message, finish_reason)| Reply {
+            message,
+            model: current,
+            finish_reason,
+        })
     }
 
-    pub async fn send_and_wait(&self, options: MessageOptions) -> Result<SessionEvent> {
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        let tx = Arc::new(Mutex::new(Some(tx)));
+    /// Sends a message and forwards every event of the turn into `tx`, in
+    /// order. Generation waits whenever the channel is full, so a slow
+    /// consumer applies backpressure. Returns once the turn completes;
+    /// handlers registered with `on` still receive the events too.
+    pub async fn send_into(
+        &self,
+        options: MessageOptions,
+        tx: mpsc::Sender<SessionEvent>,
+    ) -> Result<Message> {
+        self.send_message_inner(options, None, Some(&tx)).await
+    }
 
-        let handler: SessionEventHandler = {
-            let tx = tx.clone();
-            Arc::new(move |event: SessionEvent| {
-                if matches!(
-                    event.event_type,
-                    EventType::AssistantMessage | EventType::SessionIdle | EventType::SessionError
-                ) {
-                    if let Some(sender) = tx.try_lock().ok().and_then(|mut g| g.take()) {
-                        let _ = sender.send(event);
+    /// Sends a message and yields the raw chunks as they stream in, without
+    /// going through event handlers. The assistant message (and any tool
+    /// results) are added to the history once the stream is exhausted; if it
+    /// fails or is dropped early, the text received so far is kept instead.
+    pub async fn send_stream(
+        &self,
+        options: MessageOptions,
+    ) -> Result<impl Stream<Item = Result<LLMChunk>> + '_> {
+        let busy = InFlight::enter(&self.in_flight);
+        // The stream outlives this call, so it keeps its own handle on the
+        // options the turn's overrides borrow from.
+        let options = Arc::new(options);
+        let turn = Turn::new(&options);
+        self.begin_turn(&options, turn).await?;
+
+        let requested = options.model.as_deref().unwrap_or(&self.model);
+        let (model, stream) = self.open_with_fallbacks(requested, turn).await?;
+        *self.last_model.write().await = Some(model.clone());
+        *self.last_finish_reason.write().await = None;
+
+        let reply = PartialReply::new(&self.messages);
+        let state = (stream, reply, ToolCallAccumulator::default(), None);
+        Ok(futures::stream::unfold(Some(state), move |state| {
+            let _busy = &busy;
+            let model = model.clone();
+            let options = options.clone();
+            async move {
+                let (mut stream, mut reply, mut tool_calls, mut usage) = state?;
+                match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        reply.content.push_str(&chunk.content);
+                        if let Some(calls) = &chunk.tool_calls {
+                            tool_calls.push(calls.clone());
+                        }
+                        if chunk.usage.is_some() {
+                            usage = chunk.usage.clone();
+                        }
+                        if chunk.finish_reason.is_some() {
+                            *self.last_finish_reason.write().await = chunk.finish_reason.clone();
+                        }
+                        Some((Ok(chunk), Some((stream, reply, tool_calls, usage))))
+                    }
+                    Some(Err(e)) => Some((Err(reply.interrupt(e).await), None)),
+                    None => {
+                        if let Some(usage) = &usage {
+                            self.record_usage(&model, usage).await;
+                        }
+                        let content = reply.take();
+                        let turn = Turn::new(&options);
+                        match self
+                            .finish_streamed_turn(content, tool_calls.finish(), turn)
+                            .await
+                        {
+                            Ok(()) => None,
+                            Err(e) => Some((Err(e), None)),
+                        }
                     }
                 }
-            })
-        };
+            }
+        }))
+    }
 
-        self.on(handler).await;
-        self.send(options).await?;
+    /// Answers the history with `model`, returning the stored reply and why
+    /// it stopped.
+    async fn respond(&self, model: &str, turn: Turn<'_>) -> Result<(Message, Option<String>)> {
+        *self.last_finish_reason.write().await = None;
+        if self.streaming {
+            self.stream_response(model, turn).await
+        } else {
+            self.get_response(model, turn).await
+        }
+    }
 
-        rx.await.map_err(|_| GeminiSDKError::Session {
-            message: "No response received".to_string(),
-            session_id: Some(self.session_id.clone()),
-        })
+    async fn note_fallback(&self, turn: Turn<'_>, from: &str, to: &str, err: &GeminiSDKError) {
+        log::warn!("Model {} failed ({}); falling back to {}", from, err, to);
+        self.emit(
+            turn,
+            EventType::SessionModelFallback,
+            json!({"from": from, "to": to, "error": err.to_string()}),
+        )
+        .await;
     }
 
-    async fn stream_response(&self) -> Result<()> {
-        let mut full_content = String::new();
-        let mut full_reasoning = String::new();
-        let mut all_tool_calls: Vec<ToolCall> = Vec::new();
-        let mut final_usage = None;
+    /// Decides what to do when a request for `model` failed with `err`: if
+    /// the account can't use `"auto"` routing, returns the configured
+    /// fallback model to retry with, or an error asking for a concrete model.
+    /// Any other failure is returned unchanged.
+    fn auto_fallback(&self, model: &str, err: GeminiSDKError) -> Result<String> {
+        if model != AUTO_MODEL || !is_model_unavailable(&err) {
+            return Err(err);
+        }
+
+        match &self.auto_fallback_model {
+            Some(fallback) => {
+                log::warn!(
+                    "Model \"auto\" is unavailable ({}); falling back to {}",
+                    err,
+                    fallback
+                );
+                Ok(fallback.clone())
+            }
+            None => Err(GeminiSDKError::Configuration {
+                message: format!(
+                    "Model \"auto\" is not available for this account ({}). Set a concrete \
+                     model such as \"gemini-2.5-pro\", or configure auto_fallback_model",
+                    err
+                ),
+                config_key: Some("model".to_string()),
+            }),
+        }
+    }
+
+    /// Opens a stream for `requested`, moving down the fallback models while
+    /// that fails. Returns the model that answered with the stream.
+    async fn open_with_fallbacks(
+        &self,
+        requested: &str,
+        turn: Turn<'_>,
+    ) -> Result<(String, LLMChunkStream)> {
+        let mut model = requested.to_string();
+        let mut opened = match self.open_stream(requested, turn).await {
+            Err(e) => {
+                model = self.auto_fallback(requested, e)?;
+                self.open_stream(&model, turn).await
+            }
+            stream => stream,
+        };
+        let mut chain = self
+            .fallback_models
+            .iter()
+            .filter(|m| m.as_str() != requested);
+        while let Err(e) = &opened {
+            let Some(next) = chain.next().filter(|_| should_fall_back(e)) else {
+                break;
+            };
+            self.note_fallback(turn, &model, next, e).await;
+            model = next.clone();
+            opened = self.open_stream(&model, turn).await;
+        }
+        Ok((model, opened?))
+    }
+
+    async fn open_stream(&self, model: &str, turn: Turn<'_>) -> Result<LLMChunkStream> {
+        self.open_stream_with(model, Vec::new(), turn).await
+    }
 
-        let messages = self.messages.lock().await.clone();
+    /// Re-issues a dropped stream, asking the model to pick up after
+    /// `partial`, the text already received.
+    async fn reopen_stream(
+        &self,
+        model: &str,
+        partial: &str,
+        turn: Turn<'_>,
+    ) -> Result<LLMChunkStream> {
+        if partial.is_empty() {
+            return self.open_stream(model, turn).await;
+        }
+        let continuation = vec![
+            text_message(Role::Assistant, partial),
+            text_message(Role::User, CONTINUE_PROMPT),
+        ];
+        self.open_stream_with(model, continuation, turn).await
+    }
+
+    /// Opens a stream over the history followed by `extra`, which is sent
+    /// but not recorded.
+    async fn open_stream_with(
+        &self,
+        model: &str,
+        extra: Vec<Message>,
+        turn: Turn<'_>,
+    ) -> Result<LLMChunkStream> {
+        let mut messages = self.messages.read().await.clone();
+        messages.extend(extra);
         let tools = if self.tools.is_empty() {
             None
         } else {
             Some(self.tools.as_slice())
         };
-
-        let mut stream = self
-            .backend
+        let thinking_config = self.thinking_config_for(model, turn);
+        let generation_config = self.generation_config_for_turn(turn);
+        self.backend()
+            .await?
             .complete_streaming(
-                &self.model,
+                model,
                 &messages,
-                self.generation_config.as_ref(),
-                self.thinking_config.as_ref(),
-                tools,
+                RequestOptions {
+                    generation_config,
+                    thinking_config: thinking_config.as_ref(),
+                    tools,
+                    safety_settings: self.safety_settings.as_deref(),
+                },
             )
-            .await?;
+            .await
+    }
 
-        while let Some(chunk_result) = stream.next().await {
-            let chunk = chunk_result?;
+    async fn finish_streamed_turn(
+        &self,
+        mut content: String,
+        tool_calls: Vec<ToolCall>,
+        turn: Turn<'_>,
+    ) -> Result<()> {
+        self.trim_stop_sequence(&mut content, turn);
+        self.normalize_whitespace(&mut content);
+        self.messages.write().await.push(Message {
+            role: Role::Assistant,
+            content: MessageContent::Text(content),
+            name: None,
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls.clone())
+            },
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        });
+        *self.modified_time.write().await = Utc::now();
 
-            if !chunk.content.is_empty() {
-                full_content.push_str(&chunk.content);
-                self.emit(
+        // Results go after the call so the history reads call -> response.
+        if !tool_calls.is_empty() {
+            self.handle_tool_calls(&tool_calls, turn).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds one response's usage to the session totals, priced at the model
+    /// that produced it.
+    async fn record_usage(&self, model: &str, usage: &LLMUsage) {
+        let cost = get_gemini_cli_model(model).map_or(0.0, |info| usage.estimated_cost(&info));
+        let mut totals = self.usage_totals.write().await;
+        totals.usage.prompt_tokens += usage.prompt_tokens;
+        totals.usage.completion_tokens += usage.completion_tokens;
+        totals.usage.total_tokens += usage.total_tokens;
+        totals.usage.thoughts_tokens += usage.thoughts_tokens;
+        totals.cost += cost;
+    }
+
+    /// Token usage summed over every response in this session.
+    pub async fn usage(&self) -> LLMUsage {
+        self.usage_totals.read().await.usage.clone()
+    }
+
+    /// Estimated spend for this session, from each response's usage and its
+    /// model's prices. Zero for models without pricing.
+    pub async fn estimated_cost(&self) -> f64 {
+        self.usage_totals.read().await.cost
+    }
+
+    /// Checks the session is open and appends the user's message.
+    async fn begin_turn(&self, options: &MessageOptions, turn: Turn<'_>) -> Result<()> {
+        if *self.closed.lock().await {
+            return Err(GeminiSDKError::session_closed(Some(self.session_id.clone())));
+        }
+
+        let mut content = options.prompt.clone();
+        if let Some(context) = &options.context {
+            content = format!("{}\n\n{}", context, content);
+        }
+        let content = match options.attachments.as_deref() {
+            Some(attachments) if !attachments.is_empty() => {
+                let mut parts = vec![ContentPart {
+                    text: Some(content),
+                    image_url: None,
+                    image_data: None,
+                    image_mime_type: None,
+                    file_uri: None,
+                }];
+                let backend = self.backend().await?;
+                for attachment in attachments {
+                    parts.push(backend.resolve_attachment(attachment).await?);
+                }
+                MessageContent::Parts(parts)
+            }
+            _ => MessageContent::Text(content),
+        };
+
+        let mut user_message = Message {
+            role: Role::User,
+            content,
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        };
+
+        // This turn's attachments are uploaded before the message is
+        // stored, so a failed upload leaves the history as it was.
+        if matches!(user_message.content, MessageContent::Parts(_)) {
+            let backend = self.backend().await?;
+            for mut part in backend.large_parts(std::slice::from_ref(&user_message)) {
+                backend.upload_large_part(&mut part).await?;
+                part.apply(std::slice::from_mut(&mut user_message));
+            }
+        }
+
+        {
+            let mut messages = self.messages.write().await;
+            messages.push(user_message);
+        }
+
+        {
+            let mut modified = self.modified_time.write().await;
+            *modified = Utc::now();
+        }
+
+        if let Some(compaction) = &self.compaction {
+            let estimated = estimate_message_tokens(&self.messages.read().await);
+            if estimated > compaction.threshold_tokens {
+                if let Err(e) = self.compact_history_in(turn).await {
+                    log::warn!("Failed to compact session {}: {}", self.session_id, e);
+                }
+            }
+        }
+        self.trim_history(options.model.as_deref().unwrap_or(&self.model), turn)
+            .await;
+
+        // Covers binary tool results from the previous turn. Copies are
+        // uploaded so the history stays unlocked during the transfer; only
+        // swapping in the file URIs locks it.
+        let backend = self.backend().await?;
+        let mut large = backend.large_parts(&self.messages.read().await);
+        if !large.is_empty() {
+            for part in &mut large {
+                backend.upload_large_part(part).await?;
+            }
+            let mut messages = self.messages.write().await;
+            for part in large {
+                part.apply(&mut messages);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The current turn's generation config override, else the session's.
+    fn generation_config_for_turn<'a>(&'a self, turn: Turn<'a>) -> Option<&'a GenerationConfig> {
+        turn.generation_config.or(self.generation_config.as_ref())
+    }
+
+    /// The data of a delta event: just the new text, plus everything so far
+    /// when `include_accumulated_content` is on.
+    fn delta_payload(&self, delta: &str, accumulated: &str) -> serde_json::Value {
+        if self.include_accumulated_content {
+            json!({"deltaContent": delta, "content": accumulated})
+        } else {
+            json!({"deltaContent": delta})
+        }
+    }
+
+    /// With `trim_stop_sequences` on, cuts `content` at the turn's stop
+    /// sequences.
+    fn trim_stop_sequence(&self, content: &mut String, turn: Turn<'_>) {
+        if !self.trim_stop_sequences {
+            return;
+        }
+        let stops = self
+            .generation_config_for_turn(turn)
+            .and_then(|c| c.stop_sequences.as_ref());
+        if let Some(stops) = stops {
+            trim_stop_sequence(content, stops);
+        }
+    }
+
+    /// The current turn's thinking config override, else the session's, else
+    /// the model's default when the model supports thinking.
+    fn thinking_config_for(&self, model: &str, turn: Turn<'_>) -> Option<ThinkingConfig> {
+        if turn.thinking_config.is_some() {
+            return turn.thinking_config.cloned();
+        }
+        if self.thinking_config.is_some() {
+            return self.thinking_config.clone();
+        }
+        get_gemini_cli_model(model)
+            .filter(|info| info.supports_thinking)
+            .and_then(|info| info.default_thinking)
+    }
+
+    /// Drops the oldest non-system messages until the history fits
+    /// `max_history_tokens`, capped at the model's context window.
+    async fn trim_history(&self, model: &str, turn: Turn<'_>) {
+        let limit = match self.max_history_tokens {
+            Some(limit) => limit,
+            None => return,
+        };
+        let limit =
+            get_gemini_cli_model(model).map_or(limit, |info| limit.min(info.context_window));
+
+        let (removed, estimated) = {
+            let mut messages = self.messages.write().await;
+            let removed = trim_to_budget(&mut messages, limit);
+            if removed > 0 {
+                self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+            }
+            (removed, estimate_message_tokens(&messages))
+        };
+
+        if removed > 0 {
+            log::info!(
+                "Trimmed {} messages from session {} to fit {} tokens",
+                removed,
+                self.session_id,
+                limit
+            );
+            self.emit(
+                turn,
+                EventType::SessionHistoryTrimmed,
+                json!({
+                    "removedMessages": removed,
+                    "estimatedTokens": estimated,
+                    "maxHistoryTokens": limit
+                }),
+            )
+            .await;
+        }
+    }
+
+    /// Asks the model to summarize all but the `keep_recent` latest messages
+    /// (see `CompactionConfig`) and replaces them with the summary, kept as a
+    /// system message after the session's own. Earlier summaries are folded
+    /// into the new one. Does nothing when there is nothing old to summarize,
+    /// or when the history was edited while the summary was being written.
+    pub async fn compact_history(&self) -> Result<()> {
+        self.compact_history_in(Turn::default()).await
+    }
+
+    async fn compact_history_in(&self, turn: Turn<'_>) -> Result<()> {
+        let keep_recent = self
+            .compaction
+            .clone()
+            .unwrap_or_default()
+            .keep_recent
+            .max(1);
+        let (old, end, rewrites) = {
+            let messages = self.messages.read().await;
+            let rewrites = self.history_rewrites.load(Ordering::SeqCst);
+            let mut end = messages.len().saturating_sub(keep_recent);
+            // Keep tool results together with the call they answer.
+            while end > 0
+                && messages[end..]
+                    .first()
+                    .is_some_and(|m| m.role == Role::Tool)
+            {
+                end -= 1;
+            }
+            let old: Vec<Message> = messages[..end]
+                .iter()
+                .filter(|m| m.role != Role::System || is_summary(m))
+                .cloned()
+                .collect();
+            (old, end, rewrites)
+        };
+        if old.iter().all(is_summary) {
+            return Ok(());
+        }
+
+        let request = [
+            text_message(Role::System, COMPACTION_PROMPT),
+            text_message(Role::User, &render_transcript(&old)),
+        ];
+        let chunk = self
+            .backend()
+            .await?
+            .complete(&self.model, &request, RequestOptions::default())
+            .await?;
+        if let Some(usage) = &chunk.usage {
+            self.record_usage(&self.model, usage).await;
+        }
+        if chunk.content.trim().is_empty() {
+            return Err(GeminiSDKError::Session {
+                message: "Compaction returned an empty summary".to_string(),
+                session_id: Some(self.session_id.clone()),
+            });
+        }
+
+        let estimated = {
+            let mut messages = self.messages.write().await;
+            // Turns appended meanwhile sit after `end` and are kept; any other
+            // change means the summary no longer matches the messages.
+            if self.history_rewrites.load(Ordering::SeqCst) != rewrites {
+                log::info!(
+                    "History of session {} changed during compaction; keeping it as is",
+                    self.session_id
+                );
+                return Ok(());
+            }
+            self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+            let mut summary = text_message(
+                Role::System,
+                &format!(
+                    "Summary of the earlier conversation:\n{}",
+                    chunk.content.trim()
+                ),
+            );
+            summary.name = Some(SUMMARY_MESSAGE_NAME.to_string());
+            let mut kept: Vec<Message> = messages
+                .drain(..end)
+                .filter(|m| m.role == Role::System && !is_summary(m))
+                .collect();
+            kept.push(summary);
+            messages.splice(0..0, kept);
+            estimate_message_tokens(&messages)
+        };
+        *self.modified_time.write().await = Utc::now();
+
+        log::info!(
+            "Compacted {} messages of session {} into a summary",
+            old.len(),
+            self.session_id
+        );
+        self.emit(
+            turn,
+            EventType::SessionHistoryCompacted,
+            json!({"summarizedMessages": old.len(), "estimatedTokens": estimated}),
+        )
+        .await;
+        Ok(())
+    }
+
+    pub async fn send_and_wait(&self, options: MessageOptions) -> Result<SessionEvent> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+
+        let handler: SessionEventHandler = {
+            let tx = tx.clone();
+            Arc::new(move |event: SessionEvent| {
+                if matches!(
+                    event.event_type,
+                    EventType::AssistantMessage | EventType::SessionIdle | EventType::SessionError
+                ) {
+                    if let Some(sender) = tx.try_lock().ok().and_then(|mut g| g.take()) {
+                        let _ = sender.send(event);
+                    }
+                }
+            })
+        };
+
+        let id = self.on(handler).await;
+        let sent = self.send(options).await;
+        let response = match sent {
+            Ok(()) => rx.await.map_err(|_| GeminiSDKError::Session {
+                message: "No response received".to_string(),
+                session_id: Some(self.session_id.clone()),
+            }),
+            Err(e) => Err(e),
+        };
+        self.off(id).await;
+        response
+    }
+
+    /// Counts the tokens the current history (and tools) would consume,
+    /// so callers can check a prompt fits before calling `send`.
+    pub async fn count_tokens(&self) -> Result<u64> {
+        let messages = self.messages.read().await.clone();
+        let tools = if self.tools.is_empty() {
+            None
+        } else {
+            Some(self.tools.as_slice())
+        };
+
+        self.backend()
+            .await?
+            .count_tokens(&self.model, &messages, tools)
+            .await
+    }
+
+    async fn stream_response(
+        &self,
+        model: &str,
+        turn: Turn<'_>,
+    ) -> Result<(Message, Option<String>)> {
+        let mut reply = PartialReply::new(&self.messages);
+        let mut full_reasoning = String::new();
+        let mut all_tool_calls: Vec<ToolCall> = Vec::new();
+        let mut pending_tool_calls = ToolCallAccumulator::default();
+        let mut final_usage: Option<LLMUsage> = None;
+        let mut grounding = None;
+        let mut code_execution: Vec<CodeExecution> = Vec::new();
+        let mut finish_reason: Option<String> = None;
+        let mut block_reason: Option<BlockReason> = None;
+        let mut chunks_since_estimate: u32 = 0;
+        let mut last_estimate = Instant::now();
+
+        let mut stream = self.open_stream(model, turn).await?;
+        let mut reconnects: u32 = 0;
+
+        let mut cancelled = false;
+        loop {
+            let next = match turn.cancel {
+                Some(token) => tokio::select! {
+                    biased;
+                    _ = token.cancelled() => {
+                        cancelled = true;
+                        break;
+                    }
+                    next = stream.next() => next,
+                },
+                None => stream.next().await,
+            };
+            let chunk = match next {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(e)) if reconnects < self.stream_reconnects && is_connection_drop(&e) => {
+                    reconnects += 1;
+                    log::warn!(
+                        "Stream for session {} dropped ({}); reconnecting (attempt {}/{})",
+                        self.session_id,
+                        e,
+                        reconnects,
+                        self.stream_reconnects
+                    );
+                    self.emit(
+                        turn,
+                        EventType::SessionStreamReconnect,
+                        json!({
+                            "attempt": reconnects,
+                            "error": e.to_string(),
+                            "partialContent": reply.content
+                        }),
+                    )
+                    .await;
+                    // Call fragments from the dropped stream can't be
+                    // completed by the new one, which starts over.
+                    pending_tool_calls = ToolCallAccumulator::default();
+                    stream = match self.reopen_stream(model, &reply.content, turn).await {
+                        Ok(stream) => stream,
+                        Err(e) => return Err(reply.interrupt(e).await),
+                    };
+                    continue;
+                }
+                Some(Err(e)) => return Err(reply.interrupt(e).await),
+                None => break,
+            };
+
+            if !chunk.content.is_empty() {
+                reply.content.push_str(&chunk.content);
+                self.emit(
+                    turn,
                     EventType::AssistantMessageDelta,
-                    json!({
-                        "deltaContent": chunk.content,
-                        "content": full_content
-                    }),
+                    self.delta_payload(&chunk.content, &reply.content),
                 )
                 .await;
             }
@@ -230,27 +1510,106 @@ impl GeminiSession {
             if let Some(reasoning) = &chunk.reasoning_content {
                 full_reasoning.push_str(reasoning);
                 self.emit(
+                    turn,
                     EventType::AssistantReasoningDelta,
-                    json!({
-                        "deltaContent": reasoning,
-                        "content": full_reasoning
-                    }),
+                    self.delta_payload(reasoning, &full_reasoning),
                 )
                 .await;
             }
 
             if let Some(tool_calls) = chunk.tool_calls {
-                all_tool_calls.extend(tool_calls);
+                pending_tool_calls.push(tool_calls);
             }
 
             if chunk.usage.is_some() {
                 final_usage = chunk.usage;
             }
+
+            if chunk.grounding.is_some() {
+                grounding = chunk.grounding;
+            }
+
+            for execution in chunk.code_execution.into_iter().flatten() {
+                match code_execution.last_mut() {
+                    // A result streamed separately from its code.
+                    Some(last) if execution.code.is_empty() && last.outcome.is_none() => {
+                        last.outcome = execution.outcome;
+                        last.output = execution.output;
+                    }
+                    _ => code_execution.push(execution),
+                }
+            }
+
+            if chunk.block_reason.is_some() {
+                block_reason = chunk.block_reason;
+            }
+            if chunk.finish_reason.is_some() {
+                finish_reason = chunk.finish_reason;
+                all_tool_calls.extend(pending_tool_calls.finish());
+            }
+
+            if let Some(ref usage_config) = self.usage_estimate {
+                chunks_since_estimate += 1;
+                if chunks_since_estimate >= usage_config.every_chunks.max(1)
+                    || last_estimate.elapsed() >= Duration::from_millis(usage_config.every_ms)
+                {
+                    chunks_since_estimate = 0;
+                    last_estimate = Instant::now();
+                    self.emit(
+                        turn,
+                        EventType::AssistantUsage,
+                        json!({
+                            "completionTokens": estimate_tokens(&reply.content) + estimate_tokens(&full_reasoning),
+                            "estimated": true
+                        }),
+                    )
+                    .await;
+                }
+            }
         }
 
-        if !all_tool_calls.is_empty() {
-            self.handle_tool_calls(&all_tool_calls).await?;
+        if let Some(usage) = &final_usage {
+            self.record_usage(model, usage).await;
+        }
+
+        if let (Some(_), Some(usage)) = (&self.usage_estimate, &final_usage) {
+            self.emit(
+                turn,
+                EventType::AssistantUsage,
+                json!({
+                    "promptTokens": usage.prompt_tokens,
+                    "completionTokens": usage.completion_tokens,
+                    "totalTokens": usage.total_tokens,
+                    "estimated": false
+                }),
+            )
+            .await;
+        }
+
+        let thinking_truncated = is_thinking_truncated(
+            finish_reason.as_deref(),
+            &reply.content,
+            Some(full_reasoning.as_str()),
+        );
+        if thinking_truncated {
+            log::warn!("Thinking budget exhausted before the model produced an answer");
+        }
+        let block_reason =
+            block_reason.or_else(|| BlockReason::from_finish_reason(finish_reason.as_deref()));
+        if let Some(reason) = block_reason {
+            log::warn!("Response blocked by the backend: {:?}", reason);
+        }
+
+        if cancelled {
+            drop(stream);
+            reply.store().await;
+            return Err(GeminiSDKError::cancellation("Generation cancelled"));
         }
+        let mut full_content = reply.take();
+        self.trim_stop_sequence(&mut full_content, turn);
+        self.normalize_whitespace(&mut full_content);
+
+        all_tool_calls.extend(pending_tool_calls.finish());
 
         let assistant_message = Message {
             role: Role::Assistant,
@@ -262,15 +1621,22 @@ impl GeminiSession {
                 Some(all_tool_calls.clone())
             },
             tool_call_id: None,
+            reasoning: (!full_reasoning.is_empty()).then(|| full_reasoning.clone()),
+            tool_result_type: None,
         };
 
         {
-            let mut messages = self.messages.lock().await;
-            messages.push(assistant_message);
+            let mut messages = self.messages.write().await;
+            messages.push(assistant_message.clone());
+        }
+
+        if !all_tool_calls.is_empty() {
+            self.handle_tool_calls(&all_tool_calls, turn).await?;
         }
 
         if !full_reasoning.is_empty() {
             self.emit(
+                turn,
                 EventType::AssistantReasoning,
                 json!({"content": full_reasoning}),
             )
@@ -278,41 +1644,67 @@ impl GeminiSession {
         }
 
         self.emit(
+            turn,
             EventType::AssistantMessage,
             json!({
                 "content": full_content,
                 "toolCalls": if all_tool_calls.is_empty() { None } else { Some(&all_tool_calls) },
-                "usage": final_usage
+                "usage": final_usage,
+                "finishReason": finish_reason,
+                "thinkingTruncated": thinking_truncated,
+                "blockReason": block_reason,
+                "grounding": grounding,
+                "codeExecution": if code_execution.is_empty() { None } else { Some(&code_execution) }
             }),
         )
         .await;
 
-        self.emit(EventType::SessionIdle, json!({})).await;
+        *self.last_finish_reason.write().await = finish_reason.clone();
+        self.emit(
+            turn,
+            EventType::SessionIdle,
+            json!({"finishReason": finish_reason, "usage": final_usage}),
+        )
+        .await;
 
-        Ok(())
+        Ok((assistant_message, finish_reason))
     }
 
-    async fn get_response(&self) -> Result<()> {
-        let messages = self.messages.lock().await.clone();
+    async fn get_response(&self, model: &str, turn: Turn<'_>) -> Result<(Message, Option<String>)> {
+        let messages = self.messages.read().await.clone();
         let tools = if self.tools.is_empty() {
             None
         } else {
             Some(self.tools.as_slice())
         };
 
-        let chunk = self
-            .backend
-            .complete(
-                &self.model,
-                &messages,
-                self.generation_config.as_ref(),
-                self.thinking_config.as_ref(),
+        let thinking_config = self.thinking_config_for(model, turn);
+        let generation_config = self.generation_config_for_turn(turn);
+        let backend = self.backend().await?;
+        let request = backend.complete(
+            model,
+            &messages,
+            RequestOptions {
+                generation_config,
+                thinking_config: thinking_config.as_ref(),
                 tools,
-            )
-            .await?;
+                safety_settings: self.safety_settings.as_deref(),
+            },
+        );
+        let mut chunk = match turn.cancel {
+            Some(token) => tokio::select! {
+                _ = token.cancelled() => {
+                    return Err(GeminiSDKError::cancellation("Generation cancelled"));
+                }
+                chunk = request => chunk?,
+            },
+            None => request.await?,
+        };
+        self.trim_stop_sequence(&mut chunk.content, turn);
+        self.normalize_whitespace(&mut chunk.content);
 
-        if let Some(ref tool_calls) = chunk.tool_calls {
-            self.handle_tool_calls(tool_calls).await?;
+        if let Some(usage) = &chunk.usage {
+            self.record_usage(model, usage).await;
         }
 
         let assistant_message = Message {
@@ -321,173 +1713,2848 @@ impl GeminiSession {
             name: None,
             tool_calls: chunk.tool_calls.clone(),
             tool_call_id: None,
+            reasoning: chunk.reasoning_content.clone(),
+            tool_result_type: None,
         };
 
         {
-            let mut messages = self.messages.lock().await;
-            messages.push(assistant_message);
+            let mut messages = self.messages.write().await;
+            messages.push(assistant_message.clone());
+        }
+
+        if let Some(ref tool_calls) = chunk.tool_calls {
+            self.handle_tool_calls(tool_calls, turn).await?;
+        }
+
+        if chunk.thinking_truncated {
+            log::warn!("Thinking budget exhausted before the model produced an answer");
+        }
+        if let Some(reason) = chunk.block_reason {
+            log::warn!("Response blocked by the backend: {:?}", reason);
         }
 
         if let Some(reasoning) = &chunk.reasoning_content {
-            self.emit(EventType::AssistantReasoning, json!({"content": reasoning}))
-                .await;
+            self.emit(
+                turn,
+                EventType::AssistantReasoning,
+                json!({"content": reasoning}),
+            )
+            .await;
         }
 
         self.emit(
+            turn,
             EventType::AssistantMessage,
             json!({
                 "content": chunk.content,
                 "toolCalls": chunk.tool_calls,
-                "usage": chunk.usage
+                "usage": chunk.usage,
+                "finishReason": chunk.finish_reason,
+                "thinkingTruncated": chunk.thinking_truncated,
+                "blockReason": chunk.block_reason,
+                "grounding": chunk.grounding,
+                "codeExecution": chunk.code_execution
             }),
         )
         .await;
 
-        self.emit(EventType::SessionIdle, json!({})).await;
+        *self.last_finish_reason.write().await = chunk.finish_reason.clone();
+        self.emit(
+            turn,
+            EventType::SessionIdle,
+            json!({"finishReason": chunk.finish_reason, "usage": chunk.usage}),
+        )
+        .await;
+
+        Ok((assistant_message, chunk.finish_reason))
+    }
 
-        Ok(())
+    /// With `normalize_whitespace` on, tidies the reply's whitespace.
+    fn normalize_whitespace(&self, content: &mut String) {
+        if self.normalize_whitespace {
+            *content = normalize_whitespace(content);
+        }
     }
 
-    async fn handle_tool_calls(&self, tool_calls: &[ToolCall]) -> Result<()> {
+    /// Runs the turn's tool calls concurrently (at most `max_parallel_tools`
+    /// at a time) and appends their results to the history in call order.
+    /// A failing or panicking tool only produces its own error result.
+    async fn handle_tool_calls(&self, tool_calls: &[ToolCall], turn: Turn<'_>) -> Result<()> {
         for tool_call in tool_calls {
-            let tool_name = &tool_call.function.name;
-
             self.emit(
+                turn,
                 EventType::ToolCall,
                 json!({
-                    "name": tool_name,
+                    "name": tool_call.function.name,
                     "arguments": tool_call.function.arguments,
                     "callId": tool_call.id
                 }),
             )
             .await;
+        }
 
-            let handler = self.tool_handlers.get(tool_name);
-
-            if handler.is_none() {
-                log::warn!("No handler for tool: {}", tool_name);
-                let mut messages = self.messages.lock().await;
-                messages.push(Message {
-                    role: Role::User,
-                    content: MessageContent::Text(format!("Error: Tool '{}' not found", tool_name)),
-                    name: Some(tool_name.clone()),
-                    tool_calls: None,
-                    tool_call_id: Some(tool_call.id.clone()),
-                });
-                continue;
-            }
+        let runs: Vec<_> = tool_calls
+            .iter()
+            .map(|tc| self.run_tool(tc).boxed())
+            .collect();
+        let mut results = futures::stream::iter(runs).buffered(self.max_parallel_tools.max(1));
 
-            let handler = handler.unwrap();
+        let mut index = 0;
+        while let Some((event, content, result_type)) = results.next().await {
+            let tool_call = &tool_calls[index];
+            index += 1;
 
-            let invocation = ToolInvocation {
-                name: tool_name.clone(),
-                arguments: tool_call
-                    .function
-                    .arguments
-                    .as_object()
-                    .map(|o| {
-                        o.iter()
-                            .map(|(k, v)| (k.clone(), v.clone()))
-                            .collect()
-                    })
-                    .unwrap_or_default(),
-                call_id: tool_call.id.clone(),
-            };
+            if let Some(event) = event {
+                self.emit(turn, EventType::ToolResult, event).await;
+            }
 
-            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler(invocation))) {
-                Ok(future) => {
-                    let result = future.await;
-                    let result_text = result
-                        .text_result_for_llm
-                        .unwrap_or_else(|| "Success".to_string());
+            let mut messages = self.messages.write().await;
+            messages.push(Message {
+                role: Role::Tool,
+                content,
+                name: Some(tool_call.function.name.clone()),
+                tool_calls: None,
+                tool_call_id: Some(tool_call.id.clone()),
+                reasoning: None,
+                tool_result_type: Some(result_type),
+            });
+        }
 
-                    self.emit(
-                        EventType::ToolResult,
-                        json!({
-                            "name": tool_name,
-                            "callId": tool_call.id,
-                            "result": result_text
-                        }),
-                    )
-                    .await;
+        Ok(())
+    }
 
-                    let mut messages = self.messages.lock().await;
-                    messages.push(Message {
-                        role: Role::User,
-                        content: MessageContent::Text(result_text),
-                        name: Some(tool_name.clone()),
-                        tool_calls: None,
-                        tool_call_id: Some(tool_call.id.clone()),
-                    });
-                }
-                Err(e) => {
-                    let error_msg = format!(
-                        "Error executing tool '{}': {:?}",
-                        tool_name,
-                        e.downcast_ref::<&str>()
-                    );
-                    log::error!("{}", error_msg);
+    /// Invokes the handler for one call, returning the `tool.result` event
+    /// data (if any) and the content to record for the model.
+    async fn run_tool(
+        &self,
+        tool_call: &ToolCall,
+    ) -> (Option<serde_json::Value>, MessageContent, ToolResultType) {
+        let tool_name = &tool_call.function.name;
 
-                    self.emit(
-                        EventType::ToolResult,
-                        json!({
-                            "name": tool_name,
-                            "callId": tool_call.id,
-                            "error": error_msg
-                        }),
-                    )
-                    .await;
+        let handler = self
+            .tool_handlers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(tool_name)
+            .cloned();
+        let handler = handler.or_else(|| self.registry.as_ref()?.get_handler(tool_name).cloned());
+        let handler = match handler {
+            Some(handler) => handler,
+            None => {
+                log::warn!("No handler for tool: {}", tool_name);
+                return (
+                    None,
+                    MessageContent::Text(format!("Error: Tool '{}' not found", tool_name)),
+                    ToolResultType::Failure,
+                );
+            }
+        };
 
-                    let mut messages = self.messages.lock().await;
-                    messages.push(Message {
-                        role: Role::User,
-                        content: MessageContent::Text(error_msg),
-                        name: Some(tool_name.clone()),
-                        tool_calls: None,
-                        tool_call_id: Some(tool_call.id.clone()),
-                    });
-                }
+        let schema = self
+            .tools
+            .iter()
+            .find(|t| &t.name == tool_name)
+            .and_then(|t| t.parameters.as_ref());
+        let mut arguments = tool_call.function.arguments.clone();
+        if self.coerce_integer_arguments {
+            if let Some(schema) = schema {
+                coerce_integer_arguments(schema, &mut arguments);
             }
         }
+        let invalid = match schema {
+            Some(schema) if self.validate_tool_args => {
+                validate_tool_arguments(schema, &arguments).err()
+            }
+            _ => None,
+        };
 
-        Ok(())
-    }
+        let invocation = ToolInvocation {
+            name: tool_name.clone(),
+            arguments: arguments
+                .as_object()
+                .map(|o| o.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            call_id: tool_call.id.clone(),
+        };
 
-    pub fn add_tool(&mut self, tool: Tool) {
-        self.tools.push(tool);
-    }
+        let approved = match (&invalid, &self.tool_approver) {
+            (None, Some(approver)) => approver(&invocation).await,
+            _ => true,
+        };
 
-    pub fn remove_tool(&mut self, tool_name: &str) {
-        self.tools.retain(|t| t.name != tool_name);
-        self.tool_handlers.remove(tool_name);
+        // Catch panics both while building the future and while polling it.
+        let outcome = if let Some(errors) = invalid {
+            log::warn!(
+                "Invalid arguments for tool '{}': {}",
+                tool_name,
+                errors.join("; ")
+            );
+            Ok(failure_result(format!(
+                "Invalid arguments for tool '{}': {}",
+                tool_name,
+                errors.join("; ")
+            )))
+        } else if !approved {
+            log::info!("Tool call rejected by approver: {}", tool_name);
+            Ok(rejected_result(format!(
+                "Tool '{}' was rejected by the user",
+                tool_name
+            )))
+        } else {
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handler(invocation))) {
+                Ok(future) => std::panic::AssertUnwindSafe(future).catch_unwind().await,
+                Err(e) => Err(e),
+            }
+        };
+
+        match outcome {
+            Ok(result) => {
+                let result_text = result.text_for_llm();
+                let event = json!({
+                    "name": tool_name,
+                    "callId": tool_call.id,
+                    "result": result_text,
+                    "functionResponse": result.to_function_response(tool_name)["functionResponse"]
+                });
+                let content = match result.binary_content_part() {
+                    Some(binary) => MessageContent::Parts(vec![
+                        ContentPart {
+                            text: Some(result_text),
+                            image_url: None,
+                            image_data: None,
+                            image_mime_type: None,
+                            file_uri: None,
+                        },
+                        binary,
+                    ]),
+                    None => MessageContent::Text(result_text),
+                };
+                (
+                    Some(event),
+                    content,
+                    result.result_type.unwrap_or(ToolResultType::Success),
+                )
+            }
+            Err(e) => {
+                let error_msg = format!(
+                    "Error executing tool '{}': {:?}",
+                    tool_name,
+                    e.downcast_ref::<&str>()
+                );
+                log::error!("{}", error_msg);
+
+                let event = json!({
+                    "name": tool_name,
+                    "callId": tool_call.id,
+                    "error": error_msg
+                });
+                (
+                    Some(event),
+                    MessageContent::Text(error_msg),
+                    ToolResultType::Failure,
+                )
+            }
+        }
+    }
+
+    pub fn add_tool(&mut self, tool: Tool) {
+        self.tools.push(tool);
+    }
+
+    pub fn remove_tool(&mut self, tool_name: &str) {
+        self.tools.retain(|t| t.name != tool_name);
+        self.tool_handlers
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(tool_name);
     }
 
+    /// Drops the conversation, keeping the system message and any few-shot
+    /// examples from the session config.
     pub async fn clear_history(&self) {
-        let mut messages = self.messages.lock().await;
-        messages.clear();
+        let mut messages = self.messages.write().await;
+        self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+        *messages = seed_messages(self.system_message.as_deref(), &self.examples);
 
-        if let Some(ref sys_msg) = self.system_message {
-            messages.push(Message {
-                role: Role::System,
-                content: MessageContent::Text(sys_msg.clone()),
-                name: None,
-                tool_calls: None,
-                tool_call_id: None,
-            });
+        let mut modified = self.modified_time.write().await;
+        *modified = Utc::now();
+    }
+
+    /// Replaces the content of the message at `index` in the history.
+    pub async fn edit_message(&self, index: usize, content: MessageContent) -> Result<()> {
+        let mut messages = self.messages.write().await;
+        let message = messages
+            .get_mut(index)
+            .ok_or_else(|| message_index_error(index))?;
+        message.content = content;
+        self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+        drop(messages);
+
+        *self.modified_time.write().await = Utc::now();
+        Ok(())
+    }
+
+    /// Removes and returns the message at `index` in the history.
+    pub async fn delete_message(&self, index: usize) -> Result<Message> {
+        let mut messages = self.messages.write().await;
+        if index >= messages.len() {
+            return Err(message_index_error(index));
         }
+        let removed = messages.remove(index);
+        self.history_rewrites.fetch_add(1, Ordering::SeqCst);
+        drop(messages);
 
-        let mut modified = self.modified_time.lock().await;
-        *modified = Utc::now();
+        *self.modified_time.write().await = Utc::now();
+        Ok(removed)
     }
 
     pub async fn destroy(&self) {
         let mut closed = self.closed.lock().await;
         *closed = true;
 
-        let mut handlers = self.event_handlers.lock().await;
+        let mut handlers = self.event_handlers.write().await;
         handlers.clear();
 
-        let mut messages = self.messages.lock().await;
+        let mut messages = self.messages.write().await;
+        self.history_rewrites.fetch_add(1, Ordering::SeqCst);
         messages.clear();
     }
 }
+
+/// Handle to a message sent with `GeminiSession::send_cancellable`.
+pub struct SendHandle {
+    token: CancellationToken,
+    task: JoinHandle<Result<Message>>,
+}
+
+impl SendHandle {
+    /// Stops generation; `wait` then returns `GeminiSDKError::Cancellation`.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Waits for the assistant message (or the error that ended the turn).
+    pub async fn wait(self) -> Result<Message> {
+        self.task.await.map_err(|e| GeminiSDKError::Session {
+            message: format!("Send task failed: {}", e),
+            session_id: None,
+        })?
+    }
+}
+
+/// Flat per-image estimate; Gemini bills a standard-size image at 258 tokens.
+const IMAGE_TOKEN_ESTIMATE: u64 = 258;
+
+/// Allowance for the role and framing of each message.
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+
+/// Rough token estimate (about four characters per token), used for interim
+/// usage reporting and history trimming where exact counts aren't available.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+/// Estimates the tokens a conversation occupies: text at four characters per
+/// token, a flat cost per image, tool-call names and arguments as JSON, plus a
+/// small per-message overhead. Use `GeminiSession::count_tokens` when an
+/// exact count is needed.
+pub fn estimate_message_tokens(messages: &[Message]) -> u64 {
+    messages
+        .iter()
+        .map(|message| {
+            let content = match &message.content {
+                MessageContent::Text(text) => estimate_tokens(text),
+                MessageContent::Parts(parts) => parts
+                    .iter()
+                    .map(|part| {
+                        let image = part.image_url.is_some() || part.image_data.is_some();
+                        part.text.as_deref().map_or(0, estimate_tokens)
+                            + if image { IMAGE_TOKEN_ESTIMATE } else { 0 }
+                    })
+                    .sum(),
+            };
+            let tool_calls: u64 = message
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| {
+                    estimate_tokens(&call.function.name)
+                        + estimate_tokens(&call.function.arguments.to_string())
+                })
+                .sum();
+            content + tool_calls + MESSAGE_OVERHEAD_TOKENS
+        })
+        .sum()
+}
+
+/// Removes the oldest non-system messages until `messages` fits `budget`,
+/// returning how many were removed. A tool call and its results are removed
+/// together, and the latest message is always kept.
+fn trim_to_budget(messages: &mut Vec<Message>, budget: u64) -> usize {
+    let initial_len = messages.len();
+
+    while estimate_message_tokens(messages) > budget {
+        let last = messages.len() - 1;
+        let oldest = match messages.iter().position(|m| m.role != Role::System) {
+            Some(i) if i < last => i,
+            _ => break,
+        };
+
+        let mut remove = vec![false; messages.len()];
+        let mut call_ids: HashSet<String> = HashSet::new();
+        remove[oldest] = true;
+        call_ids.extend(tool_call_ids(&messages[oldest]));
+
+        let mut changed = !call_ids.is_empty();
+        while changed {
+            changed = false;
+            for (i, message) in messages.iter().enumerate() {
+                if remove[i] || i == last {
+                    continue;
+                }
+                let ids = tool_call_ids(message);
+                if ids.iter().any(|id| call_ids.contains(id)) {
+                    remove[i] = true;
+                    call_ids.extend(ids);
+                    changed = true;
+                }
+            }
+        }
+
+        let mut index = 0;
+        messages.retain(|_| {
+            let keep = !remove[index];
+            index += 1;
+            keep
+        });
+    }
+
+    initial_len - messages.len()
+}
+
+/// Ids linking a message to a tool call: the calls it makes, or the call it
+/// answers.
+fn tool_call_ids(message: &Message) -> Vec<String> {
+    let mut ids: Vec<String> = message
+        .tool_calls
+        .iter()
+        .flatten()
+        .map(|call| call.id.clone())
+        .collect();
+    ids.extend(message.tool_call_id.clone());
+    ids
+}
+
+/// Builds the history a session starts from: the system message followed by
+/// the few-shot examples as alternating user/assistant turns.
+fn seed_messages(system_message: Option<&str>, examples: &[(String, String)]) -> Vec<Message> {
+    let mut messages = Vec::with_capacity(1 + examples.len() * 2);
+    if let Some(sys_msg) = system_message {
+        messages.push(text_message(Role::System, sys_msg));
+    }
+    for (user, assistant) in examples {
+        messages.push(text_message(Role::User, user));
+        messages.push(text_message(Role::Assistant, assistant));
+    }
+    messages
+}
+
+fn text_message(role: Role, content: &str) -> Message {
+    Message {
+        role,
+        content: MessageContent::Text(content.to_string()),
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+        reasoning: None,
+        tool_result_type: None,
+    }
+}
+
+fn is_summary(message: &Message) -> bool {
+    message.role == Role::System && message.name.as_deref() == Some(SUMMARY_MESSAGE_NAME)
+}
+
+/// Renders messages as a plain-text transcript for summarization, with tool
+/// calls and results inline.
+fn render_transcript(messages: &[Message]) -> String {
+    let mut transcript = String::new();
+    for message in messages {
+        let speaker = match message.role {
+            Role::System => "Earlier summary",
+            Role::Tool => "Tool result",
+            Role::User => "User",
+            Role::Assistant => "Assistant",
+        };
+        let text = match &message.content {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(|part| match &part.text {
+                    Some(text) => text.clone(),
+                    None => "[attachment]".to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        if !text.is_empty() {
+            transcript.push_str(&format!("{}: {}\n", speaker, text));
+        }
+        for call in message.tool_calls.iter().flatten() {
+            transcript.push_str(&format!(
+                "Assistant called {}({})\n",
+                call.function.name, call.function.arguments
+            ));
+        }
+    }
+    transcript
+}
+
+/// Applies the `SessionConfig::normalize_whitespace` policy to a finished
+/// reply. Lines inside fenced code blocks are copied unchanged; an unclosed
+/// fence runs to the end of the reply.
+fn normalize_whitespace(content: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    let mut fence: Option<&str> = None;
+    let mut blank_pending = false;
+    for line in content.split('\n') {
+        if let Some(open) = fence {
+            lines.push(line);
+            if fence_marker(line).is_some_and(|close| is_fence_close(open, close, line)) {
+                fence = None;
+            }
+            continue;
+        }
+        if line.trim().is_empty() {
+            blank_pending = !lines.is_empty();
+            continue;
+        }
+        if blank_pending {
+            lines.push("");
+            blank_pending = false;
+        }
+        fence = fence_marker(line);
+        lines.push(if lines.is_empty() && fence.is_none() {
+            line.trim_start()
+        } else {
+            line
+        });
+    }
+
+    let mut normalized = lines.join("\n");
+    if fence.is_none() {
+        normalized.truncate(normalized.trim_end().len());
+    }
+    normalized
+}
+
+/// The backtick or tilde run that opens a fenced code block on `line`.
+fn fence_marker(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let fence_char = trimmed.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let marker = &trimmed[..trimmed.len() - trimmed.trim_start_matches(fence_char).len()];
+    (marker.len() >= 3).then_some(marker)
+}
+
+/// Whether `line`, starting with fence run `close`, ends the block opened
+/// by `open`: same character, at least as long, and nothing after it.
+fn is_fence_close(open: &str, close: &str, line: &str) -> bool {
+    close.as_bytes()[0] == open.as_bytes()[0]
+        && close.len() >= open.len()
+        && line.trim_start_matches(' ')[close.len()..]
+            .trim()
+            .is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockResponse, MockServer};
+
+    fn streaming_session(server: &MockServer, config: SessionConfig) -> GeminiSession {
+        let backend = Arc::new(GeminiBackend::for_tests(&server.url()));
+        GeminiSession::with_config("test-session".to_string(), backend, config)
+    }
+
+    fn collect_events(session: &GeminiSession) -> Arc<std::sync::Mutex<Vec<SessionEvent>>> {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        futures::executor::block_on(session.on(Arc::new(move |event: SessionEvent| {
+            sink.lock().unwrap().push(event);
+        })));
+        events
+    }
+
+    fn user_prompt(prompt: &str) -> MessageOptions {
+        MessageOptions {
+            prompt: prompt.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_config_snapshot() {
+        let server = MockServer::start(vec![]).await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                model: Some("gemini-2.5-flash".to_string()),
+                system_message: Some("Be brief.".to_string()),
+                generation_config: Some(GenerationConfig {
+                    temperature: 0.2,
+                    max_output_tokens: Some(256),
+                    ..Default::default()
+                }),
+                thinking_config: Some(ThinkingConfig {
+                    include_thoughts: true,
+                    thinking_budget: Some(1024),
+                }),
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        let config = session.config();
+        assert_eq!(config.session_id.as_deref(), Some("test-session"));
+        assert_eq!(config.model.as_deref(), Some("gemini-2.5-flash"));
+        assert_eq!(config.system_message.as_deref(), Some("Be brief."));
+        assert_eq!(config.streaming, Some(false));
+        let generation = config.generation_config.unwrap();
+        assert_eq!(generation.temperature, 0.2);
+        assert_eq!(generation.max_output_tokens, Some(256));
+        assert_eq!(config.thinking_config.unwrap().thinking_budget, Some(1024));
+        assert!(config.tools.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_tool_call_arguments_are_accumulated() {
+        let fragment = |args: &str, finish: Option<&str>| {
+            let mut candidate = json!({
+                "content": {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": args}}
+                ]}
+            });
+            if let Some(reason) = finish {
+                candidate["finishReason"] = json!(reason);
+            }
+            json!({"response": {"candidates": [candidate]}})
+        };
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            fragment("{\"city\": ", None),
+            fragment("\"Paris\"}", Some("STOP")),
+        ])])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+
+        let message = session.send_message(user_prompt("Weather?")).await.unwrap();
+
+        let calls = message.tool_calls.unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, json!({"city": "Paris"}));
+
+        // The call is recorded before its (error) result.
+        let history = session.messages().await;
+        assert_eq!(history[1].role, Role::Assistant);
+        assert_eq!(
+            history[2].tool_call_id.as_deref(),
+            Some(calls[0].id.as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_failure_keeps_partial_message() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            crate::test_support::text_response("Hello"),
+            crate::test_support::text_response(" world"),
+        ])
+        .hang_up_after_events(1)])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+        let events = collect_events(&session);
+
+        let err = session.send_message(user_prompt("Hi")).await.unwrap_err();
+
+        match err {
+            GeminiSDKError::Stream {
+                partial_content, ..
+            } => {
+                assert_eq!(partial_content.as_deref(), Some("Hello"));
+            }
+            other => panic!("expected Stream error, got {:?}", other),
+        }
+        let history = session.messages().await;
+        assert_eq!(history.last().unwrap().role, Role::Assistant);
+        assert_eq!(history.last().unwrap().content.as_text(), Some("Hello"));
+
+        let error_event = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_type == EventType::SessionError)
+            .cloned()
+            .unwrap();
+        assert_eq!(error_event.data["partialContent"], "Hello");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_stream_reconnects_and_continues() {
+        let server = MockServer::start(vec![
+            MockResponse::sse(&[
+                json!({"response": {"candidates": [{"content": {"parts": [{"text": "Hello"}]}}]}}),
+                crate::test_support::text_response(" world"),
+            ])
+            .hang_up_after_events(1),
+            MockResponse::sse(&[crate::test_support::text_response(" world")]),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                stream_reconnects: Some(1),
+                ..Default::default()
+            },
+        );
+        let events = collect_events(&session);
+
+        let reply = session.send_message(user_prompt("Hi")).await.unwrap();
+
+        assert_eq!(reply.content.as_text(), Some("Hello world"));
+        assert_eq!(session.messages().await.len(), 2);
+        let contents = server.requests()[1].json()["request"]["contents"].clone();
+        assert_eq!(
+            contents[1],
+            json!({"role": "model", "parts": [{"text": "Hello"}]})
+        );
+        assert_eq!(contents[2]["parts"][0]["text"], CONTINUE_PROMPT);
+
+        let events = events.lock().unwrap();
+        let reconnect = events
+            .iter()
+            .find(|e| e.event_type == EventType::SessionStreamReconnect)
+            .unwrap();
+        assert_eq!(reconnect.data["attempt"], 1);
+        assert_eq!(reconnect.data["partialContent"], "Hello");
+        let streamed: String = events
+            .iter()
+            .filter(|e| e.event_type == EventType::AssistantMessageDelta)
+            .map(|e| e.data["deltaContent"].as_str().unwrap())
+            .collect();
+        assert_eq!(streamed, "Hello world");
+        assert!(!events
+            .iter()
+            .any(|e| e.event_type == EventType::SessionError));
+
+        assert!(!is_connection_drop(&GeminiSDKError::stream(
+            "Malformed SSE event"
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_stop_sequence_split_across_chunks_is_trimmed() {
+        let server = MockServer::start(vec![
+            MockResponse::sse(&[
+                json!({"response": {"candidates": [{"content": {"parts": [{"text": "Item #1 costs 42 ##"}]}}]}}),
+                crate::test_support::text_response("#"),
+            ]),
+            MockResponse::json(200, crate::test_support::text_response("I pick E")),
+            MockResponse::sse(&[
+                crate::test_support::text_response("See you E"),
+                crate::test_support::text_response("ND tomorrow"),
+            ]),
+        ])
+        .await;
+        let config = SessionConfig::builder()
+            .stop_sequence("###")
+            .stop_sequence("END")
+            .trim_stop_sequences(true)
+            .build();
+        let session = streaming_session(&server, config.clone());
+        let events = collect_events(&session);
+
+        let reply = session.send_message(user_prompt("Price?")).await.unwrap();
+
+        assert_eq!(reply.content.as_text(), Some("Item #1 costs 42 "));
+        assert_eq!(
+            session.messages().await[1].content.as_text(),
+            Some("Item #1 costs 42 ")
+        );
+        let message = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_type == EventType::AssistantMessage)
+            .unwrap()
+            .data
+            .clone();
+        assert_eq!(message["content"], "Item #1 costs 42 ");
+
+        // A reply that merely ends the way a stop sequence starts is kept.
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..config
+            },
+        );
+        let reply = session
+            .send_message(user_prompt("Which option?"))
+            .await
+            .unwrap();
+        assert_eq!(reply.content.as_text(), Some("I pick E"));
+
+        // send_stream yields the chunks untouched but stores the trimmed reply,
+        // using the turn's own stop sequences.
+        let session = streaming_session(
+            &server,
+            SessionConfig::builder().trim_stop_sequences(true).build(),
+        );
+        let options = MessageOptions {
+            generation_config: Some(GenerationConfig::builder().stop_sequence("END").build()),
+            ..user_prompt("Bye")
+        };
+        let chunks: Vec<LLMChunk> = session
+            .send_stream(options)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let streamed: String = chunks.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(streamed, "See you END tomorrow");
+        assert_eq!(
+            session.messages().await[1].content.as_text(),
+            Some("See you ")
+        );
+
+        let mut content = "a END b END".to_string();
+        trim_stop_sequence(&mut content, &["END".to_string()]);
+        assert_eq!(content, "a ");
+        let mut content = "no stop here".to_string();
+        trim_stop_sequence(&mut content, &["END".to_string(), String::new()]);
+        assert_eq!(content, "no stop here");
+    }
+
+    #[tokio::test]
+    async fn test_deltas_carry_accumulated_content_only_when_enabled() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            json!({"response": {"candidates": [{"content": {"parts": [
+                {"text": "Let me think", "thought": true},
+                {"text": "Hello"}
+            ]}}]}}),
+            crate::test_support::text_response(" world"),
+        ])])
+        .await;
+        let deltas = |session: &GeminiSession| {
+            let events = collect_events(session);
+            move || -> Vec<serde_json::Value> {
+                events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|e| {
+                        matches!(
+                            e.event_type,
+                            EventType::AssistantMessageDelta | EventType::AssistantReasoningDelta
+                        )
+                    })
+                    .map(|e| e.data.clone())
+                    .collect()
+            }
+        };
+
+        let session = streaming_session(&server, SessionConfig::default());
+        let events = deltas(&session);
+        session.send_message(user_prompt("Hi")).await.unwrap();
+        assert_eq!(
+            events(),
+            vec![
+                json!({"deltaContent": "Hello"}),
+                json!({"deltaContent": "Let me think"}),
+                json!({"deltaContent": " world"}),
+            ]
+        );
+
+        let session = streaming_session(
+            &server,
+            SessionConfig::builder()
+                .include_accumulated_content(true)
+                .build(),
+        );
+        let events = deltas(&session);
+        session.send_message(user_prompt("Hi")).await.unwrap();
+        let events = events();
+        assert_eq!(
+            events[2],
+            json!({"deltaContent": " world", "content": "Hello world"})
+        );
+        assert_eq!(events[1]["content"], "Let me think");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_send_stream_keeps_partial_message() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            crate::test_support::text_response("Hello"),
+            crate::test_support::text_response(" world"),
+        ])
+        .stall_after_events(1)])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+
+        {
+            let stream = session.send_stream(user_prompt("Hi")).await.unwrap();
+            futures::pin_mut!(stream);
+            assert_eq!(stream.next().await.unwrap().unwrap().content, "Hello");
+        }
+
+        let history = session.messages().await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].content.as_text(), Some("Hello"));
+        assert!(!session.is_busy());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_mid_stream_keeps_partial_message() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            json!({"response": {"candidates": [{"content": {"role": "model", "parts": [{"text": "Hello"}]}}]}}),
+            crate::test_support::text_response(" world"),
+        ])
+        .stall_after_events(1)])
+        .await;
+        let session = Arc::new(streaming_session(&server, SessionConfig::default()));
+        let events = collect_events(&session);
+
+        let (first_delta, got_delta) = tokio::sync::oneshot::channel();
+        let first_delta = std::sync::Mutex::new(Some(first_delta));
+        session
+            .on(Arc::new(move |event: SessionEvent| {
+                if event.event_type == EventType::AssistantMessageDelta {
+                    if let Some(tx) = first_delta.lock().unwrap().take() {
+                        let _ = tx.send(());
+                    }
+                }
+            }))
+            .await;
+
+        let handle = session.send_cancellable(user_prompt("Greet me"));
+        got_delta.await.unwrap();
+        handle.cancel();
+
+        let err = tokio::time::timeout(Duration::from_secs(5), handle.wait())
+            .await
+            .expect("cancelled send did not return")
+            .unwrap_err();
+        assert!(matches!(err, GeminiSDKError::Cancellation { .. }));
+
+        let history = session.messages().await;
+        let last = history.last().unwrap();
+        assert_eq!(last.role, Role::Assistant);
+        assert!(matches!(&last.content, MessageContent::Text(t) if t == "Hello"));
+        assert!(events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|e| e.event_type == EventType::SessionError));
+    }
+
+    #[tokio::test]
+    async fn test_partial_message_is_stored_before_a_cancelled_send_returns() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            crate::test_support::text_response("Hello"),
+            crate::test_support::text_response(" world"),
+        ])
+        .stall_after_events(1)])
+        .await;
+        let session = Arc::new(streaming_session(&server, SessionConfig::default()));
+        let (first_delta, got_delta) = tokio::sync::oneshot::channel();
+        let first_delta = std::sync::Mutex::new(Some(first_delta));
+        session
+            .on(Arc::new(move |event: SessionEvent| {
+                if event.event_type == EventType::AssistantMessageDelta {
+                    if let Some(tx) = first_delta.lock().unwrap().take() {
+                        let _ = tx.send(());
+                    }
+                }
+            }))
+            .await;
+
+        let token = CancellationToken::new();
+        let send = tokio::spawn({
+            let session = session.clone();
+            let token = token.clone();
+            async move {
+                session
+                    .send_message_cancellable(user_prompt("Hi"), &token)
+                    .await
+            }
+        });
+        got_delta.await.unwrap();
+
+        // While the history is being read the partial can't be stored yet, so
+        // the send waits for it instead of returning first.
+        let reader = session.messages.read().await;
+        token.cancel();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!send.is_finished());
+        drop(reader);
+
+        let err = send.await.unwrap().unwrap_err();
+        assert!(matches!(err, GeminiSDKError::Cancellation { .. }));
+        let history = session.messages.try_read().unwrap().clone();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].content.as_text(), Some("Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_send_stream_yields_chunks_and_updates_history() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            json!({"response": {"candidates": [{"content": {"role": "model", "parts": [{"text": "Hello"}]}}]}}),
+            crate::test_support::text_response(" world"),
+        ])])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+        let events = collect_events(&session);
+
+        let mut stream = Box::pin(session.send_stream(user_prompt("Greet me")).await.unwrap());
+        let mut deltas = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            deltas.push(chunk.unwrap().content);
+        }
+        drop(stream);
+
+        assert_eq!(deltas, vec!["Hello", " world"]);
+        let history = session.messages().await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].role, Role::Assistant);
+        assert!(matches!(&history[1].content, MessageContent::Text(t) if t == "Hello world"));
+        assert!(events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_next_event_resolves_on_first_tool_call() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"response": {"candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}}
+                ]},
+                "finishReason": "STOP"
+            }]}}),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        let (event, sent) = tokio::join!(
+            session.next_event(EventType::ToolCall),
+            session.send(user_prompt("Weather in Paris?"))
+        );
+        sent.unwrap();
+
+        let event = event.unwrap();
+        assert_eq!(event.event_type, EventType::ToolCall);
+        assert_eq!(event.data["name"], "get_weather");
+        assert_eq!(event.data["arguments"]["city"], "Paris");
+        assert!(matches!(
+            event.typed().unwrap(),
+            crate::types::SessionEventData::ToolCall { name, .. } if name == "get_weather"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tool_results_use_tool_role() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("Sunny")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        session.register_tool_handler(
+            "get_weather".to_string(),
+            Arc::new(|_| Box::pin(async { crate::tools::success_result("Sunny") })),
+        );
+
+        session
+            .send(user_prompt("Weather in Paris?"))
+            .await
+            .unwrap();
+        let roles: Vec<Role> = session.messages().await.iter().map(|m| m.role).collect();
+        assert_eq!(roles, vec![Role::User, Role::Assistant, Role::Tool]);
+
+        // On the wire the result is still a user turn with a functionResponse.
+        session.send(user_prompt("Thanks")).await.unwrap();
+        let contents = server.requests()[1].json()["request"]["contents"].clone();
+        assert_eq!(contents[2]["role"], "user");
+        assert_eq!(
+            contents[2]["parts"][0]["functionResponse"]["name"],
+            "get_weather"
+        );
+
+        // Snapshots from before Role::Tool stored results as user messages.
+        let mut snapshot = session.to_metadata_with_messages().await;
+        snapshot.messages[2].role = Role::User;
+        let restored = GeminiSession::restore(session.backend.clone(), snapshot);
+        assert_eq!(restored.messages().await[2].role, Role::Tool);
+    }
+
+    #[tokio::test]
+    async fn test_failed_tool_result_is_sent_as_error() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "get_weather", "args": {"city": "Atlantis"}}},
+                        {"functionCall": {"name": "delete_files", "args": {}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("Sorry")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        session.register_tool_handler(
+            "get_weather".to_string(),
+            Arc::new(|_| Box::pin(async { crate::tools::failure_result("City not found") })),
+        );
+        session.register_tool_handler(
+            "delete_files".to_string(),
+            Arc::new(|_| Box::pin(async { crate::tools::rejected_result("Not allowed") })),
+        );
+
+        session
+            .send(user_prompt("Weather in Atlantis?"))
+            .await
+            .unwrap();
+        session.send(user_prompt("Well?")).await.unwrap();
+
+        let contents = server.requests()[1].json()["request"]["contents"].clone();
+        assert_eq!(
+            contents[2]["parts"],
+            json!([
+                {"functionResponse": {"name": "get_weather", "response": {"error": "City not found"}}},
+                {"functionResponse": {"name": "delete_files", "response": {"error": "Not allowed", "status": "rejected"}}}
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_regenerate_replaces_last_assistant_turn() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "get_weather", "args": {"city": "Paris"}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("Sunny")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        assert!(session.regenerate().await.is_err());
+
+        session
+            .send(user_prompt("Weather in Paris?"))
+            .await
+            .unwrap();
+        assert_eq!(session.messages().await.len(), 3);
+
+        let message = session.regenerate().await.unwrap();
+        assert!(matches!(&message.content, MessageContent::Text(t) if t == "Sunny"));
+
+        let history = session.messages().await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].role, Role::User);
+        assert_eq!(history[1].role, Role::Assistant);
+
+        let contents = server.requests()[1].json()["request"]["contents"].clone();
+        assert_eq!(contents.as_array().unwrap().len(), 1);
+        assert_eq!(contents[0]["parts"][0]["text"], "Weather in Paris?");
+    }
+
+    #[tokio::test]
+    async fn test_reasoning_is_kept_and_replayed() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"thought": "Paris is the capital."},
+                        {"text": "Paris"}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("About 2 million")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        session
+            .send(user_prompt("Capital of France?"))
+            .await
+            .unwrap();
+        let history = session.messages().await;
+        assert_eq!(
+            history[1].reasoning.as_deref(),
+            Some("Paris is the capital.")
+        );
+
+        session.send(user_prompt("Population?")).await.unwrap();
+        let contents = server.requests()[1].json()["request"]["contents"].clone();
+        assert_eq!(
+            contents[1]["parts"],
+            json!([{"text": "Paris is the capital.", "thought": true}, {"text": "Paris"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_code_execution_is_paired_with_its_result() {
+        let part = |part: serde_json::Value| json!({"response": {"candidates": [{"content": {"role": "model", "parts": [part]}}]}});
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            part(json!({"executableCode": {"language": "PYTHON", "code": "print(1 + 1)"}})),
+            part(json!({"codeExecutionResult": {"outcome": "OUTCOME_OK", "output": "2"}})),
+            crate::test_support::text_response("The answer is 2."),
+        ])])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+        let events = collect_events(&session);
+
+        session.send(user_prompt("What is 1 + 1?")).await.unwrap();
+
+        let message = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_type == EventType::AssistantMessage)
+            .cloned()
+            .unwrap();
+        match message.typed().unwrap() {
+            crate::types::SessionEventData::AssistantMessage { code_execution, .. } => {
+                assert_eq!(
+                    code_execution,
+                    Some(vec![CodeExecution {
+                        language: "PYTHON".to_string(),
+                        code: "print(1 + 1)".to_string(),
+                        outcome: Some("OUTCOME_OK".to_string()),
+                        output: Some("2".to_string()),
+                    }])
+                );
+            }
+            other => panic!("unexpected payload: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_attachment_upload_leaves_history_unchanged() {
+        let server = MockServer::start(vec![MockResponse::json(
+            500,
+            json!({"error": {"message": "upload unavailable"}}),
+        )])
+        .await;
+        let backend = GeminiBackend::new(crate::backend::BackendOptions {
+            oauth_path: Some(crate::test_support::write_test_credentials()),
+            base_url: Some(server.url()),
+            files_base_url: Some(server.url()),
+            inline_data_limit: Some(4),
+            retry_policy: crate::backend::RetryPolicy::none(),
+            ..Default::default()
+        })
+        .unwrap();
+        let session = GeminiSession::with_config(
+            "test-session".to_string(),
+            Arc::new(backend),
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        let result = session
+            .send_message(MessageOptions {
+                prompt: "What is this?".to_string(),
+                attachments: Some(vec![crate::types::Attachment {
+                    attachment_type: "file".to_string(),
+                    path: None,
+                    url: None,
+                    data: Some("iVBORw0KGgo=".to_string()),
+                    mime_type: None,
+                }]),
+                ..Default::default()
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(server.requests()[0].path, "/upload/v1beta/files");
+        assert!(session.messages().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_attachments_are_sent_as_parts() {
+        let server = MockServer::start(vec![
+            MockResponse::raw(200, "%PDF-1.7"),
+            MockResponse::json(200, crate::test_support::text_response("A tiny PNG.")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        session
+            .send(MessageOptions {
+                prompt: "What is this?".to_string(),
+                attachments: Some(vec![
+                    crate::types::Attachment {
+                        attachment_type: "file".to_string(),
+                        path: None,
+                        url: None,
+                        // PNG signature, so the MIME type is sniffed.
+                        data: Some("iVBORw0KGgo=".to_string()),
+                        mime_type: None,
+                    },
+                    crate::types::Attachment::from_url(format!("{}/paper.pdf", server.url())),
+                ]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        // The URL was downloaded through the backend, not sent as a file URI.
+        assert_eq!(server.requests()[0].path, "/paper.pdf");
+        let parts = server.requests()[1].json()["request"]["contents"][0]["parts"].clone();
+        assert_eq!(parts[0], json!({"text": "What is this?"}));
+        assert_eq!(
+            parts[1],
+            json!({"inlineData": {"mimeType": "image/png", "data": "iVBORw0KGgo="}})
+        );
+        assert_eq!(
+            parts[2],
+            json!({"inlineData": {"mimeType": "application/pdf", "data": "JVBERi0xLjc="}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_edit_and_delete_messages() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("Paris"),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        session
+            .send(user_prompt("Capital of France?"))
+            .await
+            .unwrap();
+
+        session
+            .edit_message(0, MessageContent::Text("Capital of Spain?".to_string()))
+            .await
+            .unwrap();
+        let removed = session.delete_message(1).await.unwrap();
+        assert!(matches!(&removed.content, MessageContent::Text(t) if t == "Paris"));
+
+        let history = session.messages().await;
+        assert_eq!(history.len(), 1);
+        assert!(matches!(&history[0].content, MessageContent::Text(t) if t == "Capital of Spain?"));
+
+        assert!(matches!(
+            session.delete_message(1).await,
+            Err(GeminiSDKError::Validation { .. })
+        ));
+        assert!(session
+            .edit_message(5, MessageContent::Text(String::new()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compact_history_summarizes_old_turns() {
+        let text = crate::test_support::text_response;
+        let server = MockServer::start(vec![
+            MockResponse::json(200, text("A1")),
+            MockResponse::json(200, text("A2")),
+            MockResponse::json(200, text("A3")),
+            MockResponse::json(200, text("They said hi twice.")),
+            MockResponse::json(200, text("A4")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                system_message: Some("Be brief.".to_string()),
+                streaming: Some(false),
+                compaction: Some(CompactionConfig {
+                    threshold_tokens: u64::MAX,
+                    keep_recent: 2,
+                }),
+                ..Default::default()
+            },
+        );
+        let received = collect_events(&session);
+        for prompt in ["Q1", "Q2", "Q3"] {
+            session.send(user_prompt(prompt)).await.unwrap();
+        }
+
+        session.compact_history().await.unwrap();
+
+        let compaction = server.requests()[3].json();
+        assert!(
+            compaction["request"]["systemInstruction"]["parts"][0]["text"]
+                .as_str()
+                .unwrap()
+                .starts_with("Summarize the conversation")
+        );
+        let transcript = compaction["request"]["contents"][0]["parts"][0]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            transcript,
+            "User: Q1\nAssistant: A1\nUser: Q2\nAssistant: A2\n"
+        );
+
+        let history = session.messages().await;
+        let texts: Vec<&str> = history
+            .iter()
+            .map(|m| m.content.as_text().unwrap())
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                "Be brief.",
+                "Summary of the earlier conversation:\nThey said hi twice.",
+                "Q3",
+                "A3"
+            ]
+        );
+        let compacted = received
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_type == EventType::SessionHistoryCompacted)
+            .cloned()
+            .unwrap();
+        assert_eq!(compacted.data["summarizedMessages"], 4);
+
+        session.send(user_prompt("Q4")).await.unwrap();
+        let next = server.requests()[4].json();
+        let system = next["request"]["systemInstruction"]["parts"][0]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(system.starts_with("Be brief."), "{}", system);
+        assert!(system.contains("They said hi twice."), "{}", system);
+        assert_eq!(next["request"]["contents"].as_array().unwrap().len(), 3);
+
+        // Nothing older than the kept turns: no request is made.
+        let before = server.requests().len();
+        let fresh = streaming_session(&server, SessionConfig::default());
+        fresh.compact_history().await.unwrap();
+        assert_eq!(server.requests().len(), before);
+
+        // Over the threshold, compaction runs before the turn is sent.
+        let auto = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                compaction: Some(CompactionConfig {
+                    threshold_tokens: 0,
+                    keep_recent: 1,
+                }),
+                ..Default::default()
+            },
+        );
+        auto.send(user_prompt("Q1")).await.unwrap();
+        auto.send(user_prompt("Q2")).await.unwrap();
+        assert_eq!(server.requests().len(), before + 3);
+        assert_eq!(auto.messages().await.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_keeps_edits_made_meanwhile() {
+        let text = crate::test_support::text_response;
+        let server = MockServer::start(vec![
+            MockResponse::json(200, text("A1")),
+            MockResponse::json(200, text("A2")),
+            MockResponse::json(200, text("They asked twice."))
+                .with_delay(Duration::from_millis(300)),
+        ])
+        .await;
+        let session = Arc::new(streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                compaction: Some(CompactionConfig {
+                    threshold_tokens: u64::MAX,
+                    keep_recent: 0,
+                }),
+                ..Default::default()
+            },
+        ));
+        session.send(user_prompt("Q1")).await.unwrap();
+        session.send(user_prompt("Q2")).await.unwrap();
+        let texts = |history: Vec<Message>| -> Vec<String> {
+            history
+                .iter()
+                .map(|m| m.content.as_text().unwrap().to_string())
+                .collect()
+        };
+
+        let compacting = session.clone();
+        let compaction = tokio::spawn(async move { compacting.compact_history().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        session
+            .edit_message(0, MessageContent::Text("Q1, edited".to_string()))
+            .await
+            .unwrap();
+        compaction.await.unwrap().unwrap();
+        assert_eq!(
+            texts(session.messages().await),
+            ["Q1, edited", "A1", "Q2", "A2"]
+        );
+
+        // `keep_recent: 0` still keeps the latest message.
+        session.compact_history().await.unwrap();
+        assert_eq!(
+            texts(session.messages().await),
+            [
+                "Summary of the earlier conversation:\nThey asked twice.",
+                "A2"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compaction_survives_a_send_that_trims_nothing() {
+        let text = crate::test_support::text_response;
+        let server = MockServer::start(vec![
+            MockResponse::json(200, text("A1")),
+            MockResponse::json(200, text("A2")),
+            MockResponse::json(200, text("They asked twice."))
+                .with_delay(Duration::from_millis(300)),
+            MockResponse::json(200, text("A3")),
+        ])
+        .await;
+        let session = Arc::new(streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                max_history_tokens: Some(1_000_000),
+                compaction: Some(CompactionConfig {
+                    threshold_tokens: u64::MAX,
+                    keep_recent: 1,
+                }),
+                ..Default::default()
+            },
+        ));
+        session.send(user_prompt("Q1")).await.unwrap();
+        session.send(user_prompt("Q2")).await.unwrap();
+
+        let compacting = session.clone();
+        let compaction = tokio::spawn(async move { compacting.compact_history().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        session.send(user_prompt("Q3")).await.unwrap();
+        compaction.await.unwrap().unwrap();
+
+        let history: Vec<String> = session
+            .messages()
+            .await
+            .iter()
+            .map(|m| m.content.as_text().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            history,
+            [
+                "Summary of the earlier conversation:\nThey asked twice.",
+                "A2",
+                "Q3",
+                "A3"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_history_is_trimmed_to_max_tokens() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("ok"),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                system_message: Some("sys".to_string()),
+                streaming: Some(false),
+                max_history_tokens: Some(100),
+                ..Default::default()
+            },
+        );
+        let events = collect_events(&session);
+
+        let text = |role: Role, content: &str| Message {
+            role,
+            content: MessageContent::Text(content.to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        };
+        {
+            let mut messages = session.messages.write().await;
+            messages.push(text(Role::User, &"q".repeat(400)));
+            messages.push(Message {
+                tool_calls: Some(vec![ToolCall {
+                    id: "call-1".to_string(),
+                    call_type: "function".to_string(),
+                    function: crate::types::FunctionCall {
+                        name: "lookup".to_string(),
+                        arguments: json!({"q": "x"}),
+                    },
+                }]),
+                ..text(Role::Assistant, "")
+            });
+            messages.push(Message {
+                name: Some("lookup".to_string()),
+                tool_call_id: Some("call-1".to_string()),
+                reasoning: None,
+                tool_result_type: None,
+                ..text(Role::User, &"r".repeat(400))
+            });
+            messages.push(text(Role::Assistant, "the answer"));
+        }
+
+        session.send(user_prompt("next?")).await.unwrap();
+
+        let contents = server.requests()[0].json()["request"]["contents"].clone();
+        let texts: Vec<&str> = contents
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["parts"][0]["text"].as_str().unwrap_or(""))
+            .collect();
+        assert_eq!(texts, vec!["the answer", "next?"]);
+
+        let events = events.lock().unwrap();
+        let trimmed = events
+            .iter()
+            .find(|e| e.event_type == EventType::SessionHistoryTrimmed)
+            .expect("no trim event");
+        assert_eq!(trimmed.data["removedMessages"], 3);
+        assert!(trimmed.data["estimatedTokens"].as_u64().unwrap() <= 100);
+    }
+
+    #[test]
+    fn test_estimate_message_tokens() {
+        let message = Message {
+            role: Role::User,
+            content: MessageContent::Text("abcdefgh".to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            reasoning: None,
+            tool_result_type: None,
+        };
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(
+            estimate_message_tokens(&[message]),
+            2 + MESSAGE_OVERHEAD_TOKENS
+        );
+    }
+
+    fn auto_unavailable() -> MockResponse {
+        MockResponse::json(
+            404,
+            json!({"error": {
+                "code": 404,
+                "message": "Requested entity was not found: model auto",
+                "status": "NOT_FOUND"
+            }}),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_auto_model_falls_back_when_unavailable() {
+        let server = MockServer::start(vec![
+            auto_unavailable(),
+            MockResponse::json(200, crate::test_support::text_response("hi")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                model: Some("auto".to_string()),
+                auto_fallback_model: Some("gemini-2.5-flash".to_string()),
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        session.send(user_prompt("Hello")).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["model"], "auto");
+        assert_eq!(requests[1].json()["model"], "gemini-2.5-flash");
+        assert_eq!(session.model(), "auto");
+    }
+
+    #[tokio::test]
+    async fn test_fallback_models_are_tried_in_order() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                429,
+                json!({"error": {
+                    "code": 429,
+                    "message": "Quota exceeded",
+                    "status": "RESOURCE_EXHAUSTED",
+                    "details": [{
+                        "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                        "reason": "QUOTA_EXHAUSTED"
+                    }]
+                }}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("hi")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                model: Some("gemini-2.5-pro".to_string()),
+                fallback_models: Some(vec!["gemini-2.5-flash".to_string()]),
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        let events = collect_events(&session);
+
+        session.send(user_prompt("Hello")).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].json()["model"], "gemini-2.5-pro");
+        assert_eq!(requests[1].json()["model"], "gemini-2.5-flash");
+        assert_eq!(session.model(), "gemini-2.5-pro");
+        assert_eq!(
+            session.last_model().await.as_deref(),
+            Some("gemini-2.5-flash")
+        );
+
+        let fallback = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_type == EventType::SessionModelFallback)
+            .cloned()
+            .expect("fallback event");
+        match fallback.typed().unwrap() {
+            crate::types::SessionEventData::SessionModelFallback { from, to, .. } => {
+                assert_eq!(from, "gemini-2.5-pro");
+                assert_eq!(to, "gemini-2.5-flash");
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_model_unavailable_without_fallback_is_clear_error() {
+        let server = MockServer::start(vec![auto_unavailable()]).await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                model: Some("auto".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let err = session.send(user_prompt("Hello")).await.unwrap_err();
+
+        match err {
+            GeminiSDKError::Configuration {
+                message,
+                config_key,
+            } => {
+                assert!(message.contains("gemini-2.5-pro"), "{}", message);
+                assert_eq!(config_key.as_deref(), Some("model"));
+            }
+            other => panic!("expected Configuration error, got {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_into_forwards_turn_events_in_order() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            json!({"response": {"candidates": [{"content": {"role": "model", "parts": [{"text": "Hello"}]}}]}}),
+            crate::test_support::text_response(" world"),
+        ])])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+
+        // Capacity 1 forces the turn to wait on the consumer.
+        let (tx, mut rx) = mpsc::channel(1);
+        let consumer = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(event) = rx.recv().await {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                received.push(event);
+            }
+            received
+        });
+
+        session
+            .send_into(user_prompt("Greet me"), tx)
+            .await
+            .unwrap();
+        let received = consumer.await.unwrap();
+
+        let kinds: Vec<EventType> = received.iter().map(|e| e.event_type).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventType::AssistantMessageDelta,
+                EventType::AssistantMessageDelta,
+                EventType::AssistantMessage,
+                EventType::SessionIdle,
+            ]
+        );
+        assert_eq!(received[2].data["content"], "Hello world");
+        for event in &received {
+            event.typed().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_into_keeps_nested_session_events_out() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "ask_helper", "args": {}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("42")),
+        ])
+        .await;
+        let config = SessionConfig {
+            streaming: Some(false),
+            ..Default::default()
+        };
+        let outer = streaming_session(&server, config.clone());
+        let helper = Arc::new(streaming_session(&server, config));
+        let helper_events = collect_events(&helper);
+        let inner = helper.clone();
+        outer.register_tool_handler(
+            "ask_helper".to_string(),
+            Arc::new(move |_| {
+                let helper = inner.clone();
+                Box::pin(async move {
+                    let reply = helper.send_message(user_prompt("Answer?")).await.unwrap();
+                    crate::tools::success_result(reply.content.as_text().unwrap_or_default())
+                })
+            }),
+        );
+
+        let (tx, mut rx) = mpsc::channel(64);
+        outer
+            .send_into(user_prompt("Ask the helper"), tx)
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        while let Some(event) = rx.recv().await {
+            received.push(event);
+        }
+        assert!(received
+            .iter()
+            .any(|e| e.event_type == EventType::ToolResult));
+        assert!(received.iter().all(|e| e.session_id == outer.session_id()));
+        assert!(!helper_events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_run_concurrently_in_order() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"response": {"candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"functionCall": {"name": "slow", "args": {"id": 1}}},
+                    {"functionCall": {"name": "explode", "args": {}}},
+                    {"functionCall": {"name": "slow", "args": {"id": 2}}}
+                ]},
+                "finishReason": "STOP"
+            }]}}),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        session.register_tool_handler(
+            "slow".to_string(),
+            Arc::new(|inv: ToolInvocation| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    crate::tools::success_result(format!("done {}", inv.arguments["id"]))
+                })
+            }),
+        );
+        session.register_tool_handler(
+            "explode".to_string(),
+            Arc::new(|_| Box::pin(async { panic!("boom") })),
+        );
+
+        let started = Instant::now();
+        session.send(user_prompt("Go")).await.unwrap();
+        assert!(
+            started.elapsed() < Duration::from_millis(550),
+            "tools ran sequentially"
+        );
+
+        let results: Vec<String> = session
+            .messages()
+            .await
+            .iter()
+            .filter(|m| m.role == Role::Tool)
+            .map(|m| m.content.as_text().unwrap().to_string())
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], "done 1");
+        assert!(results[1].contains("Error executing tool 'explode'"));
+        assert_eq!(results[2], "done 2");
+    }
+
+    #[tokio::test]
+    async fn test_tool_approver_rejects_calls() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "delete_file", "args": {"path": "/etc"}}},
+                        {"functionCall": {"name": "read_file", "args": {"path": "a.txt"}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("ok")),
+        ])
+        .await;
+        let mut session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        let ran = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for name in ["delete_file", "read_file"] {
+            let ran = ran.clone();
+            session.register_tool_handler(
+                name.to_string(),
+                Arc::new(move |inv: ToolInvocation| {
+                    ran.lock().unwrap().push(inv.name.clone());
+                    Box::pin(async { crate::tools::success_result("done") })
+                }),
+            );
+        }
+        session.set_tool_approver(Arc::new(|inv: &ToolInvocation| {
+            let allowed = inv.name != "delete_file";
+            Box::pin(async move { allowed })
+        }));
+
+        session.send_message(user_prompt("Clean up")).await.unwrap();
+
+        assert_eq!(*ran.lock().unwrap(), vec!["read_file".to_string()]);
+        let results: Vec<String> = session
+            .messages()
+            .await
+            .iter()
+            .filter(|m| m.tool_call_id.is_some())
+            .map(|m| m.content.as_text().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            results,
+            vec!["Tool 'delete_file' was rejected by the user", "done"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attached_registry_declares_and_runs_tools() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "add", "args": {"a": 2.0, "b": 3}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("5")),
+        ])
+        .await;
+        let mut session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        let mut registry = ToolRegistry::new();
+        registry.register(
+            crate::tools::create_tool(
+                "add",
+                "Add two integers",
+                Some(json!({
+                    "type": "object",
+                    "properties": {"a": {"type": "integer"}, "b": {"type": "integer"}}
+                })),
+            ),
+            |inv: ToolInvocation| async move {
+                let sum =
+                    inv.arguments["a"].as_i64().unwrap() + inv.arguments["b"].as_i64().unwrap();
+                crate::tools::success_result(sum.to_string())
+            },
+        );
+        session.attach_registry(Arc::new(registry));
+
+        session.send_message(user_prompt("2 + 3?")).await.unwrap();
+
+        let declared = &server.requests()[0].json()["request"]["tools"][0]["functionDeclarations"];
+        assert_eq!(declared[0]["name"], "add");
+        let result = session
+            .messages()
+            .await
+            .into_iter()
+            .find(|m| m.tool_call_id.is_some())
+            .unwrap();
+        assert_eq!(result.content.as_text(), Some("5"));
+    }
+
+    #[tokio::test]
+    async fn test_binary_tool_results_are_sent_as_inline_data() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "chart", "args": {}}},
+                        {"functionCall": {"name": "blob", "args": {}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("Looks good")),
+        ])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        session.register_tool_handler(
+            "chart".to_string(),
+            Arc::new(|_| {
+                Box::pin(async {
+                    ToolResult {
+                        text_result_for_llm: Some("Rendered chart".to_string()),
+                        binary_result: Some(b"\x89PNG\r\n\x1a\nrest".to_vec()),
+                        ..Default::default()
+                    }
+                })
+            }),
+        );
+        // Unrecognised bytes without a MIME type are dropped, keeping the text.
+        session.register_tool_handler(
+            "blob".to_string(),
+            Arc::new(|_| {
+                Box::pin(async {
+                    ToolResult {
+                        text_result_for_llm: Some("Raw data".to_string()),
+                        binary_result: Some(vec![1, 2, 3]),
+                        ..Default::default()
+                    }
+                })
+            }),
+        );
+
+        session.send_message(user_prompt("Plot it")).await.unwrap();
+        session
+            .send_message(user_prompt("Describe it"))
+            .await
+            .unwrap();
+
+        let contents = server.requests()[1].json()["request"]["contents"].clone();
+        let parts = contents[2]["parts"].as_array().unwrap().clone();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts[0]["functionResponse"]["name"], "chart");
+        assert_eq!(
+            parts[0]["functionResponse"]["response"]["result"],
+            "Rendered chart"
+        );
+        assert_eq!(parts[1]["inlineData"]["mimeType"], "image/png");
+        assert_eq!(parts[1]["inlineData"]["data"], "iVBORw0KGgpyZXN0");
+        assert_eq!(parts[2]["functionResponse"]["name"], "blob");
+        assert_eq!(
+            parts[2]["functionResponse"]["response"]["result"],
+            "Raw data"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_model_default_thinking_applies_without_session_config() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, crate::test_support::text_response("hi")),
+            MockResponse::json(200, crate::test_support::text_response("hi")),
+            MockResponse::json(200, crate::test_support::text_response("hi")),
+        ])
+        .await;
+
+        for model in ["gemini-2.5-pro", "gemini-2.5-flash-lite"] {
+            let session = streaming_session(
+                &server,
+                SessionConfig {
+                    model: Some(model.to_string()),
+                    streaming: Some(false),
+                    ..Default::default()
+                },
+            );
+            session.send_message(user_prompt("Hello")).await.unwrap();
+        }
+
+        let opted_out = streaming_session(
+            &server,
+            SessionConfig {
+                model: Some("gemini-2.5-pro".to_string()),
+                streaming: Some(false),
+                thinking_config: Some(ThinkingConfig {
+                    include_thoughts: false,
+                    thinking_budget: None,
+                }),
+                ..Default::default()
+            },
+        );
+        opted_out.send_message(user_prompt("Hello")).await.unwrap();
+
+        let requests = server.requests();
+        let pro = &requests[0].json()["request"]["generationConfig"];
+        let lite = &requests[1].json()["request"]["generationConfig"];
+        let off = &requests[2].json()["request"]["generationConfig"];
+        assert_eq!(pro["thinkingConfig"]["includeThoughts"], true);
+        assert!(lite.get("thinkingConfig").is_none(), "{}", lite);
+        assert!(off.get("thinkingConfig").is_none(), "{}", off);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_tool_arguments_skip_handler() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"response": {"candidates": [{
+                "content": {"role": "model", "parts": [
+                    {"functionCall": {"name": "get_weather", "args": {"town": "Paris"}}}
+                ]},
+                "finishReason": "STOP"
+            }]}}),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                validate_tool_args: Some(true),
+                tools: Some(vec![crate::tools::create_tool(
+                    "get_weather",
+                    "Get the weather",
+                    Some(json!({
+                        "type": "object",
+                        "properties": {"city": {"type": "string"}},
+                        "required": ["city"],
+                        "additionalProperties": false
+                    })),
+                )]),
+                ..Default::default()
+            },
+        );
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        session.register_tool_handler(
+            "get_weather".to_string(),
+            Arc::new(move |_| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Box::pin(async { crate::tools::success_result("sunny") })
+            }),
+        );
+
+        session.send_message(user_prompt("Weather?")).await.unwrap();
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+        let result = session
+            .messages()
+            .await
+            .into_iter()
+            .find(|m| m.tool_call_id.is_some())
+            .unwrap();
+        assert_eq!(
+            result.content.as_text(),
+            Some(
+                "Invalid arguments for tool 'get_weather': arguments: missing required property 'city'; \
+                 arguments.town: unknown property"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_usage_and_cost_accumulate_across_turns() {
+        let with_usage = |prompt: u64, completion: u64, thoughts: u64| {
+            let mut body = crate::test_support::text_response("ok");
+            body["response"]["usageMetadata"] = json!({
+                "promptTokenCount": prompt,
+                "candidatesTokenCount": completion,
+                "thoughtsTokenCount": thoughts,
+                "totalTokenCount": prompt + completion + thoughts
+            });
+            MockResponse::json(200, body)
+        };
+        let server =
+            MockServer::start(vec![with_usage(1_000, 200, 0), with_usage(3_000, 800, 500)]).await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        session.send_message(user_prompt("One")).await.unwrap();
+        session.send_message(user_prompt("Two")).await.unwrap();
+
+        let usage = session.usage().await;
+        assert_eq!(
+            (
+                usage.prompt_tokens,
+                usage.completion_tokens,
+                usage.thoughts_tokens,
+                usage.total_tokens
+            ),
+            (4_000, 1_000, 500, 5_500)
+        );
+        // The bundled model table has no prices.
+        assert_eq!(session.estimated_cost().await, 0.0);
+
+        let mut paid = get_gemini_cli_model("gemini-2.5-pro").unwrap();
+        paid.input_price = 1.25;
+        paid.output_price = 10.0;
+        assert!((usage.estimated_cost(&paid) - 0.02).abs() < 1e-12);
+    }
+
+    #[tokio::test]
+    async fn test_off_removes_handler() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("hi"),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let id = session
+            .on(Arc::new(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))
+            .await;
+
+        session
+            .emit(Turn::default(), EventType::SessionIdle, json!({}))
+            .await;
+        assert!(session.off(id).await);
+        assert!(!session.off(id).await);
+        session
+            .emit(Turn::default(), EventType::SessionIdle, json!({}))
+            .await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // send_and_wait cleans up its own one-shot handler.
+        session.send_and_wait(user_prompt("Hello")).await.unwrap();
+        assert!(session.event_handlers.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_close_if_idle_skips_busy_sessions() {
+        let server = MockServer::start(vec![]).await;
+        let session = streaming_session(&server, SessionConfig::default());
+
+        let busy = InFlight::enter(&session.in_flight);
+        assert!(!session.close_if_idle().await);
+        drop(busy);
+        assert!(session.close_if_idle().await);
+
+        let err = session.send(user_prompt("Hi")).await.unwrap_err();
+        assert!(
+            matches!(err, GeminiSDKError::SessionClosed { .. }),
+            "{:?}",
+            err
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handler_can_remove_itself() {
+        let server = MockServer::start(vec![]).await;
+        let session = Arc::new(streaming_session(&server, SessionConfig::default()));
+        let own_id = Arc::new(std::sync::OnceLock::new());
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (weak, id_slot, counter) = (Arc::downgrade(&session), own_id.clone(), calls.clone());
+        let id = session
+            .on(Arc::new(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                if let (Some(session), Some(id)) = (weak.upgrade(), id_slot.get()) {
+                    futures::executor::block_on(session.off(*id));
+                }
+            }))
+            .await;
+        own_id.set(id).unwrap();
+
+        let emitter = session.clone();
+        let emit_twice = tokio::spawn(async move {
+            emitter
+                .emit(Turn::default(), EventType::SessionIdle, json!({}))
+                .await;
+            emitter
+                .emit(Turn::default(), EventType::SessionIdle, json!({}))
+                .await;
+        });
+        tokio::time::timeout(Duration::from_secs(5), emit_twice)
+            .await
+            .expect("emit deadlocked on a handler calling off")
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_readers_do_not_block_each_other() {
+        let server = MockServer::start(vec![]).await;
+        let session = streaming_session(&server, SessionConfig::default());
+        let wait = Duration::from_millis(200);
+
+        // A long-lived reader (e.g. a dashboard snapshotting the session)
+        // must not stall other readers or event emission.
+        let _history = session.messages.read().await;
+        let _handlers = session.event_handlers.read().await;
+
+        tokio::time::timeout(wait, session.messages())
+            .await
+            .expect("messages() blocked behind another reader");
+        tokio::time::timeout(wait, session.modified_time())
+            .await
+            .expect("modified_time() blocked");
+        tokio::time::timeout(
+            wait,
+            session.emit(Turn::default(), EventType::SessionIdle, json!({})),
+        )
+        .await
+        .expect("emit blocked behind another reader");
+    }
+
+    #[tokio::test]
+    async fn test_few_shot_examples_seed_history() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("positive"),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                system_message: Some("Classify the sentiment.".to_string()),
+                examples: Some(vec![
+                    ("I love it".to_string(), "positive".to_string()),
+                    ("Terrible".to_string(), "negative".to_string()),
+                ]),
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        session.send(user_prompt("Pretty great")).await.unwrap();
+
+        let contents = server.requests()[0].json()["request"]["contents"].clone();
+        let turns: Vec<(&str, &str)> = contents
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| {
+                (
+                    c["role"].as_str().unwrap(),
+                    c["parts"][0]["text"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            turns,
+            vec![
+                ("user", "I love it"),
+                ("model", "positive"),
+                ("user", "Terrible"),
+                ("model", "negative"),
+                ("user", "Pretty great"),
+            ]
+        );
+
+        session.clear_history().await;
+        assert_eq!(session.messages().await.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_per_request_model_override() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("Summary"),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                model: Some("gemini-2.5-pro".to_string()),
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        session
+            .send(MessageOptions {
+                prompt: "Summarize".to_string(),
+                model: Some("gemini-2.5-flash".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        session.send(user_prompt("Continue")).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["model"], "gemini-2.5-flash");
+        assert_eq!(requests[1].json()["model"], "gemini-2.5-pro");
+        assert_eq!(session.model(), "gemini-2.5-pro");
+    }
+
+    #[tokio::test]
+    async fn test_per_request_generation_config_override() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("Done"),
+        )])
+        .await;
+
+        for streaming in [false, true] {
+            let session = streaming_session(
+                &server,
+                SessionConfig {
+                    model: Some("gemini-2.5-pro".to_string()),
+                    streaming: Some(streaming),
+                    generation_config: Some(GenerationConfig::builder().temperature(0.2).build()),
+                    ..Default::default()
+                },
+            );
+            let options = MessageOptions {
+                prompt: "Be creative".to_string(),
+                generation_config: Some(GenerationConfig::builder().temperature(1.5).build()),
+                thinking_config: Some(ThinkingConfig {
+                    include_thoughts: true,
+                    thinking_budget: Some(64),
+                }),
+                ..Default::default()
+            };
+            if streaming {
+                let stream = session.send_stream(options).await.unwrap();
+                stream.for_each(|_| async {}).await;
+            } else {
+                session.send(options).await.unwrap();
+            }
+            session.send(user_prompt("Again")).await.unwrap();
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        for pair in requests.chunks(2) {
+            let first = &pair[0].json()["request"]["generationConfig"];
+            assert_eq!(first["temperature"], 1.5);
+            assert_eq!(first["thinkingConfig"]["thinkingBudget"], 64);
+            let second = &pair[1].json()["request"]["generationConfig"];
+            assert_eq!(second["temperature"], 0.2);
+            assert_eq!(second["thinkingConfig"]["includeThoughts"], true);
+            assert!(second["thinkingConfig"].get("thinkingBudget").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_turn_config_does_not_reach_nested_sessions() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, crate::test_support::text_response("Hi")),
+            MockResponse::json(
+                200,
+                json!({"response": {"candidates": [{
+                    "content": {"role": "model", "parts": [
+                        {"functionCall": {"name": "retry_helper", "args": {}}}
+                    ]},
+                    "finishReason": "STOP"
+                }]}}),
+            ),
+            MockResponse::json(200, crate::test_support::text_response("Hello")),
+        ])
+        .await;
+        let config = SessionConfig {
+            streaming: Some(false),
+            ..Default::default()
+        };
+        let outer = streaming_session(&server, config.clone());
+        let helper = Arc::new(streaming_session(&server, config));
+        helper.send(user_prompt("Greet me")).await.unwrap();
+        outer.register_tool_handler(
+            "retry_helper".to_string(),
+            Arc::new(move |_| {
+                let helper = helper.clone();
+                Box::pin(async move {
+                    // Not a send, so it has no overrides of its own.
+                    helper.regenerate().await.unwrap();
+                    crate::tools::success_result("done")
+                })
+            }),
+        );
+
+        outer
+            .send(MessageOptions {
+                prompt: "Retry the helper".to_string(),
+                generation_config: Some(GenerationConfig::builder().temperature(1.5).build()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[1].json()["request"]["generationConfig"]["temperature"],
+            1.5
+        );
+        assert_ne!(
+            requests[2].json()["request"]["generationConfig"]["temperature"],
+            1.5
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_message_returns_appended_message() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            crate::test_support::text_response("Hi there"),
+        )])
+        .await;
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+
+        let message = session.send_message(user_prompt("Hello")).await.unwrap();
+
+        assert_eq!(message.role, Role::Assistant);
+        assert_eq!(message.content.as_text(), Some("Hi there"));
+        let history = session.messages().await;
+        let last = history.last().unwrap();
+        assert_eq!(last.role, message.role);
+        assert_eq!(last.content.as_text(), message.content.as_text());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_flags_thinking_truncation() {
+        let server = MockServer::start(vec![MockResponse::sse(&[
+            json!({"response": {"candidates": [{"content": {"parts": [{"thought": "Step one"}]}}]}}),
+            json!({"response": {"candidates": [{"content": {"parts": []}, "finishReason": "MAX_TOKENS"}]}}),
+        ])])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+        let received = collect_events(&session);
+
+        session.send(user_prompt("Think hard")).await.unwrap();
+
+        let events = received.lock().unwrap();
+        let message = events
+            .iter()
+            .find(|e| e.event_type == EventType::AssistantMessage)
+            .unwrap();
+        assert_eq!(message.data["thinkingTruncated"], true);
+        assert_eq!(message.data["finishReason"], "MAX_TOKENS");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_replies_are_detected_and_continued() {
+        let truncated = json!({"response": {"candidates": [{
+            "content": {"role": "model", "parts": [{"text": "Once upon"}]},
+            "finishReason": "MAX_TOKENS"
+        }]}});
+        let server = MockServer::start(vec![
+            MockResponse::json(200, truncated.clone()),
+            MockResponse::json(200, truncated),
+            MockResponse::json(200, crate::test_support::text_response(" a time.")),
+        ])
+        .await;
+        let config = SessionConfig {
+            streaming: Some(false),
+            ..Default::default()
+        };
+
+        let session = streaming_session(&server, config.clone());
+        assert!(!session.was_truncated().await);
+        session.send(user_prompt("Tell a story")).await.unwrap();
+        assert!(session.was_truncated().await);
+        assert_eq!(
+            session.last_finish_reason().await.as_deref(),
+            Some("MAX_TOKENS")
+        );
+
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                auto_continue: Some(3),
+                ..config
+            },
+        );
+        let reply = session
+            .send_message(user_prompt("Tell a story"))
+            .await
+            .unwrap();
+        assert_eq!(reply.content.as_text(), Some("Once upon a time."));
+        assert!(!session.was_truncated().await);
+        let history = session.messages().await;
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].content.as_text(), reply.content.as_text());
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        let contents = requests[2].json()["request"]["contents"].clone();
+        assert_eq!(contents[2]["parts"][0]["text"], CONTINUE_PROMPT);
+    }
+
+    #[tokio::test]
+    async fn test_idle_event_reports_finish_reason_and_usage() {
+        let server = MockServer::start(vec![
+            MockResponse::sse(&[
+                json!({"response": {"candidates": [{"content": {"parts": [{"text": "Once upon"}]}}]}}),
+                json!({"response": {
+                    "candidates": [{"content": {"parts": [{"text": " a time"}]}, "finishReason": "MAX_TOKENS"}],
+                    "usageMetadata": {"promptTokenCount": 4, "candidatesTokenCount": 8, "totalTokenCount": 12}
+                }}),
+            ]),
+            MockResponse::json(200, crate::test_support::text_response("The end.")),
+        ])
+        .await;
+        let session = streaming_session(&server, SessionConfig::default());
+        let received = collect_events(&session);
+
+        session.send(user_prompt("Tell a story")).await.unwrap();
+        let streamed = received.lock().unwrap().last().unwrap().clone();
+        assert_eq!(streamed.event_type, EventType::SessionIdle);
+        match streamed.typed().unwrap() {
+            crate::types::SessionEventData::SessionIdle {
+                finish_reason,
+                usage,
+            } => {
+                assert_eq!(finish_reason.as_deref(), Some("MAX_TOKENS"));
+                assert_eq!(usage.unwrap().total_tokens, 12);
+            }
+            other => panic!("unexpected payload: {:?}", other),
+        }
+
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        let received = collect_events(&session);
+        session.send(user_prompt("Tell a story")).await.unwrap();
+        let idle = received.lock().unwrap().last().unwrap().clone();
+        assert_eq!(idle.event_type, EventType::SessionIdle);
+        assert_eq!(idle.data["finishReason"], "STOP");
+    }
+
+    #[tokio::test]
+    async fn test_streaming_emits_interim_usage_estimates() {
+        let mut events: Vec<serde_json::Value> = (0..5)
+            .map(|i| crate::test_support::text_response(&format!("chunk number {} ", i)))
+            .collect();
+        events.push(json!({
+            "response": {
+                "candidates": [{"content": {"parts": [{"text": "done"}]}, "finishReason": "STOP"}],
+                "usageMetadata": {"promptTokenCount": 12, "candidatesTokenCount": 21, "totalTokenCount": 33}
+            }
+        }));
+        let server = MockServer::start(vec![MockResponse::sse(&events)]).await;
+
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                usage_estimate: Some(UsageEstimateConfig {
+                    every_chunks: 2,
+                    every_ms: 60_000,
+                }),
+                ..Default::default()
+            },
+        );
+        let received = collect_events(&session);
+
+        session.send(user_prompt("Count")).await.unwrap();
+
+        let usage_events: Vec<serde_json::Value> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|e| e.event_type == EventType::AssistantUsage)
+            .map(|e| e.data.clone())
+            .collect();
+
+        assert_eq!(usage_events.len(), 4);
+        assert!(usage_events[..3].iter().all(|e| e["estimated"] == true));
+        assert!(usage_events[0]["completionTokens"].as_u64().unwrap() > 0);
+
+        let last = usage_events.last().unwrap();
+        assert_eq!(last["estimated"], false);
+        assert_eq!(last["completionTokens"], 21);
+        assert_eq!(last["totalTokens"], 33);
+    }
+
+    #[tokio::test]
+    async fn test_normalize_whitespace_leaves_code_fences_alone() {
+        let raw =
+            "\n\n  Steps:\n\n\n\n```python\ndef f():\n\n\n    return 1  \n```\n\n\n\nDone.  \n\n";
+        let server = MockServer::start(vec![
+            MockResponse::sse(&[
+                crate::test_support::text_response(&raw[..20]),
+                crate::test_support::text_response(&raw[20..]),
+            ]),
+            MockResponse::json(200, crate::test_support::text_response(raw)),
+        ])
+        .await;
+        let normalized = "Steps:\n\n```python\ndef f():\n\n\n    return 1  \n```\n\nDone.";
+
+        let config = SessionConfig {
+            normalize_whitespace: Some(true),
+            ..Default::default()
+        };
+        let session = streaming_session(&server, config.clone());
+        let events = collect_events(&session);
+        let reply = session.send_message(user_prompt("Steps?")).await.unwrap();
+        assert_eq!(reply.content.as_text(), Some(normalized));
+        let message = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.event_type == EventType::AssistantMessage)
+            .unwrap()
+            .data
+            .clone();
+        assert_eq!(message["content"], normalized);
+
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..config.clone()
+            },
+        );
+        let reply = session.send_message(user_prompt("Steps?")).await.unwrap();
+        assert_eq!(reply.content.as_text(), Some(normalized));
+
+        // Off by default.
+        let session = streaming_session(
+            &server,
+            SessionConfig {
+                streaming: Some(false),
+                ..Default::default()
+            },
+        );
+        let reply = session.send_message(user_prompt("Steps?")).await.unwrap();
+        assert_eq!(reply.content.as_text(), Some(raw));
+
+        // An unclosed fence keeps everything after it.
+        assert_eq!(
+            normalize_whitespace("Code:\n\n\n~~~~\nx\n\n\n~~~\n  "),
+            "Code:\n\n~~~~\nx\n\n\n~~~\n  "
+        );
+        assert_eq!(normalize_whitespace("   \n\n"), "");
+    }
+}
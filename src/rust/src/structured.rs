@@ -0,0 +1,293 @@
+//! Incremental assembly of streamed structured (JSON) output.
+//!
+//! With `response_mime_type: "application/json"` and streaming enabled, each
+//! chunk carries a fragment of one JSON document. `JsonStreamAssembler`
+//! collects the fragments and can produce a best-effort partial value after
+//! each one, so a UI can render the object while it is still arriving.
+
+use crate::errors::Result;
+use serde_json::Value;
+
+/// Accumulates JSON fragments from a structured-output stream.
+#[derive(Debug, Default, Clone)]
+pub struct JsonStreamAssembler {
+    buffer: String,
+    scanner: PartialScanner,
+    last_partial: Option<Value>,
+}
+
+impl JsonStreamAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a fragment and returns the partial value parsed so far, if it
+    /// changed since the previous call. Only the new fragment is scanned.
+    pub fn push(&mut self, fragment: &str) -> Option<Value> {
+        self.buffer.push_str(fragment);
+        self.scanner.scan(&self.buffer);
+
+        let partial = self.scanner.partial(&self.buffer)?;
+        if self.last_partial.as_ref() == Some(&partial) {
+            return None;
+        }
+        self.last_partial = Some(partial.clone());
+        Some(partial)
+    }
+
+    /// The raw text received so far.
+    pub fn buffer(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Parses the complete document. Fails if the stream ended mid-value.
+    pub fn finish(&self) -> Result<Value> {
+        Ok(serde_json::from_str(&self.buffer)?)
+    }
+}
+
+/// Leniently parses a truncated JSON document by closing any open string,
+/// array and object. Trailing members that cannot be completed (a key
+/// without a value, a half-written literal) are dropped, and so is a
+/// trailing number, which may still grow. Returns `None` when nothing usable
+/// has arrived yet.
+pub fn parse_partial_json(input: &str) -> Option<Value> {
+    let mut scanner = PartialScanner::default();
+    scanner.scan(input);
+    scanner.partial(input)
+}
+
+/// What the scanner expects next at the current nesting level.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Expect {
+    #[default]
+    Value,
+    Key,
+    Colon,
+    Comma,
+}
+
+#[derive(Debug, Clone)]
+struct OpenString {
+    is_key: bool,
+    /// Inside an escape: `Some(0)` right after the backslash, `Some(n)`
+    /// with `n` hex digits of a `\u` escape still to come.
+    escape: Option<u8>,
+    /// End of the last complete character of the string.
+    complete_to: usize,
+}
+
+/// Tracks where a growing JSON prefix can be cut and closed, resuming from
+/// where the previous scan stopped.
+#[derive(Debug, Default, Clone)]
+struct PartialScanner {
+    scanned: usize,
+    closers: Vec<char>,
+    expect: Expect,
+    string: Option<OpenString>,
+    /// Start of an unterminated number or literal.
+    token: Option<usize>,
+    /// End of the longest prefix that is valid once `closers` are appended.
+    safe_end: usize,
+}
+
+impl PartialScanner {
+    fn scan(&mut self, input: &str) {
+        for (offset, c) in input[self.scanned..].char_indices() {
+            let i = self.scanned + offset;
+            if let Some(string) = &mut self.string {
+                if let Some(left) = string.escape {
+                    string.escape = match (left, c) {
+                        (0, 'u') => Some(4),
+                        (0 | 1, _) => None,
+                        (left, _) => Some(left - 1),
+                    };
+                    if string.escape.is_none() {
+                        string.complete_to = i + c.len_utf8();
+                    }
+                    continue;
+                }
+                match c {
+                    '\\' => string.escape = Some(0),
+                    '"' => {
+                        if string.is_key {
+                            self.expect = Expect::Colon;
+                        } else {
+                            self.expect = Expect::Comma;
+                            self.safe_end = i + 1;
+                        }
+                        self.string = None;
+                    }
+                    _ => string.complete_to = i + c.len_utf8(),
+                }
+                continue;
+            }
+
+            if self.token.is_some() {
+                if !(c.is_whitespace() || matches!(c, ',' | '}' | ']' | ':')) {
+                    continue;
+                }
+                self.expect = Expect::Comma;
+                self.safe_end = i;
+                self.token = None;
+            }
+            match c {
+                '"' => {
+                    self.string = Some(OpenString {
+                        is_key: self.expect == Expect::Key,
+                        escape: None,
+                        complete_to: i + 1,
+                    })
+                }
+                '{' | '[' => {
+                    self.closers.push(if c == '{' { '}' } else { ']' });
+                    self.expect = if c == '{' { Expect::Key } else { Expect::Value };
+                    self.safe_end = i + 1;
+                }
+                '}' | ']' => {
+                    self.closers.pop();
+                    self.expect = Expect::Comma;
+                    self.safe_end = i + 1;
+                }
+                ':' => self.expect = Expect::Value,
+                ',' => {
+                    self.expect = match self.closers.last() {
+                        Some('}') => Expect::Key,
+                        _ => Expect::Value,
+                    }
+                }
+                _ if c.is_whitespace() => {}
+                _ => self.token = Some(i),
+            }
+        }
+        self.scanned = input.len();
+    }
+
+    /// Closes the scanned prefix of `input` and parses it.
+    fn partial(&self, input: &str) -> Option<Value> {
+        let closers: String = self.closers.iter().rev().collect();
+        if let Some(string) = self.string.as_ref().filter(|string| !string.is_key) {
+            let candidate = format!("{}\"{}", &input[..string.complete_to], closers);
+            if let Ok(value) = serde_json::from_str(&candidate) {
+                return Some(value);
+            }
+        }
+
+        let prefix = &input[..self.safe_end];
+        if prefix.trim().is_empty() {
+            return None;
+        }
+        serde_json::from_str(&format!("{}{}", prefix, closers)).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_partial_json_closes_open_values() {
+        assert_eq!(parse_partial_json(""), None);
+        assert_eq!(parse_partial_json("{"), Some(json!({})));
+        assert_eq!(
+            parse_partial_json(r#"{"name": "Ad"#),
+            Some(json!({"name": "Ad"}))
+        );
+        assert_eq!(
+            parse_partial_json(r#"{"name": "Ada", "ag"#),
+            Some(json!({"name": "Ada"}))
+        );
+        assert_eq!(
+            parse_partial_json(r#"{"name": "Ada", "age":"#),
+            Some(json!({"name": "Ada"}))
+        );
+        assert_eq!(
+            parse_partial_json(r#"{"tags": ["a", "b\""#),
+            Some(json!({"tags": ["a", "b\""]}))
+        );
+        assert_eq!(parse_partial_json(r#"{"ok": tr"#), Some(json!({})));
+        assert_eq!(parse_partial_json(r#"{"a": "x\"#), Some(json!({"a": "x"})));
+        assert_eq!(
+            parse_partial_json(r#"{"a": "x\u00"#),
+            Some(json!({"a": "x"}))
+        );
+        assert_eq!(
+            parse_partial_json(r#"{"a": "x\u00e9"#),
+            Some(json!({"a": "x\u{e9}"}))
+        );
+    }
+
+    #[test]
+    fn test_partial_json_holds_back_unterminated_numbers() {
+        // "12" may be the start of 1234, so it only shows once a delimiter follows.
+        assert_eq!(parse_partial_json(r#"{"n": 12"#), Some(json!({})));
+        assert_eq!(
+            parse_partial_json(r#"{"n": 1234,"#),
+            Some(json!({"n": 1234}))
+        );
+        assert_eq!(
+            parse_partial_json(r#"{"n": 1234 "#),
+            Some(json!({"n": 1234}))
+        );
+        assert_eq!(parse_partial_json("[1, 2.5, -3e"), Some(json!([1, 2.5])));
+        assert_eq!(parse_partial_json("[1, 2]"), Some(json!([1, 2])));
+        assert_eq!(parse_partial_json("12"), None);
+    }
+
+    #[test]
+    fn test_assembler_emits_partials_and_final_value() {
+        let fragments = [
+            r#"{"title": "Du"#,
+            r#"ne", "year": 19"#,
+            r#"65, "genres": ["sci"#,
+            r#"-fi", "adventure"]"#,
+            "}",
+        ];
+
+        let mut assembler = JsonStreamAssembler::new();
+        let partials: Vec<Value> = fragments.iter().filter_map(|f| assembler.push(f)).collect();
+
+        assert_eq!(partials[0], json!({"title": "Du"}));
+        assert_eq!(partials[1], json!({"title": "Dune"}));
+        assert_eq!(
+            partials[2],
+            json!({"title": "Dune", "year": 1965, "genres": ["sci"]})
+        );
+        assert_eq!(
+            partials.last().unwrap(),
+            &json!({"title": "Dune", "year": 1965, "genres": ["sci-fi", "adventure"]})
+        );
+        assert_eq!(
+            assembler.finish().unwrap(),
+            json!({"title": "Dune", "year": 1965, "genres": ["sci-fi", "adventure"]})
+        );
+    }
+
+    #[test]
+    fn test_assembler_matches_a_full_reparse_at_every_split() {
+        let document = r#"{"a": [1, {"b": "c\"\u00e9"}], "d": -2.5e3, "e": null}"#;
+        let mut assembler = JsonStreamAssembler::new();
+        for (i, c) in document.char_indices() {
+            assembler.push(&document[i..i + c.len_utf8()]);
+            let prefix = &document[..i + c.len_utf8()];
+            assert_eq!(
+                assembler.scanner.partial(assembler.buffer()),
+                parse_partial_json(prefix),
+                "{}",
+                prefix
+            );
+        }
+        assert_eq!(
+            assembler.scanner.partial(assembler.buffer()),
+            Some(serde_json::from_str(document).unwrap())
+        );
+    }
+
+    #[test]
+    fn test_assembler_finish_rejects_truncated_document() {
+        let mut assembler = JsonStreamAssembler::new();
+        assembler.push(r#"{"title": "Du"#);
+        assert!(assembler.finish().is_err());
+    }
+}
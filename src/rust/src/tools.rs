@@ -1,13 +1,17 @@
 //! Tool utilities for GeminiSDK Rust.
 
+use crate::errors::{GeminiSDKError, Result};
 use crate::types::{Tool, ToolInvocation, ToolResult, ToolResultType};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 
+pub use geminisdk_derive::ToolSchema;
+
 pub type BoxedToolHandler =
     Arc<dyn Fn(ToolInvocation) -> Pin<Box<dyn Future<Output = ToolResult> + Send>> + Send + Sync>;
 
@@ -24,6 +28,45 @@ pub fn create_tool(
     }
 }
 
+impl Tool {
+    /// Creates a tool from a raw JSON schema, validating that it is an object
+    /// schema with a `properties` map before it is sent to the API.
+    pub fn from_schema(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        schema: Value,
+    ) -> Result<Tool> {
+        let name = name.into();
+        let obj = schema.as_object().ok_or_else(|| {
+            GeminiSDKError::validation(
+                format!("Schema for tool '{}' must be a JSON object", name),
+                Some("parameters".to_string()),
+            )
+        })?;
+
+        if let Some(schema_type) = obj.get("type") {
+            if schema_type.as_str() != Some("object") {
+                return Err(GeminiSDKError::validation(
+                    format!("Schema for tool '{}' must have type \"object\"", name),
+                    Some("parameters.type".to_string()),
+                ));
+            }
+        }
+
+        if !obj.get("properties").is_some_and(Value::is_object) {
+            return Err(GeminiSDKError::validation(
+                format!(
+                    "Schema for tool '{}' must define an object of properties",
+                    name
+                ),
+                Some("parameters.properties".to_string()),
+            ));
+        }
+
+        Ok(create_tool(name, description, Some(schema)))
+    }
+}
+
 /// Creates a declarative tool (without a handler) from a name and optional parameters.
 ///
 /// This is the Rust equivalent of passing `{"googleSearch": {}}` in Python. It is
@@ -32,6 +75,7 @@ pub fn create_tool(
 ///
 /// # Example
 /// ```rust
+/// # use geminisdk::declarative_tool;
 /// let tool = declarative_tool("googleSearch", None);
 /// ```
 pub fn declarative_tool(name: impl Into<String>, params: Option<Value>) -> Tool {
@@ -52,6 +96,7 @@ pub fn declarative_tool(name: impl Into<String>, params: Option<Value>) -> Tool
 ///
 /// # Example
 /// ```rust
+/// # use geminisdk::normalize_tools_from_value;
 /// let specs = serde_json::json!([{"googleSearch": {}}, {"codeExecution": {}}]);
 /// let tools = normalize_tools_from_value(&specs);
 /// assert_eq!(tools.len(), 2);
@@ -63,7 +108,7 @@ pub fn normalize_tools_from_value(specs: &Value) -> Vec<Tool> {
         for item in arr {
             if let Value::Object(map) = item {
                 for (name, params) in map {
-                    let parameters = if params.as_object().map_or(false, |m| !m.is_empty()) {
+                    let parameters = if params.as_object().is_some_and(|m| !m.is_empty()) {
                         Some(params.clone())
                     } else {
                         None
@@ -76,6 +121,144 @@ pub fn normalize_tools_from_value(specs: &Value) -> Vec<Tool> {
     result
 }
 
+/// Coerces whole-valued floats (e.g. `1.0`) to integers wherever `schema`
+/// declares an `integer` type, recursing into object properties and array
+/// items. Values that are not whole numbers are left untouched.
+pub fn coerce_integer_arguments(schema: &Value, args: &mut Value) {
+    match schema.get("type").and_then(|t| t.as_str()) {
+        Some("integer") => {
+            if let Some(f) = args.as_f64() {
+                if args.is_f64() && f.fract() == 0.0 && f.abs() < i64::MAX as f64 {
+                    *args = json!(f as i64);
+                }
+            }
+        }
+        Some("array") => {
+            if let (Some(items), Some(values)) = (schema.get("items"), args.as_array_mut()) {
+                for value in values {
+                    coerce_integer_arguments(items, value);
+                }
+            }
+        }
+        _ => {
+            if let (Some(props), Some(obj)) = (
+                schema.get("properties").and_then(|p| p.as_object()),
+                args.as_object_mut(),
+            ) {
+                for (key, value) in obj.iter_mut() {
+                    if let Some(prop_schema) = props.get(key) {
+                        coerce_integer_arguments(prop_schema, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Checks `args` against a tool's JSON Schema, returning one message per
+/// problem. Covers `type`, `required`, `enum`, nested `properties` and array
+/// `items`. Properties the schema doesn't declare are allowed unless
+/// `additionalProperties` is `false`; when it is a schema, they are checked
+/// against it.
+pub fn validate_tool_arguments(
+    schema: &Value,
+    args: &Value,
+) -> std::result::Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    validate_value(schema, args, "arguments", &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn validate_value(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    // Tool schemas may leave out the top-level `type`; `properties` implies
+    // an object.
+    let expected = schema
+        .get("type")
+        .and_then(Value::as_str)
+        .or_else(|| schema.get("properties").map(|_| "object"));
+
+    if let Some(expected) = expected {
+        let matches = match expected {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "boolean" => value.is_boolean(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        if !matches {
+            errors.push(format!(
+                "{}: expected {}, got {}",
+                path,
+                expected,
+                json_type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            errors.push(format!(
+                "{}: {} is not one of {}",
+                path,
+                value,
+                Value::Array(allowed.clone())
+            ));
+        }
+    }
+
+    if let Some(obj) = value.as_object() {
+        let props = schema.get("properties").and_then(Value::as_object);
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !obj.contains_key(name) {
+                    errors.push(format!("{}: missing required property '{}'", path, name));
+                }
+            }
+        }
+        for (key, field) in obj {
+            let field_path = format!("{}.{}", path, key);
+            match props.and_then(|p| p.get(key)) {
+                Some(field_schema) => validate_value(field_schema, field, &field_path, errors),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        errors.push(format!("{}: unknown property", field_path));
+                    }
+                    Some(extra) if extra.is_object() => {
+                        validate_value(extra, field, &field_path, errors)
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in values.iter().enumerate() {
+            validate_value(items, item, &format!("{}[{}]", path, i), errors);
+        }
+    }
+}
+
+fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
 /// A macro-friendly helper to define tool parameters.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ToolParameters {
@@ -89,11 +272,58 @@ pub struct ToolParameters {
 pub struct ToolProperty {
     #[serde(rename = "type")]
     pub prop_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
     pub enum_values: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub default: Option<Value>,
+    /// Fields of an `object` property.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, ToolProperty>>,
+    /// Required fields of an `object` property.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required: Option<Vec<String>>,
+    /// Element schema of an `array` property.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<ToolProperty>>,
+}
+
+impl ToolProperty {
+    /// A property of the given JSON Schema type, e.g. `"string"`.
+    pub fn new(prop_type: impl Into<String>) -> Self {
+        Self {
+            prop_type: prop_type.into(),
+            description: None,
+            enum_values: None,
+            default: None,
+            properties: None,
+            required: None,
+            items: None,
+        }
+    }
+
+    /// An `object` property with the fields described by `params`.
+    pub fn object(params: ToolParameters) -> Self {
+        Self {
+            properties: Some(params.properties),
+            required: (!params.required.is_empty()).then_some(params.required),
+            ..Self::new("object")
+        }
+    }
+
+    /// An `array` property whose elements match `items`.
+    pub fn array(items: ToolProperty) -> Self {
+        Self {
+            items: Some(Box::new(items)),
+            ..Self::new("array")
+        }
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
 }
 
 impl ToolParameters {
@@ -104,12 +334,7 @@ impl ToolParameters {
     pub fn add_string(mut self, name: impl Into<String>, description: impl Into<String>) -> Self {
         self.properties.insert(
             name.into(),
-            ToolProperty {
-                prop_type: "string".to_string(),
-                description: Some(description.into()),
-                enum_values: None,
-                default: None,
-            },
+            ToolProperty::new("string").with_description(description),
         );
         self
     }
@@ -117,12 +342,7 @@ impl ToolParameters {
     pub fn add_number(mut self, name: impl Into<String>, description: impl Into<String>) -> Self {
         self.properties.insert(
             name.into(),
-            ToolProperty {
-                prop_type: "number".to_string(),
-                description: Some(description.into()),
-                enum_values: None,
-                default: None,
-            },
+            ToolProperty::new("number").with_description(description),
         );
         self
     }
@@ -130,12 +350,7 @@ impl ToolParameters {
     pub fn add_integer(mut self, name: impl Into<String>, description: impl Into<String>) -> Self {
         self.properties.insert(
             name.into(),
-            ToolProperty {
-                prop_type: "integer".to_string(),
-                description: Some(description.into()),
-                enum_values: None,
-                default: None,
-            },
+            ToolProperty::new("integer").with_description(description),
         );
         self
     }
@@ -143,12 +358,7 @@ impl ToolParameters {
     pub fn add_boolean(mut self, name: impl Into<String>, description: impl Into<String>) -> Self {
         self.properties.insert(
             name.into(),
-            ToolProperty {
-                prop_type: "boolean".to_string(),
-                description: Some(description.into()),
-                enum_values: None,
-                default: None,
-            },
+            ToolProperty::new("boolean").with_description(description),
         );
         self
     }
@@ -162,15 +372,42 @@ impl ToolParameters {
         self.properties.insert(
             name.into(),
             ToolProperty {
-                prop_type: "string".to_string(),
-                description: Some(description.into()),
                 enum_values: Some(values),
-                default: None,
+                ..ToolProperty::new("string").with_description(description)
             },
         );
         self
     }
 
+    /// Adds a nested object whose fields are described by `params`.
+    pub fn add_object(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        params: ToolParameters,
+    ) -> Self {
+        self.properties.insert(
+            name.into(),
+            ToolProperty::object(params).with_description(description),
+        );
+        self
+    }
+
+    /// Adds an array whose elements match `items`, e.g.
+    /// `ToolProperty::new("string")` or `ToolProperty::object(..)`.
+    pub fn add_array(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        items: ToolProperty,
+    ) -> Self {
+        self.properties.insert(
+            name.into(),
+            ToolProperty::array(items).with_description(description),
+        );
+        self
+    }
+
     pub fn required(mut self, fields: Vec<&str>) -> Self {
         self.required = fields.into_iter().map(String::from).collect();
         self
@@ -181,6 +418,91 @@ impl ToolParameters {
     }
 }
 
+/// A type whose fields describe a tool's parameters, usually implemented with
+/// `#[derive(ToolSchema)]` on the struct the handler deserializes its
+/// arguments into.
+///
+/// # Example
+/// ```rust
+/// # use geminisdk::ToolSchema;
+/// #[derive(serde::Deserialize, ToolSchema)]
+/// struct WeatherArgs {
+///     /// The city name
+///     city: String,
+///     units: Option<String>,
+/// }
+///
+/// let tool = WeatherArgs::tool("get_weather", "Get current weather for a city");
+/// assert_eq!(WeatherArgs::tool_parameters().required, vec!["city"]);
+/// ```
+pub trait ToolSchema {
+    fn tool_parameters() -> ToolParameters;
+
+    /// A tool named `name` taking these parameters.
+    fn tool(name: impl Into<String>, description: impl Into<String>) -> Tool {
+        create_tool(name, description, Some(Self::tool_parameters().to_value()))
+    }
+}
+
+/// The schema of one tool parameter of this type. Implemented for the
+/// primitive types, `Option`, `Box`, the sequence types and every
+/// `#[derive(ToolSchema)]` struct.
+pub trait SchemaProperty {
+    /// Whether the parameter may be left out, as for `Option<T>`.
+    const OPTIONAL: bool = false;
+
+    fn schema_property() -> ToolProperty;
+}
+
+macro_rules! schema_property {
+    ($prop_type:literal: $($ty:ty),*) => {
+        $(impl SchemaProperty for $ty {
+            fn schema_property() -> ToolProperty {
+                ToolProperty::new($prop_type)
+            }
+        })*
+    };
+}
+
+schema_property!("string": String, char);
+schema_property!("boolean": bool);
+schema_property!("integer": i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+schema_property!("number": f32, f64);
+
+impl<T: SchemaProperty> SchemaProperty for Option<T> {
+    const OPTIONAL: bool = true;
+
+    fn schema_property() -> ToolProperty {
+        T::schema_property()
+    }
+}
+
+impl<T: SchemaProperty> SchemaProperty for Box<T> {
+    const OPTIONAL: bool = T::OPTIONAL;
+
+    fn schema_property() -> ToolProperty {
+        T::schema_property()
+    }
+}
+
+impl<T: SchemaProperty> SchemaProperty for Vec<T> {
+    fn schema_property() -> ToolProperty {
+        ToolProperty::array(T::schema_property())
+    }
+}
+
+impl<T: SchemaProperty> SchemaProperty for HashSet<T> {
+    fn schema_property() -> ToolProperty {
+        ToolProperty::array(T::schema_property())
+    }
+}
+
+impl<T: SchemaProperty> SchemaProperty for BTreeSet<T> {
+    fn schema_property() -> ToolProperty {
+        ToolProperty::array(T::schema_property())
+    }
+}
+
 /// Tool registry for managing multiple tools.
 pub struct ToolRegistry {
     tools: HashMap<String, Tool>,
@@ -215,6 +537,35 @@ impl ToolRegistry {
         );
     }
 
+    /// Registers a tool whose handler receives the arguments deserialized
+    /// into `A` rather than a raw `ToolInvocation`. Arguments that don't
+    /// deserialize are reported back to the model as a failure. Pairs with
+    /// `#[derive(ToolSchema)]`: `register_typed(Args::tool(..), handler)`.
+    pub fn register_typed<A, F, Fut>(&mut self, tool: Tool, handler: F)
+    where
+        A: DeserializeOwned + Send + 'static,
+        F: Fn(A) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ToolResult> + Send + 'static,
+    {
+        let schema = tool.parameters.clone().unwrap_or_default();
+        let handler = Arc::new(handler);
+        self.register(tool, move |invocation: ToolInvocation| {
+            let handler = handler.clone();
+            let mut args = Value::Object(invocation.arguments.into_iter().collect());
+            coerce_integer_arguments(&schema, &mut args);
+            let parsed = serde_json::from_value::<A>(args);
+            let name = invocation.name;
+            async move {
+                match parsed {
+                    Ok(args) => handler(args).await,
+                    Err(e) => {
+                        failure_result(format!("Invalid arguments for tool '{}': {}", name, e))
+                    }
+                }
+            }
+        });
+    }
+
     /// Get all registered tools.
     pub fn tools(&self) -> Vec<Tool> {
         self.tools.values().cloned().collect()
@@ -239,6 +590,7 @@ impl ToolRegistry {
                 result_type: Some(ToolResultType::Failure),
                 text_result_for_llm: Some(format!("Tool '{}' not found", invocation.name)),
                 binary_result: None,
+                binary_mime_type: None,
                 session_log: None,
             }
         }
@@ -267,6 +619,7 @@ pub fn success_result(text: impl Into<String>) -> ToolResult {
         result_type: Some(ToolResultType::Success),
         text_result_for_llm: Some(text.into()),
         binary_result: None,
+        binary_mime_type: None,
         session_log: None,
     }
 }
@@ -277,6 +630,7 @@ pub fn failure_result(text: impl Into<String>) -> ToolResult {
         result_type: Some(ToolResultType::Failure),
         text_result_for_llm: Some(text.into()),
         binary_result: None,
+        binary_mime_type: None,
         session_log: None,
     }
 }
@@ -287,6 +641,7 @@ pub fn rejected_result(text: impl Into<String>) -> ToolResult {
         result_type: Some(ToolResultType::Rejected),
         text_result_for_llm: Some(text.into()),
         binary_result: None,
+        binary_mime_type: None,
         session_log: None,
     }
 }
@@ -313,6 +668,86 @@ mod tests {
         assert!(tool.parameters.is_some());
     }
 
+    #[test]
+    fn test_tool_from_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}},
+            "required": ["city"]
+        });
+        let tool = Tool::from_schema("get_weather", "Get the weather", schema).unwrap();
+        assert_eq!(tool.name, "get_weather");
+        assert!(tool.parameters.is_some());
+    }
+
+    #[test]
+    fn test_tool_from_invalid_schema() {
+        let missing_props = serde_json::json!({"type": "object"});
+        assert!(matches!(
+            Tool::from_schema("bad", "Bad tool", missing_props),
+            Err(GeminiSDKError::Validation { .. })
+        ));
+
+        let not_object = serde_json::json!(["city"]);
+        assert!(matches!(
+            Tool::from_schema("bad", "Bad tool", not_object),
+            Err(GeminiSDKError::Validation { .. })
+        ));
+    }
+
+    #[test]
+    fn test_coerce_integer_arguments() {
+        #[derive(Deserialize)]
+        struct Args {
+            count: i64,
+            ratio: f64,
+        }
+
+        let schema = ToolParameters::new()
+            .add_integer("count", "How many")
+            .add_number("ratio", "A ratio")
+            .to_value();
+        let mut args = json!({"count": 1.0, "ratio": 2.0});
+
+        coerce_integer_arguments(&schema, &mut args);
+
+        assert!(args["count"].is_i64());
+        assert!(args["ratio"].is_f64());
+        let parsed: Args = serde_json::from_value(args).unwrap();
+        assert_eq!(parsed.count, 1);
+        assert_eq!(parsed.ratio, 2.0);
+    }
+
+    #[test]
+    fn test_tool_result_to_function_response() {
+        assert_eq!(
+            success_result("72F and sunny").to_function_response("get_weather"),
+            json!({"functionResponse": {"name": "get_weather", "response": {"result": "72F and sunny"}}})
+        );
+        assert_eq!(
+            failure_result("city not found").to_function_response("get_weather"),
+            json!({"functionResponse": {"name": "get_weather", "response": {"error": "city not found"}}})
+        );
+        assert_eq!(
+            rejected_result("user declined").to_function_response("get_weather"),
+            json!({"functionResponse": {
+                "name": "get_weather",
+                "response": {"error": "user declined", "status": "rejected"}
+            }})
+        );
+
+        let long = success_result("x".repeat(crate::types::MAX_FUNCTION_RESPONSE_CHARS + 10));
+        let value = long.to_function_response("dump");
+        assert_eq!(value["functionResponse"]["response"]["truncated"], true);
+        assert_eq!(
+            value["functionResponse"]["response"]["result"]
+                .as_str()
+                .unwrap()
+                .len(),
+            crate::types::MAX_FUNCTION_RESPONSE_CHARS
+        );
+    }
+
     #[test]
     fn test_declarative_tool() {
         let tool = declarative_tool("googleSearch", None);
@@ -338,6 +773,64 @@ mod tests {
         assert!(tools[0].parameters.is_some());
     }
 
+    #[test]
+    fn test_validate_tool_arguments() {
+        let schema = ToolParameters::new()
+            .add_string("city", "City")
+            .add_enum("unit", "Unit", vec!["c".into(), "f".into()])
+            .add_array(
+                "days",
+                "Days",
+                ToolProperty::object(ToolParameters::new().add_integer("offset", "Offset")),
+            )
+            .required(vec!["city"])
+            .to_value();
+
+        assert!(validate_tool_arguments(
+            &schema,
+            &json!({"city": "Paris", "days": [{"offset": 1}]})
+        )
+        .is_ok());
+
+        let errors = validate_tool_arguments(
+            &schema,
+            &json!({"unit": "k", "town": "Paris", "days": [{"offset": 1.5}]}),
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "arguments: missing required property 'city'",
+                "arguments.days[0].offset: expected integer, got number",
+                "arguments.unit: \"k\" is not one of [\"c\",\"f\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_additional_properties() {
+        let schema = |extra: Option<Value>| {
+            let mut schema = json!({"type": "object", "properties": {"city": {"type": "string"}}});
+            if let Some(extra) = extra {
+                schema["additionalProperties"] = extra;
+            }
+            schema
+        };
+        let args = json!({"city": "Paris", "days": 3});
+
+        assert!(validate_tool_arguments(&schema(None), &args).is_ok());
+        assert!(validate_tool_arguments(&schema(Some(json!(true))), &args).is_ok());
+        assert_eq!(
+            validate_tool_arguments(&schema(Some(json!(false))), &args).unwrap_err(),
+            vec!["arguments.days: unknown property"]
+        );
+        assert!(validate_tool_arguments(&schema(Some(json!({"type": "integer"}))), &args).is_ok());
+        assert_eq!(
+            validate_tool_arguments(&schema(Some(json!({"type": "string"}))), &args).unwrap_err(),
+            vec!["arguments.days: expected string, got integer"]
+        );
+    }
+
     #[test]
     fn test_tool_parameters() {
         let params = ToolParameters::new()
@@ -351,4 +844,207 @@ mod tests {
         assert!(value.get("properties").is_some());
         assert!(value.get("required").is_some());
     }
+
+    #[test]
+    fn test_nested_tool_parameters() {
+        let params = ToolParameters::new()
+            .add_string("customer", "Customer id")
+            .add_array(
+                "items",
+                "Line items",
+                ToolProperty::object(
+                    ToolParameters::new()
+                        .add_string("id", "Product id")
+                        .add_integer("qty", "Quantity")
+                        .required(vec!["id", "qty"]),
+                ),
+            )
+            .add_object(
+                "shipping",
+                "Shipping details",
+                ToolParameters::new().add_string("city", "City").add_array(
+                    "lines",
+                    "Address lines",
+                    ToolProperty::new("string"),
+                ),
+            )
+            .required(vec!["customer", "items"]);
+
+        assert_eq!(
+            params.to_value(),
+            json!({
+                "properties": {
+                    "customer": {"type": "string", "description": "Customer id"},
+                    "items": {
+                        "type": "array",
+                        "description": "Line items",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "id": {"type": "string", "description": "Product id"},
+                                "qty": {"type": "integer", "description": "Quantity"}
+                            },
+                            "required": ["id", "qty"]
+                        }
+                    },
+                    "shipping": {
+                        "type": "object",
+                        "description": "Shipping details",
+                        "properties": {
+                            "city": {"type": "string", "description": "City"},
+                            "lines": {
+                                "type": "array",
+                                "description": "Address lines",
+                                "items": {"type": "string"}
+                            }
+                        }
+                    }
+                },
+                "required": ["customer", "items"]
+            })
+        );
+    }
+
+    #[derive(Deserialize, ToolSchema)]
+    struct Stop {
+        city: String,
+        #[serde(default)]
+        nights: u32,
+    }
+
+    #[derive(Deserialize, ToolSchema)]
+    #[serde(rename_all = "camelCase")]
+    struct WeatherArgs {
+        /// The city name
+        city: String,
+        /// Temperature units,
+        /// "metric" or "imperial"
+        units: Option<String>,
+        forecast_days: Vec<u8>,
+        #[serde(rename = "via")]
+        stops: Option<Vec<Stop>>,
+        #[serde(skip)]
+        #[allow(dead_code)]
+        cache_key: String,
+    }
+
+    #[tokio::test]
+    async fn test_derived_tool_schema() {
+        assert_eq!(
+            WeatherArgs::tool("get_weather", "Get weather")
+                .parameters
+                .unwrap(),
+            json!({
+                "properties": {
+                    "city": {"type": "string", "description": "The city name"},
+                    "units": {"type": "string", "description": "Temperature units, \"metric\" or \"imperial\""},
+                    "forecastDays": {"type": "array", "items": {"type": "integer"}},
+                    "via": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {"city": {"type": "string"}, "nights": {"type": "integer"}},
+                            "required": ["city"]
+                        }
+                    }
+                },
+                "required": ["city", "forecastDays"]
+            })
+        );
+
+        let mut registry = ToolRegistry::new();
+        registry.register_typed(
+            WeatherArgs::tool("get_weather", "Get weather"),
+            |args: WeatherArgs| async move {
+                let stops = args.stops.unwrap_or_default();
+                success_result(format!(
+                    "{} {:?} {:?} {}",
+                    args.city,
+                    args.units,
+                    args.forecast_days,
+                    stops
+                        .iter()
+                        .map(|s| format!("{}:{}", s.city, s.nights))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                ))
+            },
+        );
+        assert_eq!(
+            registry.tools()[0].parameters,
+            WeatherArgs::tool("x", "y").parameters
+        );
+
+        let invoke = |arguments: Value| ToolInvocation {
+            name: "get_weather".to_string(),
+            arguments: serde_json::from_value(arguments).unwrap(),
+            call_id: "call-1".to_string(),
+        };
+        let result = registry
+            .execute(invoke(
+                json!({"city": "Tokyo", "forecastDays": [1.0, 2], "via": [{"city": "Kyoto"}]}),
+            ))
+            .await;
+        assert_eq!(result.result_type, Some(ToolResultType::Success));
+        assert_eq!(
+            result.text_result_for_llm.unwrap(),
+            "Tokyo None [1, 2] Kyoto:0"
+        );
+
+        let result = registry
+            .execute(invoke(json!({"city": 5, "forecastDays": []})))
+            .await;
+        assert_eq!(result.result_type, Some(ToolResultType::Failure));
+        assert!(result
+            .text_result_for_llm
+            .unwrap()
+            .starts_with("Invalid arguments for tool 'get_weather'"));
+    }
+
+    #[tokio::test]
+    async fn test_register_typed_with_handwritten_schema() {
+        #[derive(Deserialize)]
+        struct Args {
+            count: i64,
+            label: Option<String>,
+        }
+
+        let tool = create_tool(
+            "repeat",
+            "Repeat a label",
+            Some(
+                ToolParameters::new()
+                    .add_integer("count", "How many times")
+                    .add_string("label", "What to repeat")
+                    .required(vec!["count"])
+                    .to_value(),
+            ),
+        );
+        let mut registry = ToolRegistry::new();
+        registry.register_typed(tool, |args: Args| async move {
+            success_result(
+                args.label
+                    .unwrap_or_else(|| "x".to_string())
+                    .repeat(args.count as usize),
+            )
+        });
+
+        let invoke = |arguments: Value| ToolInvocation {
+            name: "repeat".to_string(),
+            arguments: serde_json::from_value(arguments).unwrap(),
+            call_id: "call-1".to_string(),
+        };
+        // Whole floats are coerced to the declared integer type first.
+        let result = registry
+            .execute(invoke(json!({"count": 3.0, "label": "ab"})))
+            .await;
+        assert_eq!(result.text_result_for_llm.unwrap(), "ababab");
+
+        let result = registry.execute(invoke(json!({"label": "ab"}))).await;
+        assert_eq!(result.result_type, Some(ToolResultType::Failure));
+        assert!(result
+            .text_result_for_llm
+            .unwrap()
+            .contains("missing field `count`"));
+    }
 }
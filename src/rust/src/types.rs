@@ -37,6 +37,9 @@ pub enum Role {
     User,
     Assistant,
     System,
+    /// A tool's result, answering the assistant call named by the message's
+    /// `tool_call_id`. Sent to the API as a user turn.
+    Tool,
 }
 
 impl Role {
@@ -45,6 +48,7 @@ impl Role {
             Role::User => "user",
             Role::Assistant => "assistant",
             Role::System => "system",
+            Role::Tool => "tool",
         }
     }
 }
@@ -87,6 +91,15 @@ pub struct GeminiModelInfo {
     pub supports_native_tools: bool,
     #[serde(default = "default_true")]
     pub supports_thinking: bool,
+    /// Accepts image input.
+    #[serde(default = "default_true")]
+    pub supports_vision: bool,
+    /// Thinking config used by sessions on this model that don't set one.
+    /// The built-in Pro and Flash models send `includeThoughts: true` this
+    /// way; sessions opt out with a `ThinkingConfig` whose
+    /// `include_thoughts` is `false`.
+    #[serde(default)]
+    pub default_thinking: Option<ThinkingConfig>,
 }
 
 fn default_context_window() -> u64 {
@@ -145,6 +158,10 @@ pub struct ContentPart {
     pub image_url: Option<String>,
     pub image_data: Option<Vec<u8>>,
     pub image_mime_type: Option<String>,
+    /// A file uploaded with `GeminiBackend::upload_file` (or any other URI
+    /// Gemini can read), sent as `fileData` with `image_mime_type`.
+    #[serde(default)]
+    pub file_uri: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -154,6 +171,14 @@ pub struct Message {
     pub name: Option<String>,
     pub tool_calls: Option<Vec<ToolCall>>,
     pub tool_call_id: Option<String>,
+    /// The model's thinking for this turn, replayed as a `thought` part so
+    /// later turns see the same chain of thought.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    /// How the call answered by a `Role::Tool` message went. Failed,
+    /// rejected and denied calls reach the model as an `error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_result_type: Option<ToolResultType>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -182,6 +207,201 @@ pub struct Attachment {
     pub mime_type: Option<String>,
 }
 
+impl Attachment {
+    /// An attachment for a local file, read when the message is sent. The
+    /// MIME type comes from the extension, else it's sniffed from the bytes.
+    pub fn from_path(path: impl AsRef<std::path::Path>) -> crate::errors::Result<Self> {
+        let path = path.as_ref();
+        if !std::fs::metadata(path)?.is_file() {
+            return Err(crate::errors::GeminiSDKError::validation(
+                format!("Attachment path is not a file: {}", path.display()),
+                Some("path".to_string()),
+            ));
+        }
+        let path = path.to_string_lossy().into_owned();
+        Ok(Self {
+            attachment_type: "file".to_string(),
+            mime_type: mime_type_from_extension(&path).map(String::from),
+            path: Some(path),
+            url: None,
+            data: None,
+        })
+    }
+
+    /// An attachment for a URL, with the MIME type guessed from its
+    /// extension.
+    pub fn from_url(url: impl Into<String>) -> Self {
+        let url = url.into();
+        let mime_type = match url::Url::parse(&url) {
+            Ok(parsed) => mime_type_from_extension(parsed.path()),
+            Err(_) => mime_type_from_extension(&url),
+        };
+        Self {
+            attachment_type: "url".to_string(),
+            path: None,
+            url: Some(url),
+            data: None,
+            mime_type: mime_type.map(String::from),
+        }
+    }
+
+    /// Like `to_content_part`, but reads files without blocking and downloads
+    /// `http(s)` URLs into inline data with `client`, since the API only
+    /// accepts file references it issued itself (Files API URIs are left as
+    /// references). Fails when the file or download is over `max_bytes`. The
+    /// MIME type comes from the response's `Content-Type` when it isn't set.
+    pub async fn resolve(
+        &self,
+        client: &reqwest::Client,
+        max_bytes: usize,
+    ) -> crate::errors::Result<ContentPart> {
+        let (data, content_type) = match (&self.data, &self.path, &self.url) {
+            (None, Some(path), _) => {
+                let size = tokio::fs::metadata(path).await?.len();
+                if size > max_bytes as u64 {
+                    return Err(attachment_too_large(path, size, max_bytes));
+                }
+                (tokio::fs::read(path).await?, None)
+            }
+            (None, None, Some(url))
+                if (url.starts_with("http://") || url.starts_with("https://"))
+                    && !url.starts_with(GEMINI_FILES_ENDPOINT) =>
+            {
+                download_attachment(client, url, max_bytes).await?
+            }
+            _ => return self.to_content_part(),
+        };
+
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+        Self {
+            attachment_type: self.attachment_type.clone(),
+            path: None,
+            url: None,
+            data: Some(STANDARD.encode(data)),
+            mime_type: self.mime_type.clone().or(content_type),
+        }
+        .to_content_part()
+    }
+
+    /// Converts the attachment to a message part: `data` (base64) and `path`
+    /// become inline data, `url` a file reference. The MIME type is sniffed
+    /// from the bytes when not given.
+    pub fn to_content_part(&self) -> crate::errors::Result<ContentPart> {
+        let data = match (&self.data, &self.path, &self.url) {
+            (Some(data), _, _) => {
+                use base64::{engine::general_purpose::STANDARD, Engine as _};
+                STANDARD.decode(data).map_err(|e| {
+                    crate::errors::GeminiSDKError::validation(
+                        format!("Attachment data is not valid base64: {}", e),
+                        Some("data".to_string()),
+                    )
+                })?
+            }
+            (None, Some(path), _) => std::fs::read(path)?,
+            (None, None, Some(url)) => {
+                return Ok(ContentPart {
+                    text: None,
+                    image_url: None,
+                    image_data: None,
+                    image_mime_type: self.mime_type.clone(),
+                    file_uri: Some(url.clone()),
+                })
+            }
+            (None, None, None) => {
+                return Err(crate::errors::GeminiSDKError::validation(
+                    "Attachment has no data, path or url",
+                    None,
+                ))
+            }
+        };
+
+        let mime_type = match &self.mime_type {
+            Some(mime_type) => mime_type.clone(),
+            None => sniff_mime_type(&data).map(String::from).ok_or_else(|| {
+                crate::errors::GeminiSDKError::validation(
+                    "Cannot determine the attachment's MIME type; set mime_type",
+                    Some("mime_type".to_string()),
+                )
+            })?,
+        };
+        Ok(ContentPart {
+            text: None,
+            image_url: None,
+            image_data: Some(data),
+            image_mime_type: Some(mime_type),
+            file_uri: None,
+        })
+    }
+}
+
+/// Downloads an attachment URL, returning the body and its `Content-Type`.
+async fn download_attachment(
+    client: &reqwest::Client,
+    url: &str,
+    max_bytes: usize,
+) -> crate::errors::Result<(Vec<u8>, Option<String>)> {
+    let mut response = client.get(url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(crate::errors::GeminiSDKError::api_error(
+            format!("Failed to download attachment {}: {}", url, status),
+            status.as_u16(),
+        ));
+    }
+    if let Some(size) = response
+        .content_length()
+        .filter(|size| *size > max_bytes as u64)
+    {
+        return Err(attachment_too_large(url, size, max_bytes));
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty() && value != "application/octet-stream");
+
+    // The length header may be missing or wrong, so count as the body arrives.
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if data.len() + chunk.len() > max_bytes {
+            return Err(attachment_too_large(
+                url,
+                (data.len() + chunk.len()) as u64,
+                max_bytes,
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok((data, content_type))
+}
+
+fn attachment_too_large(
+    source: &str,
+    size: u64,
+    max_bytes: usize,
+) -> crate::errors::GeminiSDKError {
+    crate::errors::GeminiSDKError::Validation {
+        message: format!(
+            "Attachment {} is at least {} bytes, exceeding the limit of {} bytes",
+            source, size, max_bytes
+        ),
+        field: Some("max_request_bytes".to_string()),
+        value: Some(size.to_string()),
+    }
+}
+
+/// A file stored with the Files API, referenced from messages by `uri`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileHandle {
+    /// Resource name, e.g. `files/abc-123`.
+    pub name: String,
+    pub uri: String,
+    pub mime_type: String,
+    pub size_bytes: u64,
+}
+
 // =============================================================================
 // Tool Types
 // =============================================================================
@@ -225,6 +445,9 @@ pub struct ToolResult {
     pub result_type: Option<ToolResultType>,
     pub text_result_for_llm: Option<String>,
     pub binary_result: Option<Vec<u8>>,
+    /// MIME type of `binary_result`; sniffed from the bytes when unset.
+    #[serde(default)]
+    pub binary_mime_type: Option<String>,
     pub session_log: Option<String>,
 }
 
@@ -234,11 +457,124 @@ impl Default for ToolResult {
             result_type: Some(ToolResultType::Success),
             text_result_for_llm: None,
             binary_result: None,
+            binary_mime_type: None,
             session_log: None,
         }
     }
 }
 
+/// Longest tool result text sent back to the model; longer output is cut
+/// and marked as truncated.
+pub const MAX_FUNCTION_RESPONSE_CHARS: usize = 100_000;
+
+impl ToolResult {
+    /// Text the model sees for this result, defaulting to "Success".
+    pub fn text_for_llm(&self) -> String {
+        self.text_result_for_llm
+            .clone()
+            .unwrap_or_else(|| "Success".to_string())
+    }
+
+    /// The binary output as an inline-data part for the model, or `None` for
+    /// text-only results and bytes of an unrecognised type.
+    pub fn binary_content_part(&self) -> Option<ContentPart> {
+        let data = self.binary_result.as_ref()?;
+        let mime_type = match &self.binary_mime_type {
+            Some(mime_type) => mime_type.clone(),
+            None => match sniff_mime_type(data) {
+                Some(mime_type) => mime_type.to_string(),
+                None => {
+                    log::warn!(
+                        "Dropping tool binary result of unknown type ({} bytes)",
+                        data.len()
+                    );
+                    return None;
+                }
+            },
+        };
+        Some(ContentPart {
+            text: None,
+            image_url: None,
+            image_data: Some(data.clone()),
+            image_mime_type: Some(mime_type),
+            file_uri: None,
+        })
+    }
+
+    /// Serializes the result to the `functionResponse` part sent to Gemini.
+    ///
+    /// Successful results are reported under `result`; failures under
+    /// `error`, with `status` set for rejected and denied calls. Binary output
+    /// is base64-encoded and text beyond `MAX_FUNCTION_RESPONSE_CHARS` is cut.
+    pub fn to_function_response(&self, name: &str) -> serde_json::Value {
+        let mut text = self.text_for_llm();
+        let truncated = text.chars().count() > MAX_FUNCTION_RESPONSE_CHARS;
+        if truncated {
+            text = text.chars().take(MAX_FUNCTION_RESPONSE_CHARS).collect();
+        }
+
+        let mut response = match self.result_type.unwrap_or(ToolResultType::Success) {
+            ToolResultType::Success => serde_json::json!({"result": text}),
+            ToolResultType::Failure => serde_json::json!({"error": text}),
+            ToolResultType::Rejected => serde_json::json!({"error": text, "status": "rejected"}),
+            ToolResultType::Denied => serde_json::json!({"error": text, "status": "denied"}),
+        };
+
+        if truncated {
+            response["truncated"] = serde_json::json!(true);
+        }
+        if let Some(binary) = &self.binary_result {
+            use base64::{engine::general_purpose::STANDARD, Engine as _};
+            response["binaryResult"] = serde_json::json!(STANDARD.encode(binary));
+        }
+
+        serde_json::json!({
+            "functionResponse": {
+                "name": name,
+                "response": response
+            }
+        })
+    }
+}
+
+/// Recognises the file formats Gemini accepts as inline data by their magic
+/// bytes.
+fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'%', b'P', b'D', b'F', ..] => Some("application/pdf"),
+        _ => None,
+    }
+}
+
+/// Maps a file name's extension to one of the MIME types Gemini accepts.
+fn mime_type_from_extension(name: &str) -> Option<&'static str> {
+    let (_, extension) = name.rsplit_once('.')?;
+    let mime_type = match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "pdf" => "application/pdf",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tool {
     pub name: String,
@@ -250,6 +586,98 @@ pub struct Tool {
 // Generation Config Types
 // =============================================================================
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum FunctionCallingMode {
+    /// The model decides whether to call a function.
+    #[default]
+    Auto,
+    /// The model must call one of the (allowed) functions.
+    Any,
+    /// The model must not call functions.
+    None,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolConfig {
+    pub mode: FunctionCallingMode,
+    /// Restricts which functions may be called; only honored with `Any`.
+    pub allowed_function_names: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HarmCategory {
+    #[serde(rename = "HARM_CATEGORY_HARASSMENT")]
+    Harassment,
+    #[serde(rename = "HARM_CATEGORY_HATE_SPEECH")]
+    HateSpeech,
+    #[serde(rename = "HARM_CATEGORY_SEXUALLY_EXPLICIT")]
+    SexuallyExplicit,
+    #[serde(rename = "HARM_CATEGORY_DANGEROUS_CONTENT")]
+    DangerousContent,
+    #[serde(rename = "HARM_CATEGORY_CIVIC_INTEGRITY")]
+    CivicIntegrity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HarmBlockThreshold {
+    BlockLowAndAbove,
+    BlockMediumAndAbove,
+    BlockOnlyHigh,
+    BlockNone,
+    Off,
+}
+
+/// Overrides the blocking threshold for one harm category.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafetySetting {
+    pub category: HarmCategory,
+    pub threshold: HarmBlockThreshold,
+}
+
+/// Why a response was withheld by the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum BlockReason {
+    /// Stopped by the safety filters (`finishReason: SAFETY`).
+    Safety,
+    /// Stopped for reciting training data (`finishReason: RECITATION`).
+    Recitation,
+    /// The prompt contained a term from the terminology blocklist.
+    Blocklist,
+    /// The prompt asked for prohibited content.
+    #[serde(rename = "PROHIBITED_CONTENT")]
+    ProhibitedContent,
+    /// An image in the prompt was flagged by the safety filters.
+    #[serde(rename = "IMAGE_SAFETY")]
+    ImageSafety,
+    /// Blocked for a reason the API doesn't name.
+    Other,
+}
+
+impl BlockReason {
+    pub fn from_finish_reason(finish_reason: Option<&str>) -> Option<Self> {
+        match finish_reason? {
+            "SAFETY" => Some(Self::Safety),
+            "RECITATION" => Some(Self::Recitation),
+            _ => None,
+        }
+    }
+
+    /// Maps `promptFeedback.blockReason`, set when the prompt itself was
+    /// rejected and no candidates were generated.
+    pub fn from_prompt_feedback(block_reason: &str) -> Self {
+        match block_reason {
+            "SAFETY" => Self::Safety,
+            "BLOCKLIST" => Self::Blocklist,
+            "PROHIBITED_CONTENT" => Self::ProhibitedContent,
+            "IMAGE_SAFETY" => Self::ImageSafety,
+            _ => Self::Other,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GenerationConfig {
     #[serde(default = "default_temperature")]
@@ -258,6 +686,45 @@ pub struct GenerationConfig {
     pub top_p: Option<f64>,
     pub top_k: Option<u32>,
     pub stop_sequences: Option<Vec<String>>,
+    /// Penalizes tokens that already appear in the output, favoring new
+    /// topics.
+    pub presence_penalty: Option<f64>,
+    /// Penalizes tokens by how often they already appear in the output.
+    pub frequency_penalty: Option<f64>,
+    /// Fixed sampling seed, for reproducible output at a given temperature.
+    pub seed: Option<i32>,
+    /// Function-calling mode, sent as the request's `toolConfig`.
+    pub tool_config: Option<ToolConfig>,
+    /// Output MIME type, e.g. `application/json` for structured output.
+    pub response_mime_type: Option<String>,
+    /// JSON schema the structured output must follow.
+    pub response_schema: Option<serde_json::Value>,
+    /// Number of alternative responses to generate; see
+    /// `GeminiBackend::complete_n`. Sessions use the first one.
+    pub candidate_count: Option<u32>,
+    /// Server-side tools such as Google Search, sent alongside any function
+    /// declarations.
+    pub builtin_tools: Option<Vec<BuiltinTool>>,
+}
+
+/// A tool the model runs itself rather than calling back into the SDK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuiltinTool {
+    /// Grounds answers in web search; sources come back in
+    /// `LLMChunk::grounding`.
+    GoogleSearch,
+    /// Lets the model write and run Python; the code and its output come
+    /// back in `LLMChunk::code_execution`.
+    CodeExecution,
+}
+
+impl BuiltinTool {
+    pub fn to_value(&self) -> serde_json::Value {
+        match self {
+            BuiltinTool::GoogleSearch => serde_json::json!({"googleSearch": {}}),
+            BuiltinTool::CodeExecution => serde_json::json!({"codeExecution": {}}),
+        }
+    }
 }
 
 fn default_temperature() -> f64 {
@@ -275,11 +742,17 @@ pub struct ThinkingConfig {
 // Request/Response Types
 // =============================================================================
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MessageOptions {
     pub prompt: String,
     pub attachments: Option<Vec<Attachment>>,
     pub context: Option<String>,
+    /// Model to use for this turn only; the session's model is unchanged.
+    pub model: Option<String>,
+    /// Replaces the session's generation config for this turn only.
+    pub generation_config: Option<GenerationConfig>,
+    /// Replaces the session's thinking config for this turn only.
+    pub thinking_config: Option<ThinkingConfig>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -290,6 +763,19 @@ pub struct LLMUsage {
     pub completion_tokens: u64,
     #[serde(default)]
     pub total_tokens: u64,
+    /// Tokens spent thinking (`thoughtsTokenCount`), not included in
+    /// `completion_tokens`.
+    #[serde(default)]
+    pub thoughts_tokens: u64,
+}
+
+impl LLMUsage {
+    /// Spend for this usage at `model`'s prices, which are per million tokens.
+    /// Thinking tokens are billed as output.
+    pub fn estimated_cost(&self, model: &GeminiModelInfo) -> f64 {
+        self.prompt_tokens as f64 / 1e6 * model.input_price
+            + (self.completion_tokens + self.thoughts_tokens) as f64 / 1e6 * model.output_price
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -300,12 +786,133 @@ pub struct LLMChunk {
     pub tool_calls: Option<Vec<ToolCall>>,
     pub usage: Option<LLMUsage>,
     pub finish_reason: Option<String>,
+    /// Set when generation stopped because the budget ran out while the
+    /// model was still thinking, so no answer was produced.
+    #[serde(default)]
+    pub thinking_truncated: bool,
+    /// Set when the response was blocked (`SAFETY` or `RECITATION`).
+    pub block_reason: Option<BlockReason>,
+    /// Search queries and sources behind a grounded answer.
+    #[serde(default)]
+    pub grounding: Option<GroundingMetadata>,
+    /// Code the model ran with `BuiltinTool::CodeExecution`, in order.
+    #[serde(default)]
+    pub code_execution: Option<Vec<CodeExecution>>,
+}
+
+impl LLMChunk {
+    /// Whether generation stopped at the output token limit (`MAX_TOKENS`),
+    /// leaving the content cut off.
+    pub fn was_truncated(&self) -> bool {
+        is_truncation(self.finish_reason.as_deref())
+    }
+}
+
+pub(crate) fn is_truncation(finish_reason: Option<&str>) -> bool {
+    finish_reason == Some("MAX_TOKENS")
+}
+
+/// One `executableCode` part and the `codeExecutionResult` that followed it.
+/// While streaming, the result can arrive in a later chunk than the code; it
+/// then shows up on its own with an empty `code`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CodeExecution {
+    #[serde(default)]
+    pub language: String,
+    #[serde(default)]
+    pub code: String,
+    /// `OUTCOME_OK`, `OUTCOME_FAILED` or `OUTCOME_DEADLINE_EXCEEDED`, once run.
+    pub outcome: Option<String>,
+    pub output: Option<String>,
+}
+
+/// Citations for an answer grounded with `BuiltinTool::GoogleSearch`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GroundingMetadata {
+    #[serde(default)]
+    pub web_search_queries: Vec<String>,
+    #[serde(default)]
+    pub sources: Vec<GroundingSource>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroundingSource {
+    pub uri: String,
+    pub title: Option<String>,
+}
+
+impl LLMChunk {
+    /// Deserializes the content as JSON, for use with structured output
+    /// (`response_mime_type: "application/json"`).
+    pub fn parse_json<T: serde::de::DeserializeOwned>(&self) -> crate::errors::Result<T> {
+        Ok(serde_json::from_str(&self.content)?)
+    }
 }
 
 // =============================================================================
 // Session Types
 // =============================================================================
 
+/// Controls how often estimated token usage is reported while streaming.
+///
+/// An estimate is emitted every `every_chunks` chunks or every `every_ms`
+/// milliseconds, whichever comes first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageEstimateConfig {
+    #[serde(default = "default_usage_every_chunks")]
+    pub every_chunks: u32,
+    #[serde(default = "default_usage_every_ms")]
+    pub every_ms: u64,
+}
+
+impl Default for UsageEstimateConfig {
+    fn default() -> Self {
+        Self {
+            every_chunks: default_usage_every_chunks(),
+            every_ms: default_usage_every_ms(),
+        }
+    }
+}
+
+fn default_usage_every_chunks() -> u32 {
+    10
+}
+
+fn default_usage_every_ms() -> u64 {
+    1000
+}
+
+/// Controls summarizing old history with `GeminiSession::compact_history`.
+///
+/// Everything but the `keep_recent` latest messages (at least one) is
+/// replaced by a model-written summary. With `SessionConfig::compaction` set,
+/// this happens before a turn whenever the history is estimated above
+/// `threshold_tokens`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactionConfig {
+    #[serde(default = "default_compaction_threshold_tokens")]
+    pub threshold_tokens: u64,
+    #[serde(default = "default_compaction_keep_recent")]
+    pub keep_recent: usize,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            threshold_tokens: default_compaction_threshold_tokens(),
+            keep_recent: default_compaction_keep_recent(),
+        }
+    }
+}
+
+fn default_compaction_threshold_tokens() -> u64 {
+    100_000
+}
+
+fn default_compaction_keep_recent() -> usize {
+    6
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SessionConfig {
     pub session_id: Option<String>,
@@ -313,8 +920,381 @@ pub struct SessionConfig {
     pub tools: Option<Vec<Tool>>,
     pub system_message: Option<String>,
     pub generation_config: Option<GenerationConfig>,
+    /// Defaults to the model's `default_thinking`, which turns thoughts on
+    /// for the built-in Pro and Flash models.
     pub thinking_config: Option<ThinkingConfig>,
     pub streaming: Option<bool>,
+    /// Emit `assistant.usage` estimates while streaming. Disabled when `None`.
+    pub usage_estimate: Option<UsageEstimateConfig>,
+    /// Coerce whole-valued floats to integers in tool arguments when the tool
+    /// schema declares `integer`. Defaults to `true`.
+    pub coerce_integer_arguments: Option<bool>,
+    /// Per-category blocking thresholds, sent as the request's `safetySettings`.
+    pub safety_settings: Option<Vec<SafetySetting>>,
+    /// Few-shot `(user, assistant)` pairs placed after the system message and
+    /// before live turns. Kept by `clear_history`.
+    pub examples: Option<Vec<(String, String)>>,
+    /// When set, the oldest non-system messages are dropped before each
+    /// request until the estimated history size fits this many tokens (or
+    /// the model's context window, if smaller).
+    pub max_history_tokens: Option<u64>,
+    /// Summarize old history instead of losing it once it grows past the
+    /// threshold; runs before `max_history_tokens` trimming. Off when `None`.
+    pub compaction: Option<CompactionConfig>,
+    /// Concrete model to retry with when `model` is `"auto"` and auto routing
+    /// isn't available for the account. Without it such requests fail with a
+    /// configuration error.
+    pub auto_fallback_model: Option<String>,
+    /// How many tool calls from one turn may run concurrently. Defaults to 4;
+    /// `Some(1)` runs them one after another.
+    pub max_parallel_tools: Option<usize>,
+    /// Check tool call arguments against the tool's parameter schema and
+    /// answer invalid calls with a failure instead of running the handler.
+    /// Off by default.
+    pub validate_tool_args: Option<bool>,
+    /// Models to try in order when a request fails with a rate limit,
+    /// exhausted quota, server error or unavailable model. Each switch is
+    /// emitted as `session.model_fallback`.
+    pub fallback_models: Option<Vec<String>>,
+    /// When a streamed reply's connection drops, reconnect up to this many
+    /// times, asking the model to continue from the text received so far.
+    /// Applies to `send`/`send_message` with streaming on; each reconnect is
+    /// emitted as `session.stream_reconnect`. Off by default.
+    pub stream_reconnects: Option<u32>,
+    /// When a reply stops at `MAX_TOKENS`, ask the model to continue up to
+    /// this many times. The history then holds one assistant message with
+    /// the joined text, which `send_message` returns. Off by default.
+    pub auto_continue: Option<u32>,
+    /// Strip the reply at the first of the generation config's
+    /// `stop_sequences` before the assistant message is stored and emitted,
+    /// including by `send_stream`. Only complete stop sequences are removed:
+    /// a reply that merely ends with the start of one is kept as is.
+    /// Streamed deltas and chunks are left as received. Off by default.
+    pub trim_stop_sequences: Option<bool>,
+    /// Also send the reply accumulated so far as `content` on every
+    /// `assistant.message_delta` and `assistant.reasoning_delta` event, as
+    /// earlier versions did. Off by default, since it makes the event volume
+    /// of a long reply quadratic; accumulate `deltaContent` instead.
+    pub include_accumulated_content: Option<bool>,
+    /// Tidy the reply's whitespace before the assistant message is stored
+    /// and emitted: blank lines at the start and end are dropped, runs of
+    /// blank lines collapse into one, and the reply's leading and trailing
+    /// whitespace is trimmed. Fenced code blocks (``` or ~~~) are kept
+    /// exactly as received. Streamed deltas and `send_stream` chunks are left
+    /// as received. Off by default.
+    pub normalize_whitespace: Option<bool>,
+}
+
+// =============================================================================
+// Config Builders
+// =============================================================================
+
+impl GenerationConfig {
+    /// Starts a builder with the default temperature of 0.7.
+    pub fn builder() -> GenerationConfigBuilder {
+        GenerationConfigBuilder::default()
+    }
+}
+
+/// Fluent builder for `GenerationConfig`.
+#[derive(Debug, Clone)]
+pub struct GenerationConfigBuilder {
+    config: GenerationConfig,
+}
+
+impl Default for GenerationConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: GenerationConfig {
+                temperature: default_temperature(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl GenerationConfigBuilder {
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.config.temperature = temperature;
+        self
+    }
+
+    pub fn max_output_tokens(mut self, tokens: u32) -> Self {
+        self.config.max_output_tokens = Some(tokens);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f64) -> Self {
+        self.config.top_p = Some(top_p);
+        self
+    }
+
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.config.top_k = Some(top_k);
+        self
+    }
+
+    pub fn stop_sequence(mut self, stop: impl Into<String>) -> Self {
+        self.config
+            .stop_sequences
+            .get_or_insert_with(Vec::new)
+            .push(stop.into());
+        self
+    }
+
+    pub fn presence_penalty(mut self, penalty: f64) -> Self {
+        self.config.presence_penalty = Some(penalty);
+        self
+    }
+
+    pub fn frequency_penalty(mut self, penalty: f64) -> Self {
+        self.config.frequency_penalty = Some(penalty);
+        self
+    }
+
+    pub fn seed(mut self, seed: i32) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn tool_config(mut self, tool_config: ToolConfig) -> Self {
+        self.config.tool_config = Some(tool_config);
+        self
+    }
+
+    pub fn response_mime_type(mut self, mime_type: impl Into<String>) -> Self {
+        self.config.response_mime_type = Some(mime_type.into());
+        self
+    }
+
+    /// Requests JSON output following `schema`; also sets the MIME type to
+    /// `application/json`.
+    pub fn response_schema(mut self, schema: serde_json::Value) -> Self {
+        self.config.response_mime_type = Some("application/json".to_string());
+        self.config.response_schema = Some(schema);
+        self
+    }
+
+    pub fn candidate_count(mut self, count: u32) -> Self {
+        self.config.candidate_count = Some(count);
+        self
+    }
+
+    pub fn builtin_tool(mut self, tool: BuiltinTool) -> Self {
+        let tools = self.config.builtin_tools.get_or_insert_with(Vec::new);
+        if !tools.contains(&tool) {
+            tools.push(tool);
+        }
+        self
+    }
+
+    pub fn build(self) -> GenerationConfig {
+        self.config
+    }
+}
+
+impl SessionConfig {
+    pub fn builder() -> SessionConfigBuilder {
+        SessionConfigBuilder::default()
+    }
+}
+
+/// Fluent builder for `SessionConfig`. Generation knobs such as
+/// `temperature` fill in the session's `GenerationConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfigBuilder {
+    config: SessionConfig,
+    generation: Option<GenerationConfigBuilder>,
+}
+
+impl SessionConfigBuilder {
+    pub fn session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.config.session_id = Some(session_id.into());
+        self
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.config.model = Some(model.into());
+        self
+    }
+
+    pub fn tool(mut self, tool: Tool) -> Self {
+        self.config.tools.get_or_insert_with(Vec::new).push(tool);
+        self
+    }
+
+    pub fn tools(mut self, tools: Vec<Tool>) -> Self {
+        self.config.tools.get_or_insert_with(Vec::new).extend(tools);
+        self
+    }
+
+    pub fn system_message(mut self, message: impl Into<String>) -> Self {
+        self.config.system_message = Some(message.into());
+        self
+    }
+
+    /// Replaces the generation config, including knobs set earlier.
+    pub fn generation_config(mut self, config: GenerationConfig) -> Self {
+        self.generation = Some(GenerationConfigBuilder { config });
+        self
+    }
+
+    pub fn thinking_config(mut self, config: ThinkingConfig) -> Self {
+        self.config.thinking_config = Some(config);
+        self
+    }
+
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.config.streaming = Some(streaming);
+        self
+    }
+
+    pub fn usage_estimate(mut self, config: UsageEstimateConfig) -> Self {
+        self.config.usage_estimate = Some(config);
+        self
+    }
+
+    pub fn compaction(mut self, config: CompactionConfig) -> Self {
+        self.config.compaction = Some(config);
+        self
+    }
+
+    pub fn coerce_integer_arguments(mut self, coerce: bool) -> Self {
+        self.config.coerce_integer_arguments = Some(coerce);
+        self
+    }
+
+    pub fn safety_setting(mut self, category: HarmCategory, threshold: HarmBlockThreshold) -> Self {
+        self.config
+            .safety_settings
+            .get_or_insert_with(Vec::new)
+            .push(SafetySetting {
+                category,
+                threshold,
+            });
+        self
+    }
+
+    pub fn example(mut self, user: impl Into<String>, assistant: impl Into<String>) -> Self {
+        self.config
+            .examples
+            .get_or_insert_with(Vec::new)
+            .push((user.into(), assistant.into()));
+        self
+    }
+
+    pub fn max_history_tokens(mut self, tokens: u64) -> Self {
+        self.config.max_history_tokens = Some(tokens);
+        self
+    }
+
+    pub fn auto_fallback_model(mut self, model: impl Into<String>) -> Self {
+        self.config.auto_fallback_model = Some(model.into());
+        self
+    }
+
+    pub fn fallback_models(mut self, models: Vec<String>) -> Self {
+        self.config.fallback_models = Some(models);
+        self
+    }
+
+    pub fn stream_reconnects(mut self, max_reconnects: u32) -> Self {
+        self.config.stream_reconnects = Some(max_reconnects);
+        self
+    }
+
+    pub fn auto_continue(mut self, max_continuations: u32) -> Self {
+        self.config.auto_continue = Some(max_continuations);
+        self
+    }
+
+    pub fn trim_stop_sequences(mut self, trim: bool) -> Self {
+        self.config.trim_stop_sequences = Some(trim);
+        self
+    }
+
+    pub fn include_accumulated_content(mut self, include: bool) -> Self {
+        self.config.include_accumulated_content = Some(include);
+        self
+    }
+
+    pub fn max_parallel_tools(mut self, max: usize) -> Self {
+        self.config.max_parallel_tools = Some(max);
+        self
+    }
+
+    pub fn validate_tool_args(mut self, validate: bool) -> Self {
+        self.config.validate_tool_args = Some(validate);
+        self
+    }
+
+    pub fn normalize_whitespace(mut self, normalize: bool) -> Self {
+        self.config.normalize_whitespace = Some(normalize);
+        self
+    }
+
+    pub fn temperature(self, temperature: f64) -> Self {
+        self.map_generation(|g| g.temperature(temperature))
+    }
+
+    pub fn max_output_tokens(self, tokens: u32) -> Self {
+        self.map_generation(|g| g.max_output_tokens(tokens))
+    }
+
+    pub fn top_p(self, top_p: f64) -> Self {
+        self.map_generation(|g| g.top_p(top_p))
+    }
+
+    pub fn top_k(self, top_k: u32) -> Self {
+        self.map_generation(|g| g.top_k(top_k))
+    }
+
+    pub fn stop_sequence(self, stop: impl Into<String>) -> Self {
+        self.map_generation(|g| g.stop_sequence(stop))
+    }
+
+    pub fn presence_penalty(self, penalty: f64) -> Self {
+        self.map_generation(|g| g.presence_penalty(penalty))
+    }
+
+    pub fn frequency_penalty(self, penalty: f64) -> Self {
+        self.map_generation(|g| g.frequency_penalty(penalty))
+    }
+
+    pub fn seed(self, seed: i32) -> Self {
+        self.map_generation(|g| g.seed(seed))
+    }
+
+    pub fn tool_config(self, tool_config: ToolConfig) -> Self {
+        self.map_generation(|g| g.tool_config(tool_config))
+    }
+
+    pub fn response_mime_type(self, mime_type: impl Into<String>) -> Self {
+        self.map_generation(|g| g.response_mime_type(mime_type))
+    }
+
+    pub fn response_schema(self, schema: serde_json::Value) -> Self {
+        self.map_generation(|g| g.response_schema(schema))
+    }
+
+    pub fn builtin_tool(self, tool: BuiltinTool) -> Self {
+        self.map_generation(|g| g.builtin_tool(tool))
+    }
+
+    fn map_generation(
+        mut self,
+        f: impl FnOnce(GenerationConfigBuilder) -> GenerationConfigBuilder,
+    ) -> Self {
+        self.generation = Some(f(self.generation.take().unwrap_or_default()));
+        self
+    }
+
+    pub fn build(mut self) -> SessionConfig {
+        if let Some(generation) = self.generation {
+            self.config.generation_config = Some(generation.build());
+        }
+        self.config
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -326,19 +1306,80 @@ pub struct SessionMetadata {
     pub model: String,
 }
 
+/// A session's configuration and full history, for persisting a
+/// conversation between runs. Rebuild it with `GeminiClient::restore_session`;
+/// tool handlers are not included and must be registered again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializedSession {
+    pub metadata: SessionMetadata,
+    pub config: SessionConfig,
+    pub messages: Vec<Message>,
+}
+
 // =============================================================================
 // Client Options Types
 // =============================================================================
 
+/// What `GeminiClient::create_session` and `restore_session` do when the
+/// session id is already in use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionIdCollision {
+    /// Fail with a session error and keep the existing session.
+    #[default]
+    Error,
+    /// Destroy the existing session and register the new one in its place.
+    Replace,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GeminiClientOptions {
     pub oauth_path: Option<String>,
+    /// Named credential profile under `~/.gemini` (see
+    /// `list_credential_profiles`). Ignored when `oauth_path` is set.
+    pub profile: Option<String>,
+    /// Service-account JSON key file. When set, access tokens are minted
+    /// from the key and no user OAuth credentials are needed.
+    pub service_account_path: Option<String>,
     pub client_id: Option<String>,
     pub client_secret: Option<String>,
+    /// Code Assist base URL; defaults to `GEMINI_CODE_ASSIST_ENDPOINT`.
     pub base_url: Option<String>,
+    /// API version path segment; defaults to `GEMINI_CODE_ASSIST_API_VERSION`.
+    pub api_version: Option<String>,
+    /// Proxy URL for all requests; defaults to the `HTTPS_PROXY` /
+    /// `NO_PROXY` environment variables.
+    pub proxy: Option<String>,
+    /// Files API base URL; defaults to `GEMINI_FILES_ENDPOINT`.
+    pub files_base_url: Option<String>,
+    /// Upload inline data larger than this many bytes through the Files API
+    /// instead of sending it in the request. Off when `None`.
+    pub inline_data_limit: Option<usize>,
+    /// Caps generation requests in flight at once across all sessions of
+    /// the client. Unlimited when `None`.
+    pub max_concurrent_requests: Option<usize>,
     pub timeout: Option<f64>,
     pub log_level: Option<LogLevel>,
     pub auto_refresh: Option<bool>,
+    /// How often the background task re-checks the token. Defaults to 5 minutes.
+    pub auto_refresh_interval: Option<std::time::Duration>,
+    /// Sessions left unmodified for longer than this are deleted by a
+    /// background task. Sessions with a request in flight are kept.
+    pub session_ttl: Option<std::time::Duration>,
+    /// Extra OAuth scopes (e.g. Drive) requested alongside the Gemini ones.
+    pub additional_scopes: Option<Vec<String>>,
+    /// Handling of an explicit `session_id` that is already in use.
+    /// Defaults to `SessionIdCollision::Error`.
+    pub session_id_collision: Option<SessionIdCollision>,
+    /// Retries for 429 and 5xx responses and dropped connections. Defaults
+    /// to `RetryPolicy::default()`, which retries up to 3 times; pass
+    /// `RetryPolicy::none()` to fail on the first error. Not serialized.
+    #[serde(skip)]
+    pub retry_policy: Option<crate::backend::RetryPolicy>,
+    /// Polling for account onboarding before the first request. Defaults to
+    /// `OnboardingPolicy::default()`. Not serialized.
+    #[serde(skip)]
+    pub onboarding: Option<crate::backend::OnboardingPolicy>,
 }
 
 // =============================================================================
@@ -353,6 +1394,14 @@ pub enum EventType {
     SessionIdle,
     #[serde(rename = "session.error")]
     SessionError,
+    #[serde(rename = "session.history_trimmed")]
+    SessionHistoryTrimmed,
+    #[serde(rename = "session.history_compacted")]
+    SessionHistoryCompacted,
+    #[serde(rename = "session.model_fallback")]
+    SessionModelFallback,
+    #[serde(rename = "session.stream_reconnect")]
+    SessionStreamReconnect,
     #[serde(rename = "assistant.message")]
     AssistantMessage,
     #[serde(rename = "assistant.message_delta")]
@@ -361,6 +1410,8 @@ pub enum EventType {
     AssistantReasoning,
     #[serde(rename = "assistant.reasoning_delta")]
     AssistantReasoningDelta,
+    #[serde(rename = "assistant.usage")]
+    AssistantUsage,
     #[serde(rename = "tool.call")]
     ToolCall,
     #[serde(rename = "tool.result")]
@@ -374,6 +1425,132 @@ pub struct SessionEvent {
     pub session_id: String,
 }
 
+impl SessionEvent {
+    /// Decodes `data` into the payload for this event's type. `data` itself
+    /// stays available as the raw JSON.
+    pub fn typed(&self) -> crate::errors::Result<SessionEventData> {
+        let tag = match self.event_type {
+            EventType::SessionCreated => return Ok(SessionEventData::SessionCreated),
+            EventType::SessionIdle => "SessionIdle",
+            EventType::SessionError => "SessionError",
+            EventType::SessionHistoryTrimmed => "SessionHistoryTrimmed",
+            EventType::SessionHistoryCompacted => "SessionHistoryCompacted",
+            EventType::SessionModelFallback => "SessionModelFallback",
+            EventType::SessionStreamReconnect => "SessionStreamReconnect",
+            EventType::AssistantMessage => "AssistantMessage",
+            EventType::AssistantMessageDelta => "MessageDelta",
+            EventType::AssistantReasoning => "Reasoning",
+            EventType::AssistantReasoningDelta => "ReasoningDelta",
+            EventType::AssistantUsage => "Usage",
+            EventType::ToolCall => "ToolCall",
+            EventType::ToolResult => "ToolResult",
+        };
+        Ok(serde_json::from_value(
+            serde_json::json!({ tag: self.data }),
+        )?)
+    }
+}
+
+/// Typed payload of a `SessionEvent`, one variant per `EventType`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all_fields = "camelCase")]
+pub enum SessionEventData {
+    SessionCreated,
+    /// Ends every turn, with why generation stopped (`STOP`, `MAX_TOKENS`,
+    /// `SAFETY`, ...) and the turn's final usage.
+    SessionIdle {
+        #[serde(default)]
+        finish_reason: Option<String>,
+        #[serde(default)]
+        usage: Option<LLMUsage>,
+    },
+    SessionError {
+        error: String,
+        /// Text streamed before the failure, which is kept in the history.
+        #[serde(default)]
+        partial_content: Option<String>,
+    },
+    SessionHistoryTrimmed {
+        removed_messages: usize,
+        estimated_tokens: usize,
+        max_history_tokens: usize,
+    },
+    SessionHistoryCompacted {
+        summarized_messages: usize,
+        estimated_tokens: usize,
+    },
+    SessionModelFallback {
+        from: String,
+        to: String,
+        error: String,
+    },
+    SessionStreamReconnect {
+        attempt: u32,
+        error: String,
+        /// Text received before the drop, which the model is asked to
+        /// continue from.
+        #[serde(default)]
+        partial_content: Option<String>,
+    },
+    AssistantMessage {
+        content: String,
+        #[serde(default)]
+        tool_calls: Option<Vec<ToolCall>>,
+        #[serde(default)]
+        usage: Option<LLMUsage>,
+        #[serde(default)]
+        finish_reason: Option<String>,
+        #[serde(default)]
+        thinking_truncated: bool,
+        #[serde(default)]
+        block_reason: Option<BlockReason>,
+        #[serde(default)]
+        grounding: Option<GroundingMetadata>,
+        #[serde(default)]
+        code_execution: Option<Vec<CodeExecution>>,
+    },
+    MessageDelta {
+        #[serde(rename = "deltaContent")]
+        delta: String,
+        /// Only sent with `SessionConfig::include_accumulated_content`.
+        #[serde(rename = "content", default)]
+        accumulated: Option<String>,
+    },
+    Reasoning {
+        content: String,
+    },
+    ReasoningDelta {
+        #[serde(rename = "deltaContent")]
+        delta: String,
+        /// Only sent with `SessionConfig::include_accumulated_content`.
+        #[serde(rename = "content", default)]
+        accumulated: Option<String>,
+    },
+    Usage {
+        #[serde(default)]
+        prompt_tokens: Option<u64>,
+        completion_tokens: u64,
+        #[serde(default)]
+        total_tokens: Option<u64>,
+        estimated: bool,
+    },
+    ToolCall {
+        name: String,
+        arguments: serde_json::Value,
+        call_id: String,
+    },
+    ToolResult {
+        name: String,
+        call_id: String,
+        #[serde(default)]
+        result: Option<String>,
+        #[serde(default)]
+        error: Option<String>,
+        #[serde(default)]
+        function_response: Option<serde_json::Value>,
+    },
+}
+
 // =============================================================================
 // Constants
 // =============================================================================
@@ -395,10 +1572,13 @@ pub const GEMINI_OAUTH_SCOPES: &[&str] = &[
 
 pub const GEMINI_CODE_ASSIST_ENDPOINT: &str = "https://cloudcode-pa.googleapis.com";
 pub const GEMINI_CODE_ASSIST_API_VERSION: &str = "v1internal";
+pub const GEMINI_FILES_ENDPOINT: &str = "https://generativelanguage.googleapis.com";
 
 pub const GEMINI_DIR: &str = ".gemini";
 pub const GEMINI_CREDENTIAL_FILENAME: &str = "oauth_creds.json";
 pub const GEMINI_ENV_FILENAME: &str = ".env";
+/// Profile name for the plain `oauth_creds.json` the Gemini CLI writes.
+pub const DEFAULT_CREDENTIAL_PROFILE: &str = "default";
 
 pub const TOKEN_REFRESH_BUFFER_MS: u64 = 5 * 60 * 1000;
 
@@ -406,8 +1586,22 @@ pub const HTTP_OK: u16 = 200;
 pub const HTTP_UNAUTHORIZED: u16 = 401;
 pub const HTTP_FORBIDDEN: u16 = 403;
 
+/// Model sessions use when their config doesn't name one.
+pub const GEMINI_DEFAULT_MODEL: &str = "gemini-2.5-pro";
+
 /// Get available Gemini CLI models
 pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
+    builtin_models().clone()
+}
+
+/// The built-in model table, built once and shared by every lookup.
+fn builtin_models() -> &'static HashMap<String, GeminiModelInfo> {
+    static MODELS: std::sync::OnceLock<HashMap<String, GeminiModelInfo>> =
+        std::sync::OnceLock::new();
+    MODELS.get_or_init(build_model_table)
+}
+
+fn build_model_table() -> HashMap<String, GeminiModelInfo> {
     let mut models = HashMap::new();
 
     models.insert(
@@ -421,6 +1615,11 @@ pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
             output_price: 0.0,
             supports_native_tools: true,
             supports_thinking: true,
+            supports_vision: true,
+            default_thinking: Some(ThinkingConfig {
+                include_thoughts: true,
+                thinking_budget: None,
+            }),
         },
     );
 
@@ -435,6 +1634,11 @@ pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
             output_price: 0.0,
             supports_native_tools: true,
             supports_thinking: true,
+            supports_vision: true,
+            default_thinking: Some(ThinkingConfig {
+                include_thoughts: true,
+                thinking_budget: None,
+            }),
         },
     );
 
@@ -449,6 +1653,11 @@ pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
             output_price: 0.0,
             supports_native_tools: true,
             supports_thinking: true,
+            supports_vision: true,
+            default_thinking: Some(ThinkingConfig {
+                include_thoughts: true,
+                thinking_budget: None,
+            }),
         },
     );
 
@@ -463,6 +1672,11 @@ pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
             output_price: 0.0,
             supports_native_tools: true,
             supports_thinking: true,
+            supports_vision: true,
+            default_thinking: Some(ThinkingConfig {
+                include_thoughts: true,
+                thinking_budget: None,
+            }),
         },
     );
 
@@ -477,6 +1691,8 @@ pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
             output_price: 0.0,
             supports_native_tools: true,
             supports_thinking: false,
+            supports_vision: true,
+            default_thinking: None,
         },
     );
 
@@ -491,12 +1707,49 @@ pub fn get_gemini_cli_models() -> HashMap<String, GeminiModelInfo> {
             output_price: 0.0,
             supports_native_tools: true,
             supports_thinking: true,
+            supports_vision: true,
+            default_thinking: None,
+        },
+    );
+
+    models
+}
+
+/// Get available embedding models, for `GeminiBackend::embed_content`.
+/// Kept apart from `get_gemini_cli_models`, which lists chat models only.
+pub fn get_gemini_embedding_models() -> HashMap<String, GeminiModelInfo> {
+    let mut models = HashMap::new();
+
+    models.insert(
+        "gemini-embedding-001".to_string(),
+        GeminiModelInfo {
+            id: "gemini-embedding-001".to_string(),
+            name: "Gemini Embedding".to_string(),
+            context_window: 2_048,
+            max_output: 0,
+            input_price: 0.0,
+            output_price: 0.0,
+            supports_native_tools: false,
+            supports_thinking: false,
+            supports_vision: false,
+            default_thinking: None,
         },
     );
 
     models
 }
 
+/// Looks up one model's info. `"auto"` resolves to `GEMINI_DEFAULT_MODEL`
+/// rather than the placeholder entry, so the limits describe a real model.
+pub fn get_gemini_cli_model(id: &str) -> Option<GeminiModelInfo> {
+    let id = if id == "auto" {
+        GEMINI_DEFAULT_MODEL
+    } else {
+        id
+    };
+    builtin_models().get(id).cloned()
+}
+
 /// Get the path to Gemini CLI OAuth credentials file
 pub fn get_geminicli_credential_path(custom_path: Option<&str>) -> String {
     if let Some(path) = custom_path {
@@ -510,6 +1763,68 @@ pub fn get_geminicli_credential_path(custom_path: Option<&str>) -> String {
         .to_string()
 }
 
+/// Path of a named credential profile: `~/.gemini/oauth_creds.<profile>.json`,
+/// or the regular credentials file for `DEFAULT_CREDENTIAL_PROFILE`.
+pub fn get_geminicli_profile_path(profile: &str) -> String {
+    if profile == DEFAULT_CREDENTIAL_PROFILE {
+        return get_geminicli_credential_path(None);
+    }
+
+    let home = dirs::home_dir().unwrap_or_default();
+    home.join(GEMINI_DIR)
+        .join(profile_filename(profile))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn profile_filename(profile: &str) -> String {
+    let (stem, ext) = GEMINI_CREDENTIAL_FILENAME
+        .rsplit_once('.')
+        .unwrap_or((GEMINI_CREDENTIAL_FILENAME, "json"));
+    format!("{}.{}.{}", stem, profile, ext)
+}
+
+/// Whether `profile` can name a credentials file: letters, digits, `-` and `_`.
+pub(crate) fn is_valid_profile_name(profile: &str) -> bool {
+    !profile.is_empty()
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Lists the credential profiles saved in `gemini_dir` (default
+/// `~/.gemini`), sorted by name. The plain `oauth_creds.json` is listed as
+/// `DEFAULT_CREDENTIAL_PROFILE`.
+pub fn list_credential_profiles(gemini_dir: Option<&str>) -> Vec<String> {
+    let dir = match gemini_dir {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => dirs::home_dir().unwrap_or_default().join(GEMINI_DIR),
+    };
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    let (stem, ext) = GEMINI_CREDENTIAL_FILENAME
+        .rsplit_once('.')
+        .unwrap_or((GEMINI_CREDENTIAL_FILENAME, "json"));
+    let mut profiles: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|name| {
+            if name == GEMINI_CREDENTIAL_FILENAME {
+                return Some(DEFAULT_CREDENTIAL_PROFILE.to_string());
+            }
+            let profile = name
+                .strip_prefix(stem)?
+                .strip_prefix('.')?
+                .strip_suffix(ext)?
+                .strip_suffix('.')?;
+            is_valid_profile_name(profile).then(|| profile.to_string())
+        })
+        .collect();
+    profiles.sort();
+    profiles
+}
+
 /// Get the path to Gemini CLI environment file
 pub fn get_geminicli_env_path(custom_path: Option<&str>) -> String {
     if let Some(path) = custom_path {
@@ -522,3 +1837,198 @@ pub fn get_geminicli_env_path(custom_path: Option<&str>) -> String {
         .to_string_lossy()
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockResponse, MockServer};
+
+    #[tokio::test]
+    async fn test_attachment_constructors_and_resolve() {
+        let path =
+            std::env::temp_dir().join(format!("geminisdk-attachment-{}.PNG", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n").unwrap();
+        let attachment = Attachment::from_path(&path).unwrap();
+        assert_eq!(attachment.mime_type.as_deref(), Some("image/png"));
+        let client = reqwest::Client::new();
+        let part = attachment.resolve(&client, 1024).await.unwrap();
+        assert_eq!(part.image_data.as_deref(), Some(&b"\x89PNG\r\n\x1a\n"[..]));
+        let err = attachment.resolve(&client, 4).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::errors::GeminiSDKError::Validation { .. }
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert!(Attachment::from_path(&path).is_err());
+        assert!(Attachment::from_path(std::env::temp_dir()).is_err());
+
+        let server = MockServer::start(vec![
+            MockResponse::raw(200, "hello")
+                .with_header("Content-Type", "text/plain; charset=utf-8"),
+            MockResponse::raw(200, "%PDF-1.7"),
+            MockResponse::raw(404, "missing"),
+            MockResponse::raw(200, &"x".repeat(2048)),
+        ])
+        .await;
+        let part = Attachment::from_url(format!("{}/notes", server.url()))
+            .resolve(&client, 1024)
+            .await
+            .unwrap();
+        assert_eq!(part.image_data.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(part.image_mime_type.as_deref(), Some("text/plain"));
+        let pdf = Attachment::from_url(format!("{}/paper.pdf?download=1", server.url()));
+        assert_eq!(pdf.mime_type.as_deref(), Some("application/pdf"));
+        let part = pdf.resolve(&client, 1024).await.unwrap();
+        assert_eq!(part.image_mime_type.as_deref(), Some("application/pdf"));
+        let gone = Attachment::from_url(format!("{}/gone", server.url()));
+        assert_eq!(
+            gone.resolve(&client, 1024).await.unwrap_err().status_code(),
+            Some(404)
+        );
+        let huge = Attachment::from_url(format!("{}/huge.txt", server.url()));
+        let err = huge.resolve(&client, 1024).await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::errors::GeminiSDKError::Validation { .. }
+        ));
+
+        let reference = Attachment::from_url("gs://bucket/photo.jpg");
+        let reference = reference.resolve(&client, 1024).await.unwrap();
+        assert_eq!(reference.file_uri.as_deref(), Some("gs://bucket/photo.jpg"));
+        assert_eq!(reference.image_mime_type.as_deref(), Some("image/jpeg"));
+        let file = Attachment::from_url(format!("{}/v1beta/files/abc", GEMINI_FILES_ENDPOINT));
+        assert!(file
+            .resolve(&client, 1024)
+            .await
+            .unwrap()
+            .file_uri
+            .is_some());
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn test_list_credential_profiles() {
+        let dir = std::env::temp_dir().join(format!("geminisdk-profiles-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "oauth_creds.json",
+            "oauth_creds.work.json",
+            "oauth_creds.personal.json",
+            "settings.json",
+        ] {
+            std::fs::write(dir.join(name), "{}").unwrap();
+        }
+
+        assert_eq!(
+            list_credential_profiles(dir.to_str()),
+            vec!["default", "personal", "work"]
+        );
+        assert!(get_geminicli_profile_path("work").ends_with("oauth_creds.work.json"));
+        assert_eq!(
+            get_geminicli_profile_path(DEFAULT_CREDENTIAL_PROFILE),
+            get_geminicli_credential_path(None)
+        );
+        assert!(!is_valid_profile_name("../work"));
+    }
+
+    fn event(event_type: EventType, data: serde_json::Value) -> SessionEvent {
+        SessionEvent {
+            event_type,
+            data,
+            session_id: "s".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_typed_event_payloads() {
+        let delta = event(
+            EventType::AssistantMessageDelta,
+            serde_json::json!({"deltaContent": "lo", "content": "Hello"}),
+        );
+        match delta.typed().unwrap() {
+            SessionEventData::MessageDelta { delta, accumulated } => {
+                assert_eq!(delta, "lo");
+                assert_eq!(accumulated.as_deref(), Some("Hello"));
+            }
+            other => panic!("unexpected payload: {:?}", other),
+        }
+
+        let call = event(
+            EventType::ToolCall,
+            serde_json::json!({"name": "get_weather", "arguments": {"city": "Paris"}, "callId": "c1"}),
+        );
+        match call.typed().unwrap() {
+            SessionEventData::ToolCall {
+                name,
+                arguments,
+                call_id,
+            } => {
+                assert_eq!(name, "get_weather");
+                assert_eq!(arguments["city"], "Paris");
+                assert_eq!(call_id, "c1");
+            }
+            other => panic!("unexpected payload: {:?}", other),
+        }
+
+        let message = event(
+            EventType::AssistantMessage,
+            serde_json::json!({"content": "Hi", "toolCalls": null, "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}}),
+        );
+        match message.typed().unwrap() {
+            SessionEventData::AssistantMessage {
+                content,
+                tool_calls,
+                usage,
+                ..
+            } => {
+                assert_eq!(content, "Hi");
+                assert!(tool_calls.is_none());
+                assert_eq!(usage.unwrap().total_tokens, 4);
+            }
+            other => panic!("unexpected payload: {:?}", other),
+        }
+
+        assert!(matches!(
+            event(EventType::SessionIdle, serde_json::json!({}))
+                .typed()
+                .unwrap(),
+            SessionEventData::SessionIdle {
+                finish_reason: None,
+                usage: None
+            }
+        ));
+        assert!(event(EventType::SessionError, serde_json::json!({}))
+            .typed()
+            .is_err());
+    }
+
+    #[test]
+    fn test_session_config_builder() {
+        let config = SessionConfig::builder()
+            .model("gemini-2.5-pro")
+            .streaming(false)
+            .system_message("Be brief.")
+            .temperature(0.2)
+            .max_output_tokens(512)
+            .example("Hi", "Hello!")
+            .build();
+
+        assert_eq!(config.model.as_deref(), Some("gemini-2.5-pro"));
+        assert_eq!(config.streaming, Some(false));
+        assert_eq!(config.system_message.as_deref(), Some("Be brief."));
+        assert_eq!(
+            config.examples,
+            Some(vec![("Hi".to_string(), "Hello!".to_string())])
+        );
+        let generation = config.generation_config.unwrap();
+        assert_eq!(generation.temperature, 0.2);
+        assert_eq!(generation.max_output_tokens, Some(512));
+
+        // Untouched generation knobs leave the session's config unset.
+        assert!(SessionConfig::builder().build().generation_config.is_none());
+        assert_eq!(
+            GenerationConfig::builder().top_k(40).build().temperature,
+            0.7
+        );
+    }
+}